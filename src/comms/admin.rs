@@ -0,0 +1,203 @@
+use crate::{
+    engine::player::PlayerId,
+    error::{ZError, ZResult},
+};
+use log::{error, info};
+use std::str::FromStr;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::mpsc::Sender,
+};
+
+/// The smallest map that can be requested with new_map.
+const MIN_MAP_SIZE: u32 = 7;
+
+/// Commands sent to the game loop by an operator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    Kick(PlayerId),
+    Ban(String),
+    SpawnMob,
+    SetMaxMobs(usize),
+    NewMap(u32, u32),
+    Broadcast(String),
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (command, arg) = match s.find(' ') {
+            Some(index) => (&s[..index], s[index..].trim()),
+            None => (s, ""),
+        };
+
+        match (command, arg) {
+            ("kick", id) => id
+                .parse::<u64>()
+                .map(|id| AdminCommand::Kick(PlayerId::from(id)))
+                .map_err(|_| format!("Invalid player id '{}'", id)),
+            ("ban", name) if !name.is_empty() => Ok(AdminCommand::Ban(name.to_string())),
+            ("spawn_mob", "") => Ok(AdminCommand::SpawnMob),
+            ("set_max_mobs", n) => n
+                .parse()
+                .map(AdminCommand::SetMaxMobs)
+                .map_err(|_| format!("Invalid number of mobs '{}'", n)),
+            ("new_map", size) => {
+                let dimensions: Vec<u32> = size
+                    .split_whitespace()
+                    .filter_map(|x| x.parse().ok())
+                    .collect();
+                match dimensions.as_slice() {
+                    [width, height] if *width >= MIN_MAP_SIZE && *height >= MIN_MAP_SIZE => {
+                        Ok(AdminCommand::NewMap(*width, *height))
+                    }
+                    _ => Err(format!("Invalid map size '{}'", size)),
+                }
+            }
+            ("broadcast", text) if !text.is_empty() => {
+                Ok(AdminCommand::Broadcast(text.to_string()))
+            }
+            _ => Err(format!("Unknown command '{}'", s)),
+        }
+    }
+}
+
+/// An admin command and the game room it is for. Commands can be sent to a
+/// room other than the default one with e.g. "room blue spawn_mob".
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomCommand {
+    room: Option<String>,
+    command: AdminCommand,
+}
+
+impl RoomCommand {
+    /// The room the command is for, or None for the default room.
+    pub fn room(&self) -> Option<&str> {
+        self.room.as_deref()
+    }
+
+    pub fn command(&self) -> &AdminCommand {
+        &self.command
+    }
+
+    pub fn into_command(self) -> AdminCommand {
+        self.command
+    }
+}
+
+impl FromStr for RoomCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_prefix("room ") {
+            Some(rest) => {
+                let rest = rest.trim_start();
+                let (room, command) = match rest.find(' ') {
+                    Some(index) => (&rest[..index], &rest[index..]),
+                    None => return Err(format!("No command for room '{}'", rest)),
+                };
+                Ok(RoomCommand {
+                    room: Some(room.to_string()),
+                    command: command.parse()?,
+                })
+            }
+            None => Ok(RoomCommand {
+                room: None,
+                command: s.parse()?,
+            }),
+        }
+    }
+}
+
+/// Accept admin commands, one per line, on a local Unix socket.
+/// Only users with access to the socket file can connect.
+pub async fn spawn_admin_server(path: &str, commands_tx: Sender<RoomCommand>) -> ZResult<()> {
+    // Remove the socket left behind by a previous run, if any.
+    if std::fs::metadata(path).is_ok() {
+        std::fs::remove_file(path)?;
+    }
+    let mut listener = UnixListener::bind(path)?;
+    info!("Admin server listening on: {}", path);
+
+    while let Ok((stream, _)) = listener.accept().await {
+        let commands_tx = commands_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_admin(stream, commands_tx).await {
+                error!("Error serving admin connection: {:?}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn serve_admin(stream: UnixStream, mut commands_tx: Sender<RoomCommand>) -> ZResult<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match line.parse::<RoomCommand>() {
+            Ok(command) => {
+                info!("Admin command: {:?}", command);
+                commands_tx
+                    .send(command)
+                    .await
+                    .map_err(|e| ZError::FatalError(e.to_string()))?;
+                String::from("ok\n")
+            }
+            Err(e) => format!("error: {}\n", e),
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            "kick 12".parse(),
+            Ok(AdminCommand::Kick(PlayerId::from(12)))
+        );
+        assert_eq!(
+            " ban  Some Name ".parse(),
+            Ok(AdminCommand::Ban(String::from("Some Name")))
+        );
+        assert_eq!("spawn_mob".parse(), Ok(AdminCommand::SpawnMob));
+        assert_eq!("set_max_mobs 20".parse(), Ok(AdminCommand::SetMaxMobs(20)));
+        assert_eq!("new_map 51 41".parse(), Ok(AdminCommand::NewMap(51, 41)));
+        assert!("new_map 3 3".parse::<AdminCommand>().is_err());
+        assert!("new_map 51".parse::<AdminCommand>().is_err());
+        assert!("kick bob".parse::<AdminCommand>().is_err());
+        assert!("ban".parse::<AdminCommand>().is_err());
+        assert_eq!(
+            "broadcast Restarting in 5 minutes".parse(),
+            Ok(AdminCommand::Broadcast(String::from(
+                "Restarting in 5 minutes"
+            )))
+        );
+        assert!("broadcast ".parse::<AdminCommand>().is_err());
+        assert!("explode".parse::<AdminCommand>().is_err());
+    }
+
+    #[test]
+    fn test_parse_room_command() {
+        let command: RoomCommand = "spawn_mob".parse().unwrap();
+        assert_eq!(command.room(), None);
+        assert_eq!(command.command(), &AdminCommand::SpawnMob);
+
+        let command: RoomCommand = " room blue  set_max_mobs 5".parse().unwrap();
+        assert_eq!(command.room(), Some("blue"));
+        assert_eq!(command.into_command(), AdminCommand::SetMaxMobs(5));
+
+        assert!("room blue".parse::<RoomCommand>().is_err());
+        assert!("room blue explode".parse::<RoomCommand>().is_err());
+    }
+}
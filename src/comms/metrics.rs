@@ -0,0 +1,260 @@
+use crate::error::ZResult;
+use log::{error, info};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const FRAME_TIME_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// The name and description of each gauge, in the order returned by
+/// Metrics::gauges().
+const GAUGES: [(&str, &str); 4] = [
+    ("players", "Number of connected players."),
+    ("mobs", "Number of live mobs."),
+    ("bombs", "Number of live bombs."),
+    ("explosions", "Number of live explosions."),
+];
+
+/// Operational metrics, updated by the game loop and read by the metrics
+/// server. Everything is stored in atomics so that neither side ever waits
+/// on the other.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    players: AtomicUsize,
+    mobs: AtomicUsize,
+    bombs: AtomicUsize,
+    explosions: AtomicUsize,
+    // f64 values are stored as their bit patterns.
+    fps: AtomicU64,
+    frame_times: [AtomicU64; 3],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn set_counts(&self, players: usize, mobs: usize, bombs: usize, explosions: usize) {
+        self.players.store(players, Ordering::Relaxed);
+        self.mobs.store(mobs, Ordering::Relaxed);
+        self.bombs.store(bombs, Ordering::Relaxed);
+        self.explosions.store(explosions, Ordering::Relaxed);
+    }
+
+    pub fn set_fps(&self, fps: f64) {
+        self.fps.store(fps.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Update the frame time percentiles from the frame times in seconds
+    /// since the last update. The slice will be sorted.
+    pub fn set_frame_times(&self, frame_times: &mut [f64]) {
+        if frame_times.is_empty() {
+            return;
+        }
+
+        frame_times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let last = frame_times.len() - 1;
+        for (quantile, value) in FRAME_TIME_QUANTILES.iter().zip(self.frame_times.iter()) {
+            let index = (last as f64 * quantile).round() as usize;
+            value.store(frame_times[index].to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    fn gauges(&self) -> [&AtomicUsize; 4] {
+        [&self.players, &self.mobs, &self.bombs, &self.explosions]
+    }
+
+    /// Render all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        render_metrics(&[(None, self)])
+    }
+}
+
+/// The metrics for every game room, labelled with the room name.
+#[derive(Debug, Default)]
+pub struct RoomMetrics {
+    rooms: Mutex<BTreeMap<String, Arc<Metrics>>>,
+}
+
+impl RoomMetrics {
+    pub fn new() -> Self {
+        RoomMetrics::default()
+    }
+
+    pub fn insert(&self, room: &str, metrics: Arc<Metrics>) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            rooms.insert(room.to_string(), metrics);
+        }
+    }
+
+    pub fn remove(&self, room: &str) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            rooms.remove(room);
+        }
+    }
+
+    /// The metrics for the specified room, if it exists.
+    pub fn get(&self, room: &str) -> Option<Arc<Metrics>> {
+        self.rooms.lock().ok()?.get(room).cloned()
+    }
+
+    /// Render the metrics for every room in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms.clone(),
+            Err(_) => return String::new(),
+        };
+        let labelled: Vec<(Option<&str>, &Metrics)> = rooms
+            .iter()
+            .map(|(name, metrics)| (Some(name.as_str()), metrics.as_ref()))
+            .collect();
+        render_metrics(&labelled)
+    }
+}
+
+/// Render metrics in the Prometheus text format, with a room label on each
+/// sample if a room name is given.
+fn render_metrics(rooms: &[(Option<&str>, &Metrics)]) -> String {
+    let labels = |room: Option<&str>, extra: Option<String>| -> String {
+        let labels: Vec<String> = room
+            .map(|name| format!("room=\"{}\"", name))
+            .into_iter()
+            .chain(extra)
+            .collect();
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    };
+
+    let mut out = String::new();
+    for (i, (name, help)) in GAUGES.iter().enumerate() {
+        let _ = writeln!(out, "# HELP rustonator_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE rustonator_{} gauge", name);
+        for (room, metrics) in rooms {
+            let value = metrics.gauges()[i].load(Ordering::Relaxed);
+            let _ = writeln!(out, "rustonator_{}{} {}", name, labels(*room, None), value);
+        }
+    }
+
+    let _ = writeln!(out, "# HELP rustonator_fps Average frames per second.");
+    let _ = writeln!(out, "# TYPE rustonator_fps gauge");
+    for (room, metrics) in rooms {
+        let fps = f64::from_bits(metrics.fps.load(Ordering::Relaxed));
+        let _ = writeln!(out, "rustonator_fps{} {}", labels(*room, None), fps);
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP rustonator_frame_time_seconds Time spent processing each frame."
+    );
+    let _ = writeln!(out, "# TYPE rustonator_frame_time_seconds gauge");
+    for (room, metrics) in rooms {
+        for (quantile, value) in FRAME_TIME_QUANTILES.iter().zip(metrics.frame_times.iter()) {
+            let quantile = format!("quantile=\"{}\"", quantile);
+            let _ = writeln!(
+                out,
+                "rustonator_frame_time_seconds{} {}",
+                labels(*room, Some(quantile)),
+                f64::from_bits(value.load(Ordering::Relaxed))
+            );
+        }
+    }
+    out
+}
+
+/// Serve metrics over HTTP on the specified port. Every request gets the
+/// metrics for every room, regardless of the path.
+pub async fn spawn_metrics_server(port: u16, metrics: Arc<RoomMetrics>) -> ZResult<()> {
+    let addr = format!("0.0.0.0:{}", port);
+    let mut listener = TcpListener::bind(&addr).await?;
+    info!("Metrics server listening on: {}", addr);
+
+    while let Ok((stream, _)) = listener.accept().await {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(stream, &metrics).await {
+                error!("Error serving metrics: {:?}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn serve_metrics(mut stream: TcpStream, metrics: &RoomMetrics) -> ZResult<()> {
+    // The request itself doesn't matter.
+    let mut buf = [0; 1024];
+    let _ = stream.read(&mut buf).await?;
+
+    let body = metrics.render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.set_counts(2, 30, 4, 25);
+        metrics.set_fps(29.5);
+        let mut frame_times: Vec<f64> = (1..=100).rev().map(|x| x as f64 / 1000.0).collect();
+        metrics.set_frame_times(&mut frame_times);
+
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().filter(|x| !x.starts_with('#')).collect();
+        assert_eq!(lines, vec![
+            "rustonator_players 2",
+            "rustonator_mobs 30",
+            "rustonator_bombs 4",
+            "rustonator_explosions 25",
+            "rustonator_fps 29.5",
+            "rustonator_frame_time_seconds{quantile=\"0.5\"} 0.051",
+            "rustonator_frame_time_seconds{quantile=\"0.9\"} 0.09",
+            "rustonator_frame_time_seconds{quantile=\"0.99\"} 0.099",
+        ]);
+    }
+
+    #[test]
+    fn test_render_rooms() {
+        let rooms = RoomMetrics::new();
+        let (a, b) = (Arc::new(Metrics::new()), Arc::new(Metrics::new()));
+        a.set_counts(2, 30, 4, 25);
+        b.set_counts(1, 5, 0, 0);
+        rooms.insert("a", a);
+        rooms.insert("b", b);
+        rooms.remove("b");
+        rooms.insert("c", Arc::new(Metrics::new()));
+
+        let text = rooms.render();
+        let players: Vec<&str> = text
+            .lines()
+            .filter(|x| x.starts_with("rustonator_players"))
+            .collect();
+        assert_eq!(players, vec![
+            "rustonator_players{room=\"a\"} 2",
+            "rustonator_players{room=\"c\"} 0",
+        ]);
+        assert!(text.contains("rustonator_frame_time_seconds{room=\"a\",quantile=\"0.5\"} 0"));
+    }
+}
@@ -1,17 +1,22 @@
 use crate::{
     comms::websocket::WsError,
-    component::action::Action,
+    component::{
+        action::Action,
+        powerup::{PowerUp, PowerUpData},
+    },
     engine::{
         player::{PlayerId, SerPlayer},
-        worlddata::SerWorldData,
+        position::{MapPosition, SizeInTiles},
+        worlddata::{SerWorldData, SerWorldDelta},
     },
     error::{ZError, ZResult},
 };
 
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use std::ops::Deref;
 use tokio::{
-    sync::mpsc::{error::TryRecvError, Receiver, Sender},
+    sync::mpsc::{channel, error::TryRecvError, Receiver, Sender},
     time::Instant,
 };
 
@@ -38,17 +43,114 @@ impl MessageId {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "UPPERCASE", tag = "code", content = "data")]
 pub enum PlayerMessage {
-    JoinGame(String),
+    JoinGame(JoinData),
     Action(Action),
     SpawnPlayer(SerPlayer, SerWorldData),
-    PowerUp(String),
-    FrameData(serde_json::Value),
+    PowerUp(PowerUpData),
+    FrameData(Box<RawValue>),
+    WorldDelta(SerWorldDelta),
     Dead(String),
+    // The join request was refused, but the player can try again.
+    JoinError(String),
+    // The code and name of every cell type found in world data.
+    CellLegend(Vec<(u8, String)>),
+    // Sound effect cues. These don't affect the game.
+    BombPlaced(MapPosition),
+    // Only sent to the player whose bomb couldn't be placed there.
+    BombBlocked(MapPosition),
+    ItemCollected {
+        pos: MapPosition,
+        kind: String,
+    },
+    MatchEnded {
+        winner: Option<PlayerId>,
+        scores: Vec<MatchScore>,
+    },
+    // A message from the server operator, for everyone.
+    Announcement(String),
     Disconnect,
     Ping(String),
     Pong(String),
 }
 
+/// Sent by the client to join the game. Older clients only send the name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum JoinData {
+    Name(String),
+    Detail(JoinRequest),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinRequest {
+    name: String,
+    // The number of tiles the client would like to see.
+    view_width: Option<i32>,
+    view_height: Option<i32>,
+    // The preferred player image.
+    image: Option<String>,
+    // The game room to join, if there is more than one.
+    room: Option<String>,
+    // The client can apply WORLDDELTA messages, and keeps the world it was
+    // last sent when a frame has none.
+    #[serde(default)]
+    partial_world: bool,
+}
+
+impl JoinData {
+    pub fn name(&self) -> &str {
+        match self {
+            JoinData::Name(name) => name,
+            JoinData::Detail(request) => &request.name,
+        }
+    }
+
+    /// The requested player image, if the client asked for one.
+    pub fn image(&self) -> Option<&str> {
+        match self {
+            JoinData::Name(_) => None,
+            JoinData::Detail(request) => request.image.as_deref(),
+        }
+    }
+
+    /// The requested game room, if the client asked for one.
+    pub fn room(&self) -> Option<&str> {
+        match self {
+            JoinData::Name(_) => None,
+            JoinData::Detail(request) => request.room.as_deref(),
+        }
+    }
+
+    /// True if the client can be sent only the parts of the world that have
+    /// changed. Older clients need the world in every frame.
+    pub fn partial_world(&self) -> bool {
+        match self {
+            JoinData::Name(_) => false,
+            JoinData::Detail(request) => request.partial_world,
+        }
+    }
+
+    /// The requested view size, if the client asked for one.
+    pub fn view_size(&self) -> Option<SizeInTiles> {
+        match self {
+            JoinData::Name(_) => None,
+            JoinData::Detail(request) => match (request.view_width, request.view_height) {
+                (Some(width), Some(height)) => Some(SizeInTiles::new(width, height)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// A player's final score for a round.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchScore {
+    pub pid: PlayerId,
+    pub name: String,
+    pub score: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlayerMessageExternal {
     #[serde(skip_deserializing)]
@@ -77,6 +179,9 @@ pub struct PlayerComm {
     sender: PlayerSender,
     receiver: PlayerReceiver,
     last_seen: Instant,
+    powerup_details: bool,
+    // A message that has been read but put back, to be received again.
+    requeued: Option<PlayerMessage>,
 }
 
 impl PlayerComm {
@@ -87,13 +192,34 @@ impl PlayerComm {
             sender,
             receiver,
             last_seen: Instant::now(),
+            powerup_details: false,
+            requeued: None,
         }
     }
 
+    /// Create a PlayerComm with no websocket behind it, along with the
+    /// client end used to control the player from code.
+    pub fn local(id: PlayerId) -> (Self, LocalClient) {
+        let (pcomm_tx, client_rx) = channel(30);
+        let (client_tx, pcomm_rx) = channel(30);
+        let client = LocalClient {
+            sender: client_tx,
+            receiver: client_rx,
+            next_action_id: 1,
+        };
+        (PlayerComm::new(id, pcomm_tx, pcomm_rx), client)
+    }
+
     pub fn id(&self) -> PlayerId {
         self.id
     }
 
+    /// If true, POWERUP messages include the stat changed and its new value
+    /// rather than just the powerup name.
+    pub fn set_powerup_details(&mut self, enabled: bool) {
+        self.powerup_details = enabled;
+    }
+
     pub fn last_seen_seconds(&self) -> u64 {
         self.last_seen.elapsed().as_secs()
     }
@@ -102,7 +228,16 @@ impl PlayerComm {
         self.last_seen.elapsed().as_millis()
     }
 
+    /// Put a message back so that the next call to recv_one() returns it.
+    pub fn requeue(&mut self, message: PlayerMessage) {
+        self.requeued = Some(message);
+    }
+
     pub async fn recv_one(&mut self) -> ZResult<Option<PlayerMessage>> {
+        if let Some(message) = self.requeued.take() {
+            return Ok(Some(message));
+        }
+
         // If we get a ping we will want to retry.
         for _ in 0..2 {
             return match self.receiver.try_recv() {
@@ -132,8 +267,13 @@ impl PlayerComm {
         Ok(())
     }
 
-    pub async fn send_powerup(&mut self, powerup: &str) -> ZResult<()> {
-        self.send(PlayerMessage::PowerUp(powerup.to_string())).await
+    pub async fn send_powerup(&mut self, powerup: PowerUp) -> ZResult<()> {
+        let data = if self.powerup_details {
+            PowerUpData::Detail(powerup)
+        } else {
+            PowerUpData::Name(powerup.name().to_string())
+        };
+        self.send(PlayerMessage::PowerUp(data)).await
     }
 
     pub async fn disconnect(&mut self) -> ZResult<()> {
@@ -141,7 +281,98 @@ impl PlayerComm {
     }
 }
 
+/// The client end of a local PlayerComm, for practice games and tests.
+/// Messages from the game should be read regularly, otherwise the game will
+/// stall waiting to send more, just as it would for a slow websocket.
+#[derive(Debug)]
+pub struct LocalClient {
+    sender: PlayerSender,
+    receiver: PlayerReceiver,
+    next_action_id: u32,
+}
+
+impl LocalClient {
+    pub async fn send(&mut self, message: PlayerMessage) -> ZResult<()> {
+        self.sender
+            .send(PlayerMessageExternal::new(0, message))
+            .await
+            .map_err(|e| ZError::from(WsError::from(e)))?;
+        Ok(())
+    }
+
+    pub async fn join(&mut self, name: &str) -> ZResult<()> {
+        self.send(PlayerMessage::JoinGame(JoinData::Name(name.to_string())))
+            .await
+    }
+
+    /// Send the next action. Its id is set automatically.
+    pub async fn send_action(&mut self, mut action: Action) -> ZResult<()> {
+        action.set_id(self.next_action_id);
+        self.next_action_id += 1;
+        self.send(PlayerMessage::Action(action)).await
+    }
+
+    /// Get the next message from the game, if there is one.
+    pub fn try_recv(&mut self) -> Option<PlayerMessage> {
+        self.receiver.try_recv().ok().map(|m| m.data)
+    }
+}
+
 pub enum PlayerConnectEvent {
     Connected(PlayerComm),
     Disconnected(PlayerId),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::powerup::PowerUpStat;
+    use serde_json::json;
+
+    #[test]
+    fn test_powerup_message() {
+        let powerup = PowerUp::with_stat("+B", PowerUpStat::MaxBombs, 3.0);
+        let name = PlayerMessage::PowerUp(PowerUpData::Name(powerup.name().to_string()));
+        assert_eq!(
+            serde_json::to_value(&name).unwrap(),
+            json!({"code": "POWERUP", "data": "+B"})
+        );
+
+        let detail = PlayerMessage::PowerUp(PowerUpData::Detail(powerup));
+        assert_eq!(
+            serde_json::to_value(&detail).unwrap(),
+            json!({
+                "code": "POWERUP",
+                "data": {"name": "+B", "stat": "maxBombs", "value": 3.0}
+            })
+        );
+    }
+
+    #[test]
+    fn test_join_message() {
+        let old: PlayerMessage =
+            serde_json::from_value(json!({"code": "JOINGAME", "data": "Bob"})).unwrap();
+        match old {
+            PlayerMessage::JoinGame(join) => {
+                assert_eq!(join.name(), "Bob");
+                assert_eq!(join.view_size(), None);
+                assert_eq!(join.image(), None);
+            }
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        let new: PlayerMessage = serde_json::from_value(json!({
+            "code": "JOINGAME",
+            "data": {"name": "Bob", "viewWidth": 50, "viewHeight": 40, "image": "p2"}
+        }))
+        .unwrap();
+        match new {
+            PlayerMessage::JoinGame(join) => {
+                assert_eq!(join.name(), "Bob");
+                assert_eq!(join.view_size(), Some(SizeInTiles::new(50, 40)));
+                assert_eq!(join.image(), Some("p2"));
+            }
+            x => panic!("Unexpected message: {:?}", x),
+        }
+    }
+}
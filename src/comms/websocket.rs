@@ -192,7 +192,7 @@ async fn process_websocket_write(
         }
 
         ws_tx
-            .send(Message::from(serde_json::to_value(&msg)?.to_string()))
+            .send(Message::from(serde_json::to_string(&msg)?))
             .await?;
     }
 
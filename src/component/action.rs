@@ -6,7 +6,11 @@ pub struct Action {
     y: i32,
     fire: bool,
     id: u32,
-    #[serde(rename = "deltaTime")]
+    // Place the bomb with the shortest fuse, if the player has a charge left.
+    #[serde(rename = "shortFuse", default)]
+    short_fuse: bool,
+    // Always set by the server. Any value sent by the client is ignored.
+    #[serde(rename = "deltaTime", skip_deserializing)]
     delta_time: f64,
 }
 
@@ -36,6 +40,16 @@ impl Action {
         Self::clamp(self.y)
     }
 
+    /// The sequence number assigned by the client. Each new action has a
+    /// higher id than the last.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
     pub fn fire(&self) -> bool {
         self.fire
     }
@@ -44,10 +58,19 @@ impl Action {
         self.fire = false;
     }
 
+    pub fn short_fuse(&self) -> bool {
+        self.short_fuse
+    }
+
+    pub fn set_short_fuse(&mut self, short_fuse: bool) {
+        self.short_fuse = short_fuse;
+    }
+
     pub fn clear(&mut self) {
         self.x = 0;
         self.y = 0;
         self.fire = false;
+        self.short_fuse = false;
         // self.id = 0;
         self.delta_time = 0.0;
     }
@@ -1,13 +1,19 @@
-use crate::traits::randenum::RandEnumFrom;
+use crate::{
+    traits::randenum::RandEnumFrom,
+    utils::misc::{GameClock, Timestamp},
+};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(Copy, Clone, Debug, Serialize_repr, Deserialize_repr)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize_repr, Deserialize_repr)]
 #[repr(u8)]
 pub enum EffectType {
     SpeedUp = 0,
     SlowDown = 1,
     Invincibility = 2,
+    FreezeMobs = 3,
+    Ghost = 4,
+    ReverseControls = 5,
 }
 
 impl EffectType {
@@ -16,6 +22,9 @@ impl EffectType {
             EffectType::SpeedUp => String::from(">>"),
             EffectType::SlowDown => String::from("<<"),
             EffectType::Invincibility => String::from("∞"),
+            EffectType::FreezeMobs => String::from("**"),
+            EffectType::Ghost => String::from("~"),
+            EffectType::ReverseControls => String::from("?!"),
         }
     }
 }
@@ -26,6 +35,9 @@ impl From<u8> for EffectType {
             0 => EffectType::SpeedUp,
             1 => EffectType::SlowDown,
             2 => EffectType::Invincibility,
+            3 => EffectType::FreezeMobs,
+            4 => EffectType::Ghost,
+            5 => EffectType::ReverseControls,
             _ => panic!("Invalid effect type: {}", value),
         }
     }
@@ -33,7 +45,7 @@ impl From<u8> for EffectType {
 
 impl RandEnumFrom<u8> for EffectType {
     fn get_enum_values() -> Vec<u8> {
-        (0..3).collect()
+        (0..6).collect()
     }
 }
 
@@ -43,14 +55,20 @@ pub struct Effect {
     pub effect_type: EffectType,
     pub remaining: f64,
     pub active: bool,
+    #[serde(skip)]
+    expires: Timestamp,
+    #[serde(skip)]
+    applied: f64,
 }
 
 impl Effect {
-    pub fn new(effect_type: EffectType, duration: f64) -> Self {
+    pub fn new(effect_type: EffectType, duration: f64, clock: &GameClock) -> Self {
         Effect {
             effect_type,
             remaining: duration,
             active: true,
+            expires: clock.now().saturating_add((duration * 1000.0) as i64),
+            applied: 0.0,
         }
     }
 
@@ -58,9 +76,32 @@ impl Effect {
         self.effect_type.name()
     }
 
-    pub fn tick(&mut self, delta_time: f64) {
-        self.remaining -= delta_time;
+    pub fn expires(&self) -> Timestamp {
+        self.expires
+    }
+
+    /// The amount this effect changed the player's speed by when applied.
+    pub fn applied(&self) -> f64 {
+        self.applied
+    }
+
+    pub fn set_applied(&mut self, applied: f64) {
+        self.applied = applied;
+    }
+
+    /// Extend this effect to last at least as long as the other one.
+    pub fn refresh(&mut self, other: &Effect) {
+        if other.expires > self.expires {
+            self.expires = other.expires;
+            self.remaining = other.remaining;
+        }
+    }
+
+    /// Update the remaining time from the game clock.
+    pub fn tick(&mut self, clock: &GameClock) {
+        self.remaining = self.expires.millis_since(clock.now()) as f64 / 1000.0;
         if self.remaining <= 0.0 {
+            self.remaining = 0.0;
             self.active = false;
         }
     }
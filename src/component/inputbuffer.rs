@@ -0,0 +1,123 @@
+use crate::component::action::Action;
+use std::collections::VecDeque;
+
+/// The default number of actions buffered for each player. This is one
+/// second of input at 30 FPS.
+pub const INPUT_BUFFER_SIZE: usize = 30;
+
+/// The outcome of adding an action to the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputPush {
+    Queued,
+    /// The action was a duplicate or arrived after a later one, so it was
+    /// dropped.
+    Stale,
+    /// The buffer was full, so the oldest action was dropped to make room.
+    Overflow,
+}
+
+/// A bounded queue of actions received from a client, applied one per tick in
+/// the order the client sent them.
+#[derive(Debug, Clone)]
+pub struct InputBuffer {
+    actions: VecDeque<Action>,
+    capacity: usize,
+    // The id of the most recent action accepted.
+    last_id: Option<u32>,
+}
+
+impl Default for InputBuffer {
+    fn default() -> Self {
+        InputBuffer::new(INPUT_BUFFER_SIZE)
+    }
+}
+
+impl InputBuffer {
+    pub fn new(capacity: usize) -> Self {
+        InputBuffer {
+            actions: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            last_id: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn push(&mut self, action: Action) -> InputPush {
+        if self.last_id.is_some_and(|id| action.id() <= id) {
+            return InputPush::Stale;
+        }
+
+        self.last_id = Some(action.id());
+        let result = if self.actions.len() >= self.capacity {
+            self.actions.pop_front();
+            InputPush::Overflow
+        } else {
+            InputPush::Queued
+        };
+        self.actions.push_back(action);
+        result
+    }
+
+    /// Get the next action to apply, if any.
+    pub fn pop(&mut self) -> Option<Action> {
+        self.actions.pop_front()
+    }
+
+    /// Drop any queued actions. Later actions must still have higher ids.
+    pub fn clear(&mut self) {
+        self.actions.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn action(id: u32) -> Action {
+        serde_json::from_value(json!({"x": 1, "y": 0, "fire": false, "id": id, "deltaTime": 0.0}))
+            .unwrap()
+    }
+
+    fn drain(buffer: &mut InputBuffer) -> Vec<u32> {
+        let mut ids = Vec::new();
+        while let Some(a) = buffer.pop() {
+            ids.push(a.id());
+        }
+        ids
+    }
+
+    #[test]
+    fn test_out_of_order_actions_dropped() {
+        let mut buffer = InputBuffer::new(10);
+        assert_eq!(buffer.push(action(1)), InputPush::Queued);
+        assert_eq!(buffer.push(action(3)), InputPush::Queued);
+        assert_eq!(buffer.push(action(2)), InputPush::Stale);
+        assert_eq!(buffer.push(action(3)), InputPush::Stale);
+        assert_eq!(buffer.push(action(4)), InputPush::Queued);
+        assert_eq!(drain(&mut buffer), vec![1, 3, 4]);
+
+        // Ids already applied are still stale.
+        assert_eq!(buffer.push(action(4)), InputPush::Stale);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_overflow_drops_oldest() {
+        let mut buffer = InputBuffer::new(3);
+        for id in 1..=3 {
+            assert_eq!(buffer.push(action(id)), InputPush::Queued);
+        }
+        assert_eq!(buffer.push(action(4)), InputPush::Overflow);
+        assert_eq!(buffer.push(action(5)), InputPush::Overflow);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(drain(&mut buffer), vec![3, 4, 5]);
+    }
+}
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+/// The player stat that a powerup changed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PowerUpStat {
+    MaxBombs,
+    Range,
+    BombTime,
+    WalkThroughBombs,
+    Score,
+    Effect,
+}
+
+/// A collected powerup, along with the new value of the stat it changed.
+/// For effects the value is the duration in seconds, and for flags it is
+/// 1 if the flag was added and 0 if it was removed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerUp {
+    name: String,
+    stat: Option<PowerUpStat>,
+    value: Option<f64>,
+}
+
+impl PowerUp {
+    pub fn new(name: &str) -> Self {
+        PowerUp {
+            name: name.to_string(),
+            stat: None,
+            value: None,
+        }
+    }
+
+    pub fn with_stat(name: &str, stat: PowerUpStat, value: f64) -> Self {
+        PowerUp {
+            name: name.to_string(),
+            stat: Some(stat),
+            value: Some(value),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn stat(&self) -> Option<PowerUpStat> {
+        self.stat
+    }
+
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+/// The payload of a POWERUP message. Players that haven't asked for
+/// details are sent just the name, which is all older clients understand.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PowerUpData {
+    Name(String),
+    Detail(PowerUp),
+}
@@ -4,15 +4,16 @@ use crate::{
         position::MapPosition,
     },
     tools::itemstore::HasId,
-    utils::misc::Timestamp,
+    utils::misc::{GameClock, Timestamp},
 };
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::{
     ops::{Add, AddAssign, Deref, SubAssign},
     time::Duration,
 };
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct BombId(u64);
 
@@ -65,6 +66,9 @@ impl SubAssign<u32> for BombRange {
 pub struct BombTime(f64);
 
 impl BombTime {
+    /// The shortest fuse a bomb can have.
+    pub const MIN: BombTime = BombTime(2.0);
+
     pub fn millis(self) -> f64 {
         self.0 * 1000.0
     }
@@ -143,10 +147,17 @@ pub struct Bomb {
     remaining: BombTime,
     range: BombRange,
     timestamp: Timestamp,
+    // A dud never explodes. Players can't tell until it fails to go off.
+    #[serde(skip)]
+    dud: bool,
+    // Players who were standing on the bomb when it was placed, and haven't
+    // stepped off it yet.
+    #[serde(skip)]
+    overlapping: Vec<PlayerId>,
 }
 
 impl Bomb {
-    pub fn new(player: &Player, position: MapPosition) -> Self {
+    pub fn new(player: &Player, position: MapPosition, clock: &GameClock) -> Self {
         Bomb {
             id: BombId::from(0),
             pid: player.id(),
@@ -156,7 +167,9 @@ impl Bomb {
             remaining: player.bomb_time(),
             range: player.range(),
             // Set the timestamp to the explosion timestamp
-            timestamp: Timestamp::new() + player.bomb_time(),
+            timestamp: clock.now() + player.bomb_time(),
+            dud: false,
+            overlapping: Vec::new(),
         }
     }
 
@@ -188,6 +201,41 @@ impl Bomb {
         self.timestamp
     }
 
+    /// Change the fuse, starting from now.
+    pub fn set_fuse(&mut self, fuse: BombTime, clock: &GameClock) {
+        self.remaining = fuse;
+        self.timestamp = clock.now() + fuse;
+    }
+
+    pub fn is_dud(&self) -> bool {
+        self.dud
+    }
+
+    pub fn set_dud(&mut self, dud: bool) {
+        self.dud = dud;
+    }
+
+    /// Make this bomb a dud with the specified probability.
+    pub fn roll_dud(&mut self, chance: f64) {
+        self.dud = chance > 0.0 && rand::thread_rng().gen_bool(chance.min(1.0));
+    }
+
+    pub fn add_overlapping(&mut self, pid: PlayerId) {
+        if !self.overlapping.contains(&pid) {
+            self.overlapping.push(pid);
+        }
+    }
+
+    /// True if the player was on the bomb when it was placed and hasn't left
+    /// it since.
+    pub fn is_overlapping(&self, pid: PlayerId) -> bool {
+        self.overlapping.contains(&pid)
+    }
+
+    pub fn release_overlapping(&mut self, pid: PlayerId) {
+        self.overlapping.retain(|p| *p != pid);
+    }
+
     pub fn tick(&mut self, delta_time: f64) -> bool {
         self.remaining -= delta_time;
         if self.remaining.is_done() {
@@ -1,6 +1,279 @@
+use crate::engine::position::SizeInTiles;
+use log::LevelFilter;
+use rand::Rng;
+
+/// Rules for player effects such as speed up and invincibility.
+#[derive(Debug, Clone)]
+pub struct EffectConfig {
+    max_stacks: usize,
+    freeze_radius: i32,
+    speed_delta: f64,
+    min_speed: f64,
+    max_speed: f64,
+    min_random_duration: f64,
+    max_random_duration: f64,
+    spawn_invincibility: f64,
+}
+
+impl Default for EffectConfig {
+    fn default() -> Self {
+        EffectConfig {
+            max_stacks: 3,
+            freeze_radius: 6,
+            speed_delta: 50.0,
+            min_speed: 50.0,
+            max_speed: 300.0,
+            min_random_duration: 3.0,
+            max_random_duration: 10.0,
+            spawn_invincibility: 5.0,
+        }
+    }
+}
+
+impl EffectConfig {
+    pub fn new() -> Self {
+        EffectConfig::default()
+    }
+
+    /// The maximum number of effects of the same type a player can have at
+    /// once. Further effects of that type refresh an existing one instead.
+    pub fn max_stacks(&self) -> usize {
+        self.max_stacks
+    }
+
+    pub fn set_max_stacks(&mut self, max_stacks: usize) {
+        self.max_stacks = max_stacks;
+    }
+
+    /// Mobs within this many tiles of a player with the FreezeMobs effect
+    /// do not move.
+    pub fn freeze_radius(&self) -> i32 {
+        self.freeze_radius
+    }
+
+    pub fn set_freeze_radius(&mut self, radius: i32) {
+        self.freeze_radius = radius;
+    }
+
+    /// The amount the SpeedUp and SlowDown effects change a player's speed by.
+    pub fn speed_delta(&self) -> f64 {
+        self.speed_delta
+    }
+
+    pub fn set_speed_delta(&mut self, delta: f64) {
+        self.speed_delta = delta;
+    }
+
+    /// Players never move slower than this, however many effects they have.
+    pub fn min_speed(&self) -> f64 {
+        self.min_speed
+    }
+
+    /// Players never move faster than this, however many effects they have.
+    pub fn max_speed(&self) -> f64 {
+        self.max_speed
+    }
+
+    pub fn set_speed_range(&mut self, min_speed: f64, max_speed: f64) {
+        self.min_speed = min_speed;
+        self.max_speed = max_speed;
+    }
+
+    /// The shortest duration in seconds for a random effect.
+    pub fn min_random_duration(&self) -> f64 {
+        self.min_random_duration
+    }
+
+    /// The longest duration in seconds for a random effect.
+    pub fn max_random_duration(&self) -> f64 {
+        self.max_random_duration
+    }
+
+    pub fn set_random_duration(&mut self, min_seconds: f64, max_seconds: f64) {
+        self.min_random_duration = min_seconds;
+        self.max_random_duration = max_seconds;
+    }
+
+    /// How long in seconds a player is invincible for after spawning.
+    /// 0 means not at all.
+    pub fn spawn_invincibility(&self) -> f64 {
+        self.spawn_invincibility
+    }
+
+    pub fn set_spawn_invincibility(&mut self, seconds: f64) {
+        self.spawn_invincibility = seconds;
+    }
+}
+
+/// Points awarded or removed for everything that changes a player's score.
+#[derive(Debug, Clone)]
+pub struct ScoreConfig {
+    player_kill: u32,
+    mob_kill: u32,
+    smart_mob_kill: u32,
+    mystery_block: u32,
+    min_random_points: u32,
+    max_random_points: u32,
+    decay_rate: f64,
+    combo_window: f64,
+    max_combo: u32,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> Self {
+        ScoreConfig {
+            player_kill: 1000,
+            mob_kill: 500,
+            smart_mob_kill: 2000,
+            mystery_block: 10,
+            min_random_points: 10,
+            max_random_points: 90,
+            decay_rate: 0.0,
+            combo_window: 3.0,
+            max_combo: 4,
+        }
+    }
+}
+
+impl ScoreConfig {
+    pub fn new() -> Self {
+        ScoreConfig::default()
+    }
+
+    /// Points for killing another player.
+    pub fn player_kill(&self) -> u32 {
+        self.player_kill
+    }
+
+    pub fn set_player_kill(&mut self, points: u32) {
+        self.player_kill = points;
+    }
+
+    /// Points for killing a mob.
+    pub fn mob_kill(&self) -> u32 {
+        self.mob_kill
+    }
+
+    pub fn set_mob_kill(&mut self, points: u32) {
+        self.mob_kill = points;
+    }
+
+    /// Points for killing a smart mob.
+    pub fn smart_mob_kill(&self) -> u32 {
+        self.smart_mob_kill
+    }
+
+    pub fn set_smart_mob_kill(&mut self, points: u32) {
+        self.smart_mob_kill = points;
+    }
+
+    /// Points for each mystery block destroyed by a player's bomb.
+    pub fn mystery_block(&self) -> u32 {
+        self.mystery_block
+    }
+
+    pub fn set_mystery_block(&mut self, points: u32) {
+        self.mystery_block = points;
+    }
+
+    /// The +$ and -$ random items give or take a multiple of 10 points
+    /// between these values.
+    pub fn min_random_points(&self) -> u32 {
+        self.min_random_points
+    }
+
+    pub fn max_random_points(&self) -> u32 {
+        self.max_random_points
+    }
+
+    pub fn set_random_points(&mut self, min_points: u32, max_points: u32) {
+        self.min_random_points = min_points;
+        self.max_random_points = max_points;
+    }
+
+    /// Points per second removed from the score of every active player, to
+    /// discourage camping. Zero disables decay.
+    pub fn decay_rate(&self) -> f64 {
+        self.decay_rate
+    }
+
+    pub fn set_decay_rate(&mut self, points_per_second: f64) {
+        self.decay_rate = points_per_second;
+    }
+
+    /// Kills within this many seconds of the previous kill by the same
+    /// player increase their combo multiplier.
+    pub fn combo_window(&self) -> f64 {
+        self.combo_window
+    }
+
+    pub fn set_combo_window(&mut self, seconds: f64) {
+        self.combo_window = seconds;
+    }
+
+    /// The highest multiplier a combo can reach. 1 disables combos.
+    pub fn max_combo(&self) -> u32 {
+        self.max_combo
+    }
+
+    pub fn set_max_combo(&mut self, max_combo: u32) {
+        self.max_combo = max_combo;
+    }
+
+    /// Roll a random number of points for the +$ and -$ items.
+    pub fn random_points<R: Rng>(&self, rng: &mut R) -> u32 {
+        let min = self.min_random_points / 10;
+        let max = (self.max_random_points / 10).max(min);
+        rng.gen_range(min, max + 1) * 10
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct GameConfig {
     screen_x: u32,
     screen_y: u32,
+    max_view_size: SizeInTiles,
+    small_map_full_world: bool,
+    world_delta_updates: bool,
+    max_delta_time: f64,
+    idle_interval: f64,
+    powerup_details: bool,
+    cell_legend: bool,
+    sound_events: bool,
+    interpolation: bool,
+    danger_tiles: bool,
+    input_hold_frames: u32,
+    scale_mob_spawns: bool,
+    min_name_length: usize,
+    min_mob_spawn_interval: f64,
+    max_blocks_per_populate: usize,
+    powerups_enabled: bool,
+    drop_powerups_on_death: bool,
+    max_dropped_powerups: u32,
+    pickup_radius: f64,
+    bomb_cooldown: f64,
+    dud_chance: f64,
+    bomb_exit_grace: bool,
+    require_bomb_exit: bool,
+    lethal_intensity: f64,
+    diagonal_movement: bool,
+    solid_players: bool,
+    sudden_death_after: Option<f64>,
+    sudden_death_interval: f64,
+    round_time: Option<f64>,
+    last_player_standing: bool,
+    warmup_time: f64,
+    round_end_time: f64,
+    stats_file: Option<String>,
+    metrics_port: Option<u16>,
+    log_level: LevelFilter,
+    log_file: Option<String>,
+    event_log: Option<String>,
+    admin_socket: Option<String>,
+    max_rooms: usize,
+    phase_timings: bool,
+    scores: ScoreConfig,
+    effects: EffectConfig,
 }
 
 impl Default for GameConfig {
@@ -8,6 +281,48 @@ impl Default for GameConfig {
         GameConfig {
             screen_x: 800,
             screen_y: 600,
+            max_view_size: SizeInTiles::new(70, 44),
+            small_map_full_world: false,
+            world_delta_updates: false,
+            max_delta_time: 0.25,
+            idle_interval: 0.5,
+            powerup_details: false,
+            cell_legend: false,
+            sound_events: false,
+            interpolation: false,
+            danger_tiles: false,
+            input_hold_frames: 0,
+            scale_mob_spawns: false,
+            min_name_length: 1,
+            min_mob_spawn_interval: 1.0,
+            max_blocks_per_populate: 4,
+            powerups_enabled: true,
+            drop_powerups_on_death: false,
+            max_dropped_powerups: 6,
+            pickup_radius: 0.0,
+            bomb_cooldown: 0.25,
+            dud_chance: 0.0,
+            bomb_exit_grace: false,
+            require_bomb_exit: false,
+            lethal_intensity: 0.0,
+            diagonal_movement: false,
+            solid_players: false,
+            sudden_death_after: None,
+            sudden_death_interval: 10.0,
+            round_time: None,
+            last_player_standing: false,
+            warmup_time: 10.0,
+            round_end_time: 5.0,
+            stats_file: None,
+            metrics_port: None,
+            log_level: LevelFilter::Debug,
+            log_file: None,
+            event_log: None,
+            admin_socket: None,
+            max_rooms: 1,
+            phase_timings: false,
+            scores: ScoreConfig::default(),
+            effects: EffectConfig::default(),
         }
     }
 }
@@ -24,4 +339,436 @@ impl GameConfig {
     pub fn screen_height(&self) -> u32 {
         self.screen_y
     }
+
+    /// The largest view size, in tiles, that a client may request.
+    pub fn max_view_size(&self) -> SizeInTiles {
+        self.max_view_size
+    }
+
+    pub fn set_max_view_size(&mut self, size: SizeInTiles) {
+        self.max_view_size = size;
+    }
+
+    /// If true, maps that fit inside a single chunk are sent in full, and only
+    /// when they have changed since the last frame sent to that player.
+    /// Frames with no world changes will contain a null world. This only
+    /// applies to clients that asked for a partial world when joining.
+    pub fn small_map_full_world(&self) -> bool {
+        self.small_map_full_world
+    }
+
+    pub fn set_small_map_full_world(&mut self, enabled: bool) {
+        self.small_map_full_world = enabled;
+    }
+
+    /// If true, players are sent a full chunk only when their chunk moves.
+    /// Otherwise only the changed cells are sent, via a WORLDDELTA message.
+    /// Clients that didn't ask for a partial world when joining still get the
+    /// chunk in every frame.
+    pub fn world_delta_updates(&self) -> bool {
+        self.world_delta_updates
+    }
+
+    pub fn set_world_delta_updates(&mut self, enabled: bool) {
+        self.world_delta_updates = enabled;
+    }
+
+    /// The maximum time in seconds that a single frame can advance the game.
+    pub fn max_delta_time(&self) -> f64 {
+        self.max_delta_time
+    }
+
+    pub fn set_max_delta_time(&mut self, seconds: f64) {
+        self.max_delta_time = seconds;
+    }
+
+    /// The time to advance the game by for a frame that took `delta_time`
+    /// seconds, so that the game doesn't try to catch up all at once after a
+    /// stall.
+    pub fn clamp_delta_time(&self, delta_time: f64) -> f64 {
+        delta_time.min(self.max_delta_time)
+    }
+
+    /// The number of seconds to wait between checks for new players while
+    /// nobody is connected.
+    pub fn idle_interval(&self) -> f64 {
+        self.idle_interval
+    }
+
+    pub fn set_idle_interval(&mut self, seconds: f64) {
+        self.idle_interval = seconds;
+    }
+
+    /// If true, POWERUP messages carry an object with the powerup name, the
+    /// stat it changed and the new value. Otherwise only the name is sent.
+    pub fn powerup_details(&self) -> bool {
+        self.powerup_details
+    }
+
+    pub fn set_powerup_details(&mut self, enabled: bool) {
+        self.powerup_details = enabled;
+    }
+
+    /// If true, players are sent a CELLLEGEND message naming each cell type
+    /// code when they join.
+    pub fn cell_legend(&self) -> bool {
+        self.cell_legend
+    }
+
+    pub fn set_cell_legend(&mut self, enabled: bool) {
+        self.cell_legend = enabled;
+    }
+
+    /// If true, players are sent BOMBPLACED and ITEMCOLLECTED messages for
+    /// anything that happens within their view, so that they can play sounds.
+    pub fn sound_events(&self) -> bool {
+        self.sound_events
+    }
+
+    pub fn set_sound_events(&mut self, enabled: bool) {
+        self.sound_events = enabled;
+    }
+
+    /// If true, players and mobs in frame data also include their position
+    /// from the previous frame, so that clients can interpolate between them.
+    pub fn interpolation(&self) -> bool {
+        self.interpolation
+    }
+
+    pub fn set_interpolation(&mut self, enabled: bool) {
+        self.interpolation = enabled;
+    }
+
+    /// If true, frame data includes every tile in view that is in the path of
+    /// a bomb, with the time until it goes off.
+    pub fn danger_tiles(&self) -> bool {
+        self.danger_tiles
+    }
+
+    pub fn set_danger_tiles(&mut self, enabled: bool) {
+        self.danger_tiles = enabled;
+    }
+
+    /// The number of frames a player keeps moving with their last action
+    /// when no new input arrives, to smooth over network jitter.
+    pub fn input_hold_frames(&self) -> u32 {
+        self.input_hold_frames
+    }
+
+    pub fn set_input_hold_frames(&mut self, frames: u32) {
+        self.input_hold_frames = frames;
+    }
+
+    /// Names shorter than this, after removing invalid characters, are
+    /// rejected when joining. Names are always at least 1 character.
+    pub fn min_name_length(&self) -> usize {
+        self.min_name_length
+    }
+
+    pub fn set_min_name_length(&mut self, length: usize) {
+        self.min_name_length = length;
+    }
+
+    /// If true, mobs spawn more often the more players there are.
+    pub fn scale_mob_spawns(&self) -> bool {
+        self.scale_mob_spawns
+    }
+
+    pub fn set_scale_mob_spawns(&mut self, enabled: bool) {
+        self.scale_mob_spawns = enabled;
+    }
+
+    /// When mob spawns are scaled, they never happen more often than this
+    /// many seconds apart.
+    pub fn min_mob_spawn_interval(&self) -> f64 {
+        self.min_mob_spawn_interval
+    }
+
+    pub fn set_min_mob_spawn_interval(&mut self, seconds: f64) {
+        self.min_mob_spawn_interval = seconds;
+    }
+
+    /// The most mystery blocks added each time the map is topped up.
+    pub fn max_blocks_per_populate(&self) -> usize {
+        self.max_blocks_per_populate
+    }
+
+    pub fn set_max_blocks_per_populate(&mut self, max_blocks: usize) {
+        self.max_blocks_per_populate = max_blocks;
+    }
+
+    /// If false, destroyed blocks never drop items and players don't scatter
+    /// their powerups when they die, so there are no powerups at all.
+    pub fn powerups_enabled(&self) -> bool {
+        self.powerups_enabled
+    }
+
+    pub fn set_powerups_enabled(&mut self, enabled: bool) {
+        self.powerups_enabled = enabled;
+    }
+
+    /// If true, a player's extra bombs and range are scattered around them as
+    /// items when they die.
+    pub fn drop_powerups_on_death(&self) -> bool {
+        self.drop_powerups_on_death
+    }
+
+    pub fn set_drop_powerups_on_death(&mut self, enabled: bool) {
+        self.drop_powerups_on_death = enabled;
+    }
+
+    /// The most items a single player can drop on death.
+    pub fn max_dropped_powerups(&self) -> u32 {
+        self.max_dropped_powerups
+    }
+
+    pub fn set_max_dropped_powerups(&mut self, max_items: u32) {
+        self.max_dropped_powerups = max_items;
+    }
+
+    /// How close, as a fraction of a tile, a player needs to come to the
+    /// tile next to them to collect the item on it. At 0 only items on the
+    /// player's own tile are collected. Never more than half a tile.
+    pub fn pickup_radius(&self) -> f64 {
+        self.pickup_radius
+    }
+
+    pub fn set_pickup_radius(&mut self, radius: f64) {
+        self.pickup_radius = radius.clamp(0.0, 0.5);
+    }
+
+    /// The minimum number of seconds between bombs placed by the same player.
+    pub fn bomb_cooldown(&self) -> f64 {
+        self.bomb_cooldown
+    }
+
+    pub fn set_bomb_cooldown(&mut self, seconds: f64) {
+        self.bomb_cooldown = seconds;
+    }
+
+    /// The chance, from 0 to 1, that a bomb is a dud and never explodes.
+    pub fn dud_chance(&self) -> f64 {
+        self.dud_chance
+    }
+
+    pub fn set_dud_chance(&mut self, chance: f64) {
+        self.dud_chance = chance;
+    }
+
+    /// If true, anyone standing on a bomb when it is placed can walk about on
+    /// it until they have stepped fully off the tile. Otherwise it is solid
+    /// as soon as their centre leaves it.
+    pub fn bomb_exit_grace(&self) -> bool {
+        self.bomb_exit_grace
+    }
+
+    pub fn set_bomb_exit_grace(&mut self, enabled: bool) {
+        self.bomb_exit_grace = enabled;
+    }
+
+    /// If true, a bomb can only be placed where the player could step off
+    /// it onto a neighbouring tile.
+    pub fn require_bomb_exit(&self) -> bool {
+        self.require_bomb_exit
+    }
+
+    pub fn set_require_bomb_exit(&mut self, enabled: bool) {
+        self.require_bomb_exit = enabled;
+    }
+
+    /// Explosions fade towards the edge of a blast. Players caught in one
+    /// weaker than this, from 0 to 1, are slowed down rather than killed.
+    /// At 0 every explosion is lethal.
+    pub fn lethal_intensity(&self) -> f64 {
+        self.lethal_intensity
+    }
+
+    pub fn set_lethal_intensity(&mut self, intensity: f64) {
+        self.lethal_intensity = intensity.clamp(0.0, 1.0);
+    }
+
+    /// If true, players can move diagonally through open space.
+    pub fn diagonal_movement(&self) -> bool {
+        self.diagonal_movement
+    }
+
+    pub fn set_diagonal_movement(&mut self, enabled: bool) {
+        self.diagonal_movement = enabled;
+    }
+
+    /// If true, players can't walk onto a tile occupied by another player.
+    pub fn solid_players(&self) -> bool {
+        self.solid_players
+    }
+
+    pub fn set_solid_players(&mut self, enabled: bool) {
+        self.solid_players = enabled;
+    }
+
+    /// The number of seconds after which the arena starts to shrink, if it
+    /// should shrink at all.
+    pub fn sudden_death_after(&self) -> Option<f64> {
+        self.sudden_death_after
+    }
+
+    pub fn set_sudden_death_after(&mut self, seconds: f64) {
+        self.sudden_death_after = Some(seconds);
+    }
+
+    /// The number of seconds between each ring of the arena being walled in.
+    pub fn sudden_death_interval(&self) -> f64 {
+        self.sudden_death_interval
+    }
+
+    pub fn set_sudden_death_interval(&mut self, seconds: f64) {
+        self.sudden_death_interval = seconds;
+    }
+
+    /// The length of each round in seconds, if rounds are timed.
+    pub fn round_time(&self) -> Option<f64> {
+        self.round_time
+    }
+
+    pub fn set_round_time(&mut self, seconds: f64) {
+        self.round_time = Some(seconds);
+    }
+
+    /// If true, a round ends when only one player is left alive.
+    pub fn last_player_standing(&self) -> bool {
+        self.last_player_standing
+    }
+
+    pub fn set_last_player_standing(&mut self, enabled: bool) {
+        self.last_player_standing = enabled;
+    }
+
+    /// Matches are played in rounds if either win condition is enabled.
+    pub fn match_rounds(&self) -> bool {
+        self.round_time.is_some() || self.last_player_standing
+    }
+
+    /// The number of seconds of warmup before each round starts.
+    pub fn warmup_time(&self) -> f64 {
+        self.warmup_time
+    }
+
+    pub fn set_warmup_time(&mut self, seconds: f64) {
+        self.warmup_time = seconds;
+    }
+
+    /// The number of seconds the results are shown for before the next round.
+    pub fn round_end_time(&self) -> f64 {
+        self.round_end_time
+    }
+
+    pub fn set_round_end_time(&mut self, seconds: f64) {
+        self.round_end_time = seconds;
+    }
+
+    /// The file that cumulative player stats are saved to, if any. Files
+    /// ending in .db or .sqlite are SQLite databases, anything else is JSON.
+    pub fn stats_file(&self) -> Option<&str> {
+        self.stats_file.as_deref()
+    }
+
+    pub fn set_stats_file(&mut self, path: &str) {
+        self.stats_file = Some(path.to_string());
+    }
+
+    /// The port to serve Prometheus metrics on, if any.
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.metrics_port
+    }
+
+    pub fn set_metrics_port(&mut self, port: u16) {
+        self.metrics_port = Some(port);
+    }
+
+    /// The level to log messages from this crate at.
+    /// Other crates are not logged.
+    pub fn log_level(&self) -> LevelFilter {
+        self.log_level
+    }
+
+    pub fn set_log_level(&mut self, level: LevelFilter) {
+        self.log_level = level;
+    }
+
+    /// A file to log to, in addition to stdout.
+    pub fn log_file(&self) -> Option<&str> {
+        self.log_file.as_deref()
+    }
+
+    pub fn set_log_file(&mut self, path: &str) {
+        self.log_file = Some(path.to_string());
+    }
+
+    /// The file to write gameplay events to as JSON lines, if any.
+    /// A path of "-" means stdout.
+    pub fn event_log(&self) -> Option<&str> {
+        self.event_log.as_deref()
+    }
+
+    pub fn set_event_log(&mut self, path: &str) {
+        self.event_log = Some(path.to_string());
+    }
+
+    /// The Unix socket to accept admin commands on, if any.
+    pub fn admin_socket(&self) -> Option<&str> {
+        self.admin_socket.as_deref()
+    }
+
+    pub fn set_admin_socket(&mut self, path: &str) {
+        self.admin_socket = Some(path.to_string());
+    }
+
+    /// The most game rooms that can run at once, including the default room.
+    /// With only one, every player joins the default room whatever they ask
+    /// for.
+    pub fn max_rooms(&self) -> usize {
+        self.max_rooms
+    }
+
+    pub fn set_max_rooms(&mut self, max_rooms: usize) {
+        self.max_rooms = max_rooms;
+    }
+
+    /// A copy of this config for any room other than the default one. Only
+    /// one game can own the stats file, event log, metrics port and admin
+    /// socket, so these are left out.
+    pub fn for_extra_room(&self) -> Self {
+        GameConfig {
+            stats_file: None,
+            metrics_port: None,
+            event_log: None,
+            admin_socket: None,
+            ..self.clone()
+        }
+    }
+
+    /// If true, the time spent in each phase of the game loop is logged at
+    /// debug level along with the FPS.
+    pub fn phase_timings(&self) -> bool {
+        self.phase_timings
+    }
+
+    pub fn set_phase_timings(&mut self, enabled: bool) {
+        self.phase_timings = enabled;
+    }
+
+    pub fn scores(&self) -> &ScoreConfig {
+        &self.scores
+    }
+
+    pub fn set_scores(&mut self, scores: ScoreConfig) {
+        self.scores = scores;
+    }
+
+    pub fn effects(&self) -> &EffectConfig {
+        &self.effects
+    }
+
+    pub fn set_effects(&mut self, effects: EffectConfig) {
+        self.effects = effects;
+    }
 }
@@ -0,0 +1,81 @@
+use crate::{
+    engine::{
+        bomb::BombId,
+        explosion::ExplosionId,
+        mob::MobId,
+        player::PlayerId,
+        position::{MapPosition, SizeInTiles},
+    },
+    tools::spatialgrid::SpatialGrid,
+};
+
+/// The ids of all entities found within a region.
+#[derive(Debug, Default)]
+pub struct NearbyEntities {
+    pub players: Vec<PlayerId>,
+    pub mobs: Vec<MobId>,
+    pub bombs: Vec<BombId>,
+    pub explosions: Vec<ExplosionId>,
+}
+
+/// Map positions of every entity, used to find everything near a player
+/// without scanning every list. This is rebuilt every frame.
+#[derive(Debug, Clone)]
+pub struct EntityIndex {
+    players: SpatialGrid<PlayerId>,
+    mobs: SpatialGrid<MobId>,
+    bombs: SpatialGrid<BombId>,
+    explosions: SpatialGrid<ExplosionId>,
+}
+
+impl EntityIndex {
+    pub fn new(bucket_size: SizeInTiles, map_size: SizeInTiles) -> Self {
+        EntityIndex {
+            players: SpatialGrid::new(bucket_size, map_size),
+            mobs: SpatialGrid::new(bucket_size, map_size),
+            bombs: SpatialGrid::new(bucket_size, map_size),
+            explosions: SpatialGrid::new(bucket_size, map_size),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.players.clear();
+        self.mobs.clear();
+        self.bombs.clear();
+        self.explosions.clear();
+    }
+
+    pub fn add_player(&mut self, pos: MapPosition, id: PlayerId) {
+        self.players.insert(pos, id);
+    }
+
+    pub fn add_mob(&mut self, pos: MapPosition, id: MobId) {
+        self.mobs.insert(pos, id);
+    }
+
+    pub fn add_bomb(&mut self, pos: MapPosition, id: BombId) {
+        self.bombs.insert(pos, id);
+    }
+
+    pub fn add_explosion(&mut self, pos: MapPosition, id: ExplosionId) {
+        self.explosions.insert(pos, id);
+    }
+
+    /// Get all entities within the specified grid around centre.
+    /// Mobs, bombs and explosions are returned in id order.
+    pub fn query_region(&self, centre: MapPosition, xrange: i32, yrange: i32) -> NearbyEntities {
+        let mut nearby = NearbyEntities {
+            players: self.players.query_region(centre, xrange, yrange).collect(),
+            mobs: self.mobs.query_region(centre, xrange, yrange).collect(),
+            bombs: self.bombs.query_region(centre, xrange, yrange).collect(),
+            explosions: self
+                .explosions
+                .query_region(centre, xrange, yrange)
+                .collect(),
+        };
+        nearby.mobs.sort();
+        nearby.bombs.sort();
+        nearby.explosions.sort();
+        nearby
+    }
+}
@@ -1,11 +1,19 @@
 use crate::{
-    engine::{bomb::Bomb, player::PlayerId, position::MapPosition},
+    engine::{
+        bomb::{Bomb, BombId},
+        player::PlayerId,
+        position::MapPosition,
+    },
     tools::itemstore::HasId,
-    utils::misc::Timestamp,
+    utils::misc::{GameClock, Timestamp},
 };
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+// How long an explosion lasts, and how long it stays harmful for.
+const EXPLOSION_MILLIS: i64 = 500;
+const EXPLOSION_HARMFUL_MILLIS: i64 = 200;
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct ExplosionId(u64);
 
@@ -20,25 +28,36 @@ pub struct Explosion {
     id: ExplosionId,
     pid: PlayerId,
     pname: String,
+    // The bomb that caused this explosion. Every tile of a blast shares it.
+    #[serde(rename = "blastId", skip_serializing_if = "Option::is_none")]
+    blast_id: Option<BombId>,
     active: bool,
     #[serde(flatten)]
     position: MapPosition,
+    // Seconds until the explosion is over, for the client's effects. Always
+    // derived from the game clock in update(), never counted down.
     remaining: f64,
     harmful: bool,
+    // 1 at the centre of the blast, fading towards the edge.
+    #[serde(skip)]
+    intensity: f64,
     timestamp: Timestamp,
 }
 
 impl Explosion {
-    pub fn new(bomb: Option<&Bomb>, position: MapPosition) -> Self {
+    /// Explosions without a bomb are for display only and are harmless.
+    pub fn new(bomb: Option<&Bomb>, position: MapPosition, clock: &GameClock) -> Self {
         Explosion {
             id: ExplosionId::from(0),
             pid: bomb.map_or(PlayerId::from(0), |x| x.pid()),
             pname: bomb.map_or(String::new(), |x| x.pname().to_owned()),
+            blast_id: bomb.map(|x| x.id()),
             active: true,
             position,
-            remaining: 0.5,
+            remaining: EXPLOSION_MILLIS as f64 / 1000.0,
             harmful: bomb.is_some(),
-            timestamp: Timestamp::new(),
+            intensity: 1.0,
+            timestamp: clock.now(),
         }
     }
 
@@ -54,6 +73,11 @@ impl Explosion {
         &self.pname
     }
 
+    /// The id of the bomb that caused this explosion, if any.
+    pub fn blast_id(&self) -> Option<BombId> {
+        self.blast_id
+    }
+
     pub fn is_active(&self) -> bool {
         self.active
     }
@@ -62,6 +86,14 @@ impl Explosion {
         self.harmful
     }
 
+    pub fn intensity(&self) -> f64 {
+        self.intensity
+    }
+
+    pub fn set_intensity(&mut self, intensity: f64) {
+        self.intensity = intensity;
+    }
+
     pub fn position(&self) -> MapPosition {
         self.position
     }
@@ -70,14 +102,18 @@ impl Explosion {
         self.timestamp
     }
 
-    pub fn update(&mut self, delta_time: f64) {
-        self.remaining -= delta_time;
-        if self.remaining <= 0.3 {
+    /// The time at which this explosion is over.
+    pub fn end_time(&self) -> Timestamp {
+        self.timestamp.saturating_add(EXPLOSION_MILLIS)
+    }
+
+    pub fn update(&mut self, now: Timestamp) {
+        self.remaining = self.end_time().millis_since(now).max(0) as f64 / 1000.0;
+        if now.millis_since(self.timestamp) >= EXPLOSION_HARMFUL_MILLIS {
             self.harmful = false;
         }
 
-        if self.remaining <= 0.0 {
-            self.remaining = 0.0;
+        if now >= self.end_time() {
             self.active = false;
         }
     }
@@ -89,32 +125,59 @@ impl HasId<ExplosionId> for Explosion {
     }
 }
 
-impl From<MapPosition> for Explosion {
-    fn from(position: MapPosition) -> Self {
-        Explosion {
-            id: ExplosionId::from(0),
-            pid: PlayerId::from(0),
-            pname: String::new(),
-            active: true,
-            position,
-            remaining: 0.5,
-            harmful: true,
-            timestamp: Timestamp::new(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::player::{PlayerBuilder, PlayerId};
+    use serde_json::json;
+
+    #[test]
+    fn test_explosion_ends_on_clock() {
+        let mut clock = GameClock::new();
+        let mut explosion = Explosion::new(None, MapPosition::new(1, 1), &clock);
+        let end_time = explosion.end_time();
+        assert_eq!(end_time.millis_since(clock.now()), EXPLOSION_MILLIS);
+
+        // Uneven steps, so the clock doesn't land on the end time exactly.
+        for _ in 0..100 {
+            clock.advance(0.007);
+            explosion.update(clock.now());
+            assert_eq!(explosion.is_active(), clock.now() < end_time);
         }
+        assert!(!explosion.is_active());
     }
-}
 
-impl From<(Bomb, MapPosition)> for Explosion {
-    fn from(bomb: (Bomb, MapPosition)) -> Self {
-        Explosion {
-            id: ExplosionId::from(0),
-            pid: bomb.0.pid(),
-            pname: bomb.0.pname().to_string(),
-            active: true,
-            position: bomb.1,
-            remaining: 0.5,
-            harmful: true,
-            timestamp: Timestamp::new(),
-        }
+    #[test]
+    fn test_remaining_follows_clock() {
+        let mut clock = GameClock::new();
+        let mut explosion = Explosion::new(None, MapPosition::new(1, 1), &clock);
+        let value = serde_json::to_value(&explosion).unwrap();
+        assert_eq!(value["remaining"], json!(0.5));
+
+        clock.advance(0.2);
+        explosion.update(clock.now());
+        let value = serde_json::to_value(&explosion).unwrap();
+        assert_eq!(value["remaining"], json!(0.3));
+
+        clock.advance(1.0);
+        explosion.update(clock.now());
+        let value = serde_json::to_value(&explosion).unwrap();
+        assert_eq!(value["remaining"], json!(0.0));
+    }
+
+    #[test]
+    fn test_owner_serialized() {
+        let clock = GameClock::new();
+        let player = PlayerBuilder::new(PlayerId::from(7)).name("Bob").build();
+        let pos = MapPosition::new(3, 3);
+        let bomb = Bomb::new(&player, pos, &clock);
+        let value = serde_json::to_value(Explosion::new(Some(&bomb), pos, &clock)).unwrap();
+        assert_eq!(value["pid"], json!(7));
+        assert_eq!(value["pname"], json!("Bob"));
+
+        // Display-only explosions belong to nobody.
+        let value = serde_json::to_value(Explosion::new(None, pos, &clock)).unwrap();
+        assert_eq!(value["pid"], json!(0));
+        assert_eq!(value["pname"], json!(""));
     }
 }
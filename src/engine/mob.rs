@@ -1,6 +1,7 @@
 use crate::{
     component::action::Action,
     engine::{
+        pathfinder::PathFinder,
         player::PlayerId,
         position::{MapPosition, PixelPositionF64, PositionOffset},
         types::PlayerList,
@@ -14,9 +15,13 @@ use crate::{
 };
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::ops::Add;
+use std::{collections::VecDeque, ops::Add};
 
-#[derive(Copy, Clone, Debug)]
+/// If a mob following a direction hasn't changed tile for this many frames,
+/// it is stuck and chooses a new target.
+const MOB_STUCK_FRAMES: u32 = 30;
+
+#[derive(Copy, Clone, Debug, Serialize)]
 pub enum MobTargetMode {
     // Pick a nearby spot and try to reach it.
     NearbyCell = 0,
@@ -128,7 +133,7 @@ impl Add<DirAction> for MobTargetDir {
     }
 }
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct MobId(u64);
 
@@ -138,6 +143,15 @@ impl From<u64> for MobId {
     }
 }
 
+/// The parts of a mob's AI state that are useful for debugging. Players
+/// never see this.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MobDebugState {
+    pub target_mode: MobTargetMode,
+    pub danger: bool,
+    pub smart: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct MobServerData {
     target_mode: MobTargetMode,
@@ -149,9 +163,19 @@ pub struct MobServerData {
     old_position: MapPosition,
     target_player: PlayerId,
     target_dir: MobTargetDir,
+    // The number of frames spent in stuck_position, for the directional modes.
+    frames_since_moved: u32,
+    stuck_position: MapPosition,
     range: u32,   // Visibility distance.
     smart: bool,  // Some bomb/explosion avoidance AI.
     danger: bool, // Triggers smart mob to GTFO.
+
+    // Cached path to path_target, starting from path_position.
+    path: VecDeque<PositionOffset>,
+    path_position: MapPosition,
+    path_target: MapPosition,
+    // World version when the path was calculated.
+    path_version: u64,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -160,6 +184,9 @@ pub struct Mob {
     active: bool,
     #[serde(flatten)]
     position: PixelPositionF64,
+    // The position at the start of the last update, if interpolation is enabled.
+    #[serde(rename = "prev", skip_serializing_if = "Option::is_none")]
+    prev_position: Option<PixelPositionF64>,
     action: Action,
     speed: f64,
     image: String,
@@ -174,6 +201,7 @@ impl Default for Mob {
             id: MobId::from(0),
             active: true,
             position: PixelPositionF64::new(0.0, 0.0),
+            prev_position: None,
             action: Action::new(),
             speed: 60.0, // pixels per second.
             image: String::from("mob1"),
@@ -187,9 +215,15 @@ impl Default for Mob {
                 old_position: MapPosition::new(0, 0),
                 target_player: PlayerId::from(0),
                 target_dir: MobTargetDir::Up,
+                frames_since_moved: 0,
+                stuck_position: MapPosition::new(0, 0),
                 range: 8,
                 smart: rand::thread_rng().gen_range(0, 10) > 7,
                 danger: false,
+                path: VecDeque::new(),
+                path_position: MapPosition::new(0, 0),
+                path_target: MapPosition::new(0, 0),
+                path_version: 0,
             },
         }
     }
@@ -200,6 +234,10 @@ impl Mob {
         Mob::default()
     }
 
+    pub fn id(&self) -> MobId {
+        self.id
+    }
+
     pub fn is_active(&self) -> bool {
         self.active
     }
@@ -212,6 +250,16 @@ impl Mob {
         self.position
     }
 
+    pub fn prev_position(&self) -> Option<PixelPositionF64> {
+        self.prev_position
+    }
+
+    /// Remember the current position before it is updated, so that clients
+    /// can interpolate from it.
+    pub fn record_prev_position(&mut self) {
+        self.prev_position = Some(self.position);
+    }
+
     pub fn set_position(&mut self, pos: PixelPositionF64) {
         self.position = pos;
     }
@@ -220,6 +268,14 @@ impl Mob {
         self.server_data.smart
     }
 
+    pub fn debug_state(&self) -> MobDebugState {
+        MobDebugState {
+            target_mode: self.server_data.target_mode,
+            danger: self.server_data.danger,
+            smart: self.server_data.smart,
+        }
+    }
+
     pub fn update_with_temp_action(&mut self, tmp_action: Action, delta_time: f64) {
         if tmp_action.is_empty() {
             return;
@@ -236,7 +292,67 @@ impl Mob {
         self.position.y += tmp_action.y() as f64 * delta_time * effective_speed;
     }
 
-    pub fn choose_new_target(&mut self, world: &World, players: &PlayerList) {
+    /// True if any cell on the rest of the cached path has changed since the
+    /// path was found.
+    fn path_changed(&self, world: &World) -> bool {
+        let mut pos = self.server_data.path_position;
+        self.server_data.path.iter().any(|step| {
+            pos = pos + *step;
+            world.cell_version(pos) > self.server_data.path_version
+        })
+    }
+
+    /// Get the next step towards the target, following the cached path where
+    /// possible. The path is recalculated if the target or any cell on the
+    /// path has changed, if we have strayed from the path, or if it is blocked.
+    fn next_step(
+        &mut self,
+        map_pos: MapPosition,
+        target: MapPosition,
+        world: &World,
+        pathfinder: &mut PathFinder,
+    ) -> Option<PositionOffset>
+    {
+        if self.server_data.path_target != target || self.path_changed(world) {
+            self.server_data.path.clear();
+        }
+
+        // Have we reached the next waypoint?
+        if let Some(step) = self.server_data.path.front() {
+            if map_pos == self.server_data.path_position + *step {
+                self.server_data.path_position = map_pos;
+                self.server_data.path.pop_front();
+            }
+        }
+
+        let valid = match self.server_data.path.front() {
+            Some(step) => {
+                map_pos == self.server_data.path_position && self.can_pass(map_pos + *step, world)
+            }
+            None => false,
+        };
+
+        if !valid {
+            let path =
+                pathfinder.path_find_full(world, self, map_pos, target, self.server_data.range * 2);
+            self.server_data.path = path.map(VecDeque::from).unwrap_or_default();
+            self.server_data.path_position = map_pos;
+            self.server_data.path_target = target;
+            self.server_data.path_version = world.version();
+        }
+
+        self.server_data.path.front().copied()
+    }
+
+    pub fn choose_new_target(
+        &mut self,
+        world: &World,
+        players: &PlayerList,
+        pathfinder: &mut PathFinder,
+    )
+    {
+        self.server_data.path.clear();
+        self.server_data.frames_since_moved = 0;
         if self.server_data.danger {
             self.server_data.target_mode = MobTargetMode::DangerAvoidance;
         } else {
@@ -248,7 +364,8 @@ impl Mob {
         match self.server_data.target_mode {
             MobTargetMode::NearbyCell => {
                 let blank = world.find_nearest_blank(map_pos.random_offset(self.server_data.range));
-                if !blank.is_top_left() {
+                // Mobs may leave a spawner but should never head back to one.
+                if !blank.is_top_left() && world.get_cell(blank) != Some(CellType::MobSpawner) {
                     self.server_data.target_remaining = thread_rng().gen_range(5.0, 25.0);
                     self.server_data.target_position = blank;
                     has_target = true;
@@ -256,8 +373,7 @@ impl Mob {
             }
             MobTargetMode::NearbyPlayer => {
                 for p in players.values() {
-                    if p.position()
-                        .to_map_position(world)
+                    if p.map_position(world)
                         .is_within_range(map_pos, self.server_data.range as i32)
                     {
                         self.server_data.target_player = p.id();
@@ -278,8 +394,12 @@ impl Mob {
             }
             MobTargetMode::DangerAvoidance => {
                 self.server_data.target_remaining = 99999.0;
-                let safest =
-                    world.path_find_nearest_safe_space(self, map_pos, self.server_data.range);
+                let safest = pathfinder.path_find_nearest_safe_space(
+                    world,
+                    self,
+                    map_pos,
+                    self.server_data.range,
+                );
                 self.server_data.target_position = safest;
                 has_target = true;
             }
@@ -293,7 +413,14 @@ impl Mob {
         }
     }
 
-    fn update_action(&mut self, delta_time: f64, players: &PlayerList, world: &World) {
+    fn update_action(
+        &mut self,
+        delta_time: f64,
+        players: &PlayerList,
+        world: &World,
+        pathfinder: &mut PathFinder,
+    )
+    {
         let map_pos = self.position().to_map_position(world);
         self.action.clear();
 
@@ -306,11 +433,11 @@ impl Mob {
                     // We've arrived. Choose a new one.
                     new_target = true;
                 } else {
-                    match world.path_find(
-                        self,
+                    match self.next_step(
                         map_pos,
                         self.server_data.target_position,
-                        self.server_data.range * 2,
+                        world,
+                        pathfinder,
                     ) {
                         Some(best) => {
                             self.action.set(best.x, best.y, false);
@@ -326,12 +453,7 @@ impl Mob {
                     if p.is_dead() {
                         new_target = true;
                     } else {
-                        match world.path_find(
-                            self,
-                            map_pos,
-                            p.position().to_map_position(world),
-                            self.server_data.range * 2,
-                        ) {
+                        match self.next_step(map_pos, p.map_position(world), world, pathfinder) {
                             Some(best) => {
                                 self.action.set(best.x, best.y, false);
                             }
@@ -362,13 +484,18 @@ impl Mob {
                     .is_some()
                 {
                     // Still not safe, get new target.
-                    let safest =
-                        world.path_find_nearest_safe_space(self, map_pos, self.server_data.range);
+                    let safest = pathfinder.path_find_nearest_safe_space(
+                        world,
+                        self,
+                        map_pos,
+                        self.server_data.range,
+                    );
                     self.server_data.target_position = safest;
                 }
 
-                // Go.
-                if let Some(best) = world.path_find(
+                // Go. Always recalculate since the danger zone may have changed.
+                if let Some(best) = pathfinder.path_find(
+                    world,
                     self,
                     map_pos,
                     self.server_data.target_position,
@@ -381,6 +508,18 @@ impl Mob {
         }
 
         if let Some(da) = dir_action {
+            // In tight spaces we can end up turning every frame without going
+            // anywhere.
+            if map_pos == self.server_data.stuck_position {
+                self.server_data.frames_since_moved += 1;
+                if self.server_data.frames_since_moved >= MOB_STUCK_FRAMES {
+                    new_target = true;
+                }
+            } else {
+                self.server_data.stuck_position = map_pos;
+                self.server_data.frames_since_moved = 0;
+            }
+
             let mut done = false;
             if opportunistic && map_pos != self.server_data.old_position {
                 let new_dir = self.server_data.target_dir.clone() + da.clone();
@@ -413,26 +552,33 @@ impl Mob {
 
         self.server_data.target_remaining -= delta_time;
         if self.server_data.target_remaining <= 0.0 || new_target {
-            self.choose_new_target(world, players);
+            self.choose_new_target(world, players, pathfinder);
         }
     }
 
-    fn danger_enable(&mut self, world: &World, players: &PlayerList) {
+    fn danger_enable(&mut self, world: &World, players: &PlayerList, pathfinder: &mut PathFinder) {
         self.server_data.danger = true;
         match self.server_data.target_mode {
             MobTargetMode::DangerAvoidance => {}
-            _ => self.choose_new_target(world, players),
+            _ => self.choose_new_target(world, players, pathfinder),
         }
     }
 
-    fn danger_disable(&mut self, world: &World, players: &PlayerList) {
+    fn danger_disable(&mut self, world: &World, players: &PlayerList, pathfinder: &mut PathFinder) {
         self.server_data.danger = false;
         if let MobTargetMode::DangerAvoidance = self.server_data.target_mode {
-            self.choose_new_target(world, players);
+            self.choose_new_target(world, players, pathfinder);
         }
     }
 
-    pub fn update(&mut self, delta_time: f64, players: &PlayerList, world: &World) {
+    pub fn update(
+        &mut self,
+        delta_time: f64,
+        players: &PlayerList,
+        world: &World,
+        pathfinder: &mut PathFinder,
+    )
+    {
         if !self.is_active() {
             return;
         }
@@ -448,16 +594,16 @@ impl Mob {
         if self.server_data.danger {
             // We were in danger. Are we still in danger ?
             if world.get_mob_data(map_pos).is_none() {
-                self.danger_disable(world, players);
+                self.danger_disable(world, players, pathfinder);
             }
         } else {
             // We haven't been in danger but are we in danger now?
             if self.server_data.smart && world.get_mob_data(map_pos).is_some() {
-                self.danger_enable(world, players);
+                self.danger_enable(world, players, pathfinder);
             }
         }
 
-        self.update_action(delta_time, players, world);
+        self.update_action(delta_time, players, world, pathfinder);
         let mut tmp_action = self.action.clone();
         // Try X movement.
         if tmp_action.x() != 0 {
@@ -540,3 +686,157 @@ impl CanPass for Mob {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::config::GameConfig;
+    use serde_json::json;
+    use std::time::Instant;
+
+    #[test]
+    fn test_stuck_mob_chooses_new_target() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        // A dead end with no way out.
+        let pos = MapPosition::new(3, 3);
+        for offset in &[
+            PositionOffset::up(1),
+            PositionOffset::down(1),
+            PositionOffset::left(1),
+            PositionOffset::right(1),
+        ] {
+            world.set_cell(pos + *offset, CellType::Mystery);
+        }
+
+        let mut mob = Mob::new();
+        mob.set_position(PixelPositionF64::from_map_position(pos, &world));
+        mob.server_data.target_mode = MobTargetMode::Clockwise;
+        mob.server_data.target_remaining = 1000.0;
+
+        let players = PlayerList::new();
+        let mut pathfinder = PathFinder::new();
+        for _ in 0..MOB_STUCK_FRAMES + 1 {
+            mob.update(1.0 / 30.0, &players, &world, &mut pathfinder);
+        }
+        assert_eq!(mob.position().to_map_position(&world), pos);
+        assert!(mob.server_data.target_remaining < 900.0);
+    }
+
+    #[test]
+    fn test_mob_never_targets_spawner() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        // Spawners on every other cell, in a checkerboard.
+        for y in 1..14 {
+            for x in 1..14 {
+                if (x + y) % 2 == 0 {
+                    world.set_cell(MapPosition::new(x, y), CellType::MobSpawner);
+                }
+            }
+        }
+
+        let pos = MapPosition::new(7, 7);
+        let mut mob = Mob::new();
+        mob.set_position(PixelPositionF64::from_map_position(pos, &world));
+        let players = PlayerList::new();
+        let mut pathfinder = PathFinder::new();
+        for _ in 0..200 {
+            mob.choose_new_target(&world, &players, &mut pathfinder);
+            if let MobTargetMode::NearbyCell = mob.server_data.target_mode {
+                let target = mob.server_data.target_position;
+                assert_ne!(world.get_cell(target), Some(CellType::MobSpawner));
+            }
+        }
+    }
+
+    #[test]
+    fn test_path_cache() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let mut mob = Mob::new();
+        let start = MapPosition::new(1, 1);
+        let target = MapPosition::new(7, 1);
+        mob.set_position(PixelPositionF64::from_map_position(start, &world));
+        let mut pathfinder = PathFinder::new();
+
+        // The shortest path runs straight along the top row.
+        let step = mob.next_step(start, target, &world, &mut pathfinder);
+        assert_eq!(step, Some(PositionOffset::right(1)));
+        let version = mob.server_data.path_version;
+        assert_eq!(mob.server_data.path.len(), 6);
+
+        // A change elsewhere on the map leaves the path alone.
+        world.set_cell(MapPosition::new(9, 9), CellType::Bomb);
+        mob.next_step(start, target, &world, &mut pathfinder);
+        assert_eq!(mob.server_data.path_version, version);
+        assert_eq!(mob.server_data.path.len(), 6);
+
+        // Blocking the path further along forces a new one around the block.
+        world.set_cell(MapPosition::new(4, 1), CellType::Bomb);
+        mob.next_step(start, target, &world, &mut pathfinder);
+        assert_eq!(mob.server_data.path_version, world.version());
+        assert_eq!(mob.server_data.path.len(), 10);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_mob_paths() {
+        let mut world = World::new(47, 47, &GameConfig::new());
+        let mut rng = thread_rng();
+        let mut random_cell = |low: i32, high: i32| {
+            MapPosition::new(
+                rng.gen_range(low, high) * 2 + 1,
+                rng.gen_range(low, high) * 2 + 1,
+            )
+        };
+        // Each mob heads for a cell a few steps away, as it would in a game.
+        let mut mobs: Vec<(Mob, MapPosition, MapPosition)> = (0..200)
+            .map(|_| {
+                let pos = random_cell(2, 21);
+                let target = pos + (random_cell(-2, 2) - MapPosition::new(1, 1));
+                let mut mob = Mob::new();
+                mob.set_position(PixelPositionF64::from_map_position(pos, &world));
+                (mob, pos, target)
+            })
+            .collect();
+        let bombs: Vec<MapPosition> = (0..1001).map(|_| random_cell(0, 23)).collect();
+        let mut pathfinder = PathFinder::new();
+
+        // Every frame the last bomb goes off and another is placed somewhere.
+        let mut run = |world: &mut World, cached: bool| {
+            let start = Instant::now();
+            for bomb in bombs.windows(2) {
+                world.set_cell(bomb[0], CellType::Empty);
+                world.set_cell(bomb[1], CellType::Bomb);
+                for (mob, pos, target) in mobs.iter_mut() {
+                    if !cached {
+                        // Recalculate every time the map changes.
+                        mob.server_data.path.clear();
+                    }
+                    mob.next_step(*pos, *target, world, &mut pathfinder);
+                }
+            }
+            start.elapsed()
+        };
+
+        let uncached = run(&mut world, false);
+        let cached = run(&mut world, true);
+        println!(
+            "200 mobs, {} frames: {:?} recalculating on every change, {:?} with cached paths",
+            bombs.len() - 1,
+            uncached,
+            cached
+        );
+    }
+
+    #[test]
+    fn test_debug_state() {
+        let mob = Mob::new();
+        let state = serde_json::to_value(mob.debug_state()).unwrap();
+        assert_eq!(state["target_mode"], json!("NearbyCell"));
+        assert_eq!(state["danger"], json!(false));
+        assert_eq!(state["smart"], json!(mob.is_smart()));
+
+        // None of it is sent to players.
+        let value = serde_json::to_value(&mob).unwrap();
+        assert!(value.get("target_mode").is_none());
+    }
+}
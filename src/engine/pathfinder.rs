@@ -0,0 +1,444 @@
+use crate::{
+    engine::{
+        position::{MapPosition, PositionOffset},
+        world::World,
+    },
+    traits::celltypes::CanPass,
+};
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+pub struct PathFindData {
+    position: MapPosition,
+    parent: Option<MapPosition>,
+    travelled: u32,
+    initial_offset: Option<PositionOffset>,
+}
+
+impl PathFindData {
+    pub fn new(position: MapPosition) -> Self {
+        Self {
+            position,
+            parent: None,
+            travelled: 0,
+            initial_offset: None,
+        }
+    }
+
+    pub fn new_from(position: MapPosition, prev: &PathFindData) -> Self {
+        Self {
+            position,
+            parent: Some(prev.position),
+            travelled: prev.travelled + 1,
+            initial_offset: prev
+                .initial_offset
+                .or_else(|| Some(position - prev.position)),
+        }
+    }
+}
+
+/// An entry in the pathfinding frontier. The ordering is reversed so that
+/// BinaryHeap pops the lowest cost first, with ties broken in insertion order.
+struct PathFindNode {
+    cost: u32,
+    seq: u64,
+    data: PathFindData,
+}
+
+impl Ord for PathFindNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .cost
+            .cmp(&self.cost)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for PathFindNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for PathFindNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost && self.seq == other.seq
+    }
+}
+
+impl Eq for PathFindNode {
+}
+
+/// Scratch space for pathfinding. A single PathFinder should be reused for
+/// every search so that the frontier and seen set are cleared rather than
+/// reallocated each time.
+#[derive(Default)]
+pub struct PathFinder {
+    open_list: BinaryHeap<PathFindNode>,
+    seen: HashSet<MapPosition>,
+    came_from: HashMap<MapPosition, MapPosition>,
+    seq: u64,
+}
+
+impl PathFinder {
+    pub fn new() -> Self {
+        PathFinder::default()
+    }
+
+    /// The number of entries the scratch buffers have room for.
+    #[cfg(test)]
+    fn capacity(&self) -> usize {
+        self.open_list.capacity() + self.seen.capacity() + self.came_from.capacity()
+    }
+
+    fn reset(&mut self) {
+        self.open_list.clear();
+        self.seen.clear();
+        self.came_from.clear();
+        self.seq = 0;
+    }
+
+    fn push(&mut self, cost: u32, data: PathFindData) {
+        self.seq += 1;
+        self.open_list.push(PathFindNode {
+            cost,
+            seq: self.seq,
+            data,
+        });
+    }
+
+    /// Pop the next unseen element from the frontier, marking it as seen.
+    fn pop(&mut self) -> Option<PathFindData> {
+        while let Some(PathFindNode { data, .. }) = self.open_list.pop() {
+            if self.seen.insert(data.position) {
+                if let Some(parent) = data.parent {
+                    self.came_from.insert(data.position, parent);
+                }
+                return Some(data);
+            }
+            // Otherwise it was already reached via a path at least this short.
+        }
+        None
+    }
+
+    /// Add all possible moves from the specified element to the frontier.
+    /// If a goal is specified the cost includes the distance to it (A*),
+    /// otherwise the cost is simply the distance travelled.
+    fn push_possible_moves<T>(
+        &mut self,
+        world: &World,
+        agent: &T,
+        pf: &PathFindData,
+        goal: Option<MapPosition>,
+    ) where
+        T: CanPass,
+    {
+        let pos = pf.position;
+        for m in &[pos.up(1), pos.right(1), pos.down(1), pos.left(1)] {
+            if self.seen.contains(m) {
+                continue;
+            }
+
+            if agent.can_pass(*m, world) {
+                let data = PathFindData::new_from(*m, pf);
+                let cost = data.travelled + goal.map_or(0, |g| m.distance_to(g));
+                self.push(cost, data);
+            }
+        }
+    }
+
+    /// Run an A* search from pos_from to pos_to, returning the final element.
+    fn search<T>(
+        &mut self,
+        world: &World,
+        agent: &T,
+        pos_from: MapPosition,
+        pos_to: MapPosition,
+        range: u32,
+    ) -> Option<PathFindData>
+    where
+        T: CanPass,
+    {
+        self.reset();
+        if pos_to == pos_from {
+            return None;
+        }
+
+        self.push(pos_from.distance_to(pos_to), PathFindData::new(pos_from));
+        while let Some(element) = self.pop() {
+            if element.position == pos_to {
+                return Some(element);
+            }
+
+            if element.travelled < range {
+                self.push_possible_moves(world, agent, &element, Some(pos_to));
+            }
+        }
+
+        None
+    }
+
+    /// A* search from pos_from to pos_to, returning the first step to take.
+    pub fn path_find<T>(
+        &mut self,
+        world: &World,
+        agent: &T,
+        pos_from: MapPosition,
+        pos_to: MapPosition,
+        range: u32,
+    ) -> Option<PositionOffset>
+    where
+        T: CanPass,
+    {
+        self.search(world, agent, pos_from, pos_to, range)
+            .and_then(|element| element.initial_offset)
+    }
+
+    /// Same as path_find() but returns every step of the path rather than just
+    /// the first one.
+    pub fn path_find_full<T>(
+        &mut self,
+        world: &World,
+        agent: &T,
+        pos_from: MapPosition,
+        pos_to: MapPosition,
+        range: u32,
+    ) -> Option<Vec<PositionOffset>>
+    where
+        T: CanPass,
+    {
+        let element = self.search(world, agent, pos_from, pos_to, range)?;
+
+        // Walk back to the start to construct the path.
+        let mut path = Vec::with_capacity(element.travelled as usize);
+        let mut pos = element.position;
+        let mut parent = element.parent;
+        while let Some(prev) = parent {
+            path.push(pos - prev);
+            pos = prev;
+            parent = self.came_from.get(&prev).copied();
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    pub fn path_find_nearest_safe_space<T>(
+        &mut self,
+        world: &World,
+        agent: &T,
+        pos: MapPosition,
+        range: u32,
+    ) -> MapPosition
+    where
+        T: CanPass,
+    {
+        self.reset();
+        if world.get_mob_data(pos).is_none() {
+            return pos;
+        }
+
+        self.push(0, PathFindData::new(pos));
+
+        // Um, a timestamp of now definitely isn't safe! let's hope we find a better
+        // one.
+        let mut safest_timestamp = world.clock().now();
+        let mut safest_pos = pos;
+
+        while let Some(element) = self.pop() {
+            match world.get_mob_data(element.position) {
+                None => {
+                    return element.position;
+                }
+                Some(ts) => {
+                    if ts > safest_timestamp {
+                        safest_timestamp = ts;
+                        safest_pos = element.position;
+                    }
+                }
+            }
+
+            if element.travelled < range {
+                self.push_possible_moves(world, agent, &element, None);
+            }
+        }
+
+        safest_pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::config::GameConfig, traits::celltypes::CellType};
+    use rand::{thread_rng, Rng};
+    use std::time::Instant;
+
+    struct TestAgent;
+
+    impl CanPass for TestAgent {
+        fn can_pass(&self, position: MapPosition, world: &World) -> bool {
+            !matches!(
+                world.get_cell(position),
+                Some(CellType::Wall) | Some(CellType::Mystery) | Some(CellType::Bomb) | None
+            )
+        }
+    }
+
+    /// The original path_find implementation, kept for comparison.
+    fn path_find_reference<T: CanPass>(
+        world: &World,
+        agent: &T,
+        pos_from: MapPosition,
+        pos_to: MapPosition,
+        range: u32,
+    ) -> Option<PositionOffset>
+    {
+        if pos_to == pos_from {
+            return None;
+        }
+
+        let mut open_list: Vec<PathFindData> = vec![PathFindData::new(pos_from)];
+        let mut seen: HashSet<MapPosition> = HashSet::new();
+        seen.insert(pos_from);
+
+        while !open_list.is_empty() {
+            open_list.sort_by_cached_key(|a| a.travelled + a.position.distance_to(pos_to));
+            let mut processed = 0;
+            for element in &open_list {
+                if element.position == pos_to {
+                    return element.initial_offset;
+                }
+                seen.insert(element.position);
+                processed += 1;
+                if element.travelled < range {
+                    let pos = element.position;
+                    let mut new_moves: Vec<PathFindData> =
+                        [pos.up(1), pos.right(1), pos.down(1), pos.left(1)]
+                            .iter()
+                            .filter(|m| !seen.contains(m) && agent.can_pass(**m, world))
+                            .map(|m| PathFindData::new_from(*m, element))
+                            .collect();
+                    if !new_moves.is_empty() {
+                        open_list.append(&mut new_moves);
+                        break;
+                    }
+                }
+            }
+            open_list = open_list.split_off(processed);
+        }
+
+        None
+    }
+
+    fn test_world() -> World {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let blocks = [
+            (3, 1),
+            (1, 5),
+            (5, 5),
+            (6, 7),
+            (9, 3),
+            (11, 10),
+            (7, 11),
+            (13, 8),
+        ];
+        for (x, y) in blocks.iter() {
+            world.set_cell(MapPosition::new(*x, *y), CellType::Mystery);
+        }
+        world
+    }
+
+    #[test]
+    fn test_path_find_matches_reference() {
+        let world = test_world();
+        let agent = TestAgent;
+        // Reuse the same scratch space for every search.
+        let mut pathfinder = PathFinder::new();
+        let from = MapPosition::new(1, 1);
+        for y in 1..14 {
+            for x in 1..14 {
+                let to = MapPosition::new(x, y);
+                assert_eq!(
+                    pathfinder.path_find(&world, &agent, from, to, 40),
+                    path_find_reference(&world, &agent, from, to, 40),
+                    "path from {:?} to {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_path_find_full_starts_with_first_step() {
+        let world = test_world();
+        let agent = TestAgent;
+        let mut pathfinder = PathFinder::new();
+        let from = MapPosition::new(1, 1);
+        for y in 1..14 {
+            for x in 1..14 {
+                let to = MapPosition::new(x, y);
+                let first_step = pathfinder.path_find(&world, &agent, from, to, 40);
+                let path = pathfinder.path_find_full(&world, &agent, from, to, 40);
+                assert_eq!(
+                    path.as_ref().and_then(|p| p.first().copied()),
+                    first_step,
+                    "path from {:?} to {:?}",
+                    from,
+                    to
+                );
+
+                // Following the path should lead to the target.
+                if let Some(path) = path {
+                    let end = path.iter().fold(from, |pos, step| pos + *step);
+                    assert_eq!(end, to);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_path_find_allocations() {
+        // 200 mobs each looking for a path every tick, for 30 ticks.
+        let world = World::new(47, 47, &GameConfig::new());
+        let agent = TestAgent;
+        let mut rng = thread_rng();
+        let searches: Vec<(MapPosition, MapPosition)> = (0..200 * 30)
+            .map(|_| {
+                let from = MapPosition::new(rng.gen_range(0, 23) * 2 + 1, 1);
+                let to = MapPosition::new(rng.gen_range(0, 23) * 2 + 1, 45);
+                (from, to)
+            })
+            .collect();
+
+        // Count the buffer space each search sets up rather than allocations,
+        // which would need a global allocator for the whole test binary.
+        let start = Instant::now();
+        let mut fresh_capacity = 0;
+        for (from, to) in searches.iter() {
+            let mut pathfinder = PathFinder::new();
+            pathfinder.path_find(&world, &agent, *from, *to, 100);
+            fresh_capacity += pathfinder.capacity();
+        }
+        let fresh = (fresh_capacity, start.elapsed());
+
+        let start = Instant::now();
+        let mut pathfinder = PathFinder::new();
+        for (from, to) in searches.iter() {
+            pathfinder.path_find(&world, &agent, *from, *to, 100);
+        }
+        let reused = (pathfinder.capacity(), start.elapsed());
+
+        println!(
+            "Entries allocated per tick: {} ({:?}) with a new PathFinder each time, {} ({:?}) \
+             reused",
+            fresh.0 / 30,
+            fresh.1,
+            reused.0 / 30,
+            reused.1
+        );
+    }
+}
@@ -3,10 +3,13 @@ use crate::{
     component::{
         action::Action,
         effect::{Effect, EffectType},
+        inputbuffer::{InputBuffer, InputPush},
+        powerup::{PowerUp, PowerUpStat},
     },
     engine::{
         bomb::{BombRange, BombTime},
-        position::{MapPosition, PixelPositionF64, PositionOffset},
+        config::{EffectConfig, GameConfig, ScoreConfig},
+        position::{MapPosition, PixelPositionF64, PositionOffset, SizeInTiles},
         world::World,
     },
     error::{ZError, ZResult},
@@ -14,19 +17,30 @@ use crate::{
         celltypes::{CanPass, CellType},
         randenum::RandEnumFrom,
     },
+    utils::misc::{GameClock, Timestamp},
 };
 use log::*;
 use rand::{seq::SliceRandom, Rng};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::convert::TryFrom;
+use std::{collections::HashSet, convert::TryFrom};
+
+/// The player images the client knows how to draw.
+const PLAYER_IMAGES: [&str; 4] = ["p1", "p2", "p3", "p4"];
+
+// The stats every player starts with.
+const DEFAULT_SPEED: f64 = 200.0;
+const DEFAULT_RANGE: u32 = 1;
+const DEFAULT_BOMB_TIME: f64 = 3.0;
+const DEFAULT_MAX_BOMBS: u32 = 1;
 
 #[derive(Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum PlayerFlags {
     WalkThroughBombs,
     Invincible,
+    Ghost,
 }
 
 pub type PlayerFlagsList = Vec<PlayerFlags>;
@@ -70,6 +84,9 @@ pub struct Player {
     state: PlayerState,
     #[serde(flatten)]
     position: PixelPositionF64,
+    // The position at the start of the last update, if interpolation is enabled.
+    #[serde(rename = "prev", skip_serializing_if = "Option::is_none")]
+    prev_position: Option<PixelPositionF64>,
     action: Action,
     speed: f64,
     image: String,
@@ -77,8 +94,18 @@ pub struct Player {
     bomb_time: BombTime,
     max_bombs: u32,
     cur_bombs: u32,
+    // Bombs that can be placed with the shortest fuse.
+    short_fuse_charges: u32,
+    #[serde(skip)]
+    last_bomb_time: Timestamp,
     flags: PlayerFlagsList,
     score: u32,
+    combo_count: u32,
+    // Mobs and players killed.
+    kills: u32,
+    deaths: u32,
+    #[serde(skip)]
+    last_kill_time: Timestamp,
     name: String,
     rank: u32,
     effects: Vec<Effect>,
@@ -86,34 +113,165 @@ pub struct Player {
     effects_cache: Vec<Effect>,
     #[serde(skip)]
     ws: PlayerComm,
+    // Actions received but not yet applied.
+    #[serde(skip)]
+    inputs: InputBuffer,
+    // The id of the last action applied, so the client can discard any
+    // predictions it has already been sent the result of.
+    #[serde(skip)]
+    last_input_id: u32,
+    // The last action received, and the number of frames it has been
+    // repeated for while waiting for the next one.
+    #[serde(skip)]
+    last_action: Action,
+    #[serde(skip)]
+    held_frames: u32,
+    // True if the input buffer ran dry, so that it is only logged once.
+    #[serde(skip)]
+    input_underrun: bool,
     #[serde(skip)]
     kill_timer: f64,
+    // Score decay that hasn't yet added up to a whole point.
+    #[serde(skip)]
+    score_decay: f64,
+    #[serde(skip)]
+    world_version: Option<u64>,
+    #[serde(skip)]
+    chunk_topleft: Option<MapPosition>,
+    // The view size requested by the client, if any.
+    #[serde(skip)]
+    view_size: Option<SizeInTiles>,
+    // The client can be sent world deltas rather than the world every frame.
+    #[serde(skip)]
+    partial_world: bool,
+    // The position at the end of the last move that passed validation.
+    #[serde(skip)]
+    last_valid_position: Option<PixelPositionF64>,
+    // The last map position calculated, and the pixel position it was calculated for.
+    #[serde(skip)]
+    map_position_cache: Option<(PixelPositionF64, MapPosition)>,
+    // Bombs this player was standing on when they were placed, and can still
+    // walk on until they have stepped off.
+    #[serde(skip)]
+    bomb_grace: Vec<MapPosition>,
 }
 
-impl Player {
-    pub fn new(id: PlayerId, comm: PlayerComm) -> Self {
+/// Builds a player with stats other than the defaults. Without a comm, the
+/// player gets one that isn't connected to anything.
+pub struct PlayerBuilder {
+    id: PlayerId,
+    comm: Option<PlayerComm>,
+    name: String,
+    speed: f64,
+    range: BombRange,
+    bomb_time: BombTime,
+    max_bombs: u32,
+}
+
+impl PlayerBuilder {
+    pub fn new(id: PlayerId) -> Self {
+        PlayerBuilder {
+            id,
+            comm: None,
+            name: String::new(),
+            speed: DEFAULT_SPEED,
+            range: BombRange::from(DEFAULT_RANGE),
+            bomb_time: BombTime::from(DEFAULT_BOMB_TIME),
+            max_bombs: DEFAULT_MAX_BOMBS,
+        }
+    }
+
+    pub fn comm(mut self, comm: PlayerComm) -> Self {
+        self.comm = Some(comm);
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn speed(mut self, speed: f64) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn range(mut self, range: u32) -> Self {
+        self.range = BombRange::from(range);
+        self
+    }
+
+    pub fn bomb_time(mut self, seconds: f64) -> Self {
+        self.bomb_time = BombTime::from(seconds);
+        self
+    }
+
+    pub fn max_bombs(mut self, max_bombs: u32) -> Self {
+        self.max_bombs = max_bombs;
+        self
+    }
+
+    pub fn build(self) -> Player {
+        let id = self.id;
+        let comm = self.comm.unwrap_or_else(|| PlayerComm::local(id).0);
         Player {
             id,
             active: false,
             state: PlayerState::Joining,
             position: PixelPositionF64::new(0.0, 0.0),
+            prev_position: None,
             action: Action::new(),
-            speed: 200.0,
+            speed: self.speed,
             image: String::from("p1"),
-            range: BombRange::from(1),
-            bomb_time: BombTime::from(3.0),
-            max_bombs: 1,
+            range: self.range,
+            bomb_time: self.bomb_time,
+            max_bombs: self.max_bombs,
             cur_bombs: 0,
+            short_fuse_charges: 0,
+            last_bomb_time: Timestamp::zero(),
             flags: PlayerFlagsList::new(),
             score: 0,
-            name: String::new(),
+            combo_count: 0,
+            kills: 0,
+            deaths: 0,
+            last_kill_time: Timestamp::zero(),
+            name: self.name,
             rank: 0,
             effects: Vec::new(),
             effects_cache: Vec::new(),
             ws: comm,
+            inputs: InputBuffer::default(),
+            last_input_id: 0,
+            last_action: Action::new(),
+            held_frames: 0,
+            input_underrun: true,
             kill_timer: 2.0,
+            score_decay: 0.0,
+            world_version: None,
+            chunk_topleft: None,
+            view_size: None,
+            partial_world: false,
+            bomb_grace: Vec::new(),
+            last_valid_position: None,
+            map_position_cache: None,
         }
     }
+}
+
+#[cfg(test)]
+impl PlayerBuilder {
+    /// Build the player along with the client end of its connection, for
+    /// tests to talk to it. Dropping the client disconnects the player.
+    pub fn build_local(self) -> (Player, crate::comms::playercomm::LocalClient) {
+        let (comm, client) = PlayerComm::local(self.id);
+        (self.comm(comm).build(), client)
+    }
+}
+
+impl Player {
+    pub fn new(id: PlayerId, comm: PlayerComm) -> Self {
+        PlayerBuilder::new(id).comm(comm).build()
+    }
 
     pub fn ser(&self) -> ZResult<SerPlayer> {
         SerPlayer::try_from(self)
@@ -123,6 +281,10 @@ impl Player {
         self.id
     }
 
+    pub fn last_input_id(&self) -> u32 {
+        self.last_input_id
+    }
+
     pub fn is_dead(&self) -> bool {
         if let PlayerState::Dead = self.state {
             true
@@ -163,12 +325,107 @@ impl Player {
         self.position
     }
 
+    /// Get the map position of this player. The one stored by the last call
+    /// to refresh_map_position() is used if the player hasn't moved since.
+    pub fn map_position(&self, world: &World) -> MapPosition {
+        match self.map_position_cache {
+            Some((pixel_pos, map_pos)) if pixel_pos == self.position => map_pos,
+            _ => self.position.to_map_position(world),
+        }
+    }
+
+    /// Store the map position for the player's current position. This is
+    /// done whenever the server moves the player.
+    pub fn refresh_map_position(&mut self, world: &World) {
+        let map_pos = self.position.to_map_position(world);
+        self.map_position_cache = Some((self.position, map_pos));
+    }
+
+    /// True if any part of this player is over the specified tile.
+    pub fn overlaps_tile(&self, pos: MapPosition, world: &World) -> bool {
+        let tile_size = world.sizes().tile_size();
+        let centre = PixelPositionF64::from_map_position(pos, world);
+        (self.position.x - centre.x).abs() < tile_size.width as f64
+            && (self.position.y - centre.y).abs() < tile_size.height as f64
+    }
+
+    /// The tiles next to this player's own that they are within the
+    /// specified distance of, as a fraction of a tile.
+    pub fn nearby_tiles(&self, radius: f64, world: &World) -> Vec<MapPosition> {
+        if radius <= 0.0 {
+            return Vec::new();
+        }
+
+        let tile_size = world.sizes().tile_size();
+        let map_pos = self.map_position(world);
+        let centre = PixelPositionF64::from_map_position(map_pos, world);
+        let dx = (self.position.x - centre.x) / tile_size.width as f64;
+        let dy = (self.position.y - centre.y) / tile_size.height as f64;
+        // The distance to the edge of this tile in each direction.
+        [
+            (PositionOffset::up(1), 0.5 + dy),
+            (PositionOffset::right(1), 0.5 - dx),
+            (PositionOffset::down(1), 0.5 - dy),
+            (PositionOffset::left(1), 0.5 + dx),
+        ]
+        .iter()
+        .filter(|(_, gap)| *gap <= radius)
+        .map(|(offset, _)| map_pos + *offset)
+        .collect()
+    }
+
+    pub fn set_bomb_grace(&mut self, positions: Vec<MapPosition>) {
+        self.bomb_grace = positions;
+    }
+
     pub fn position_mut(&mut self) -> &mut PixelPositionF64 {
         &mut self.position
     }
 
+    pub fn prev_position(&self) -> Option<PixelPositionF64> {
+        self.prev_position
+    }
+
+    /// Remember the current position before it is updated, so that clients
+    /// can interpolate from it.
+    pub fn record_prev_position(&mut self) {
+        self.prev_position = Some(self.position);
+    }
+
+    /// Place the player. Positions set by the server are always valid.
     pub fn set_position(&mut self, pos: PixelPositionF64) {
         self.position = pos;
+        self.last_valid_position = Some(pos);
+    }
+
+    /// Check that the player hasn't moved further since the last validated
+    /// position than their speed allows, plus the tolerance in pixels.
+    /// If they have, they are moved back and false is returned. Call this once
+    /// per tick, after update(). The tolerance has to cover snapping to the
+    /// grid as well.
+    pub fn validate_move(
+        &mut self,
+        delta_time: f64,
+        tolerance: f64,
+        effects: &EffectConfig,
+    ) -> bool
+    {
+        let last = match self.last_valid_position {
+            Some(last) => last,
+            None => {
+                self.last_valid_position = Some(self.position);
+                return true;
+            }
+        };
+
+        let max_speed = self.speed.max(effects.min_speed()).min(effects.max_speed());
+        if self.position.distance_to(last) > max_speed * delta_time + tolerance {
+            self.position = last;
+            return false;
+        }
+
+        self.last_valid_position = Some(self.position);
+        true
     }
 
     pub fn action(&self) -> &Action {
@@ -203,6 +460,64 @@ impl Player {
         }
     }
 
+    /// Clear the score, kills, deaths and any combo in progress, for a new
+    /// round.
+    pub fn reset_score(&mut self) {
+        self.score = 0;
+        self.score_decay = 0.0;
+        self.combo_count = 0;
+        self.kills = 0;
+        self.deaths = 0;
+        self.last_kill_time = Timestamp::zero();
+    }
+
+    pub fn kills(&self) -> u32 {
+        self.kills
+    }
+
+    pub fn deaths(&self) -> u32 {
+        self.deaths
+    }
+
+    pub fn add_death(&mut self) {
+        self.deaths += 1;
+    }
+
+    /// The multiplier applied to the current kill combo, or 0 if there is
+    /// no combo in progress.
+    pub fn combo_count(&self) -> u32 {
+        self.combo_count
+    }
+
+    /// Award points for a kill. Each kill within the combo window of the
+    /// previous one increases the multiplier, up to the configured maximum.
+    pub fn award_kill(&mut self, points: u32, now: Timestamp, config: &ScoreConfig) {
+        self.expire_combo(now, config);
+        self.combo_count = (self.combo_count + 1).min(config.max_combo().max(1));
+        self.last_kill_time = now;
+        self.kills += 1;
+        self.increase_score(points * self.combo_count);
+    }
+
+    /// Reset the combo once the window since the last kill has passed.
+    pub fn expire_combo(&mut self, now: Timestamp, config: &ScoreConfig) {
+        let window_millis = (config.combo_window() * 1000.0) as i64;
+        if self.combo_count > 0 && now.millis_since(self.last_kill_time) > window_millis {
+            self.combo_count = 0;
+        }
+    }
+
+    /// Reduce the score at the specified rate in points per second. Partial
+    /// points are carried over to the next update.
+    pub fn decay_score(&mut self, rate: f64, delta_time: f64) {
+        self.score_decay += rate * delta_time;
+        let points = self.score_decay.floor();
+        if points >= 1.0 {
+            self.score_decay -= points;
+            self.decrease_score(points as u32);
+        }
+    }
+
     pub fn bomb_time(&self) -> BombTime {
         self.bomb_time
     }
@@ -212,7 +527,7 @@ impl Player {
     }
 
     pub fn decrease_bomb_time(&mut self) {
-        if self.bomb_time > BombTime::from(2.0) {
+        if self.bomb_time > BombTime::MIN {
             self.bomb_time -= 1.0;
         }
     }
@@ -239,12 +554,36 @@ impl Player {
         self.cur_bombs
     }
 
+    pub fn short_fuse_charges(&self) -> u32 {
+        self.short_fuse_charges
+    }
+
+    pub fn add_short_fuse_charges(&mut self, charges: u32) {
+        self.short_fuse_charges += charges;
+    }
+
+    /// Use up one short fuse charge. Returns false if there are none left.
+    pub fn use_short_fuse_charge(&mut self) -> bool {
+        if self.short_fuse_charges == 0 {
+            return false;
+        }
+        self.short_fuse_charges -= 1;
+        true
+    }
+
     pub fn has_bomb_remaining(&self) -> bool {
         self.cur_bombs < self.max_bombs
     }
 
-    pub fn bomb_placed(&mut self) {
+    /// True if the player placed a bomb less than cooldown seconds ago.
+    pub fn is_bomb_cooling_down(&self, now: Timestamp, cooldown: f64) -> bool {
+        !self.last_bomb_time.is_zero()
+            && now.millis_since(self.last_bomb_time) < (cooldown * 1000.0) as i64
+    }
+
+    pub fn bomb_placed(&mut self, now: Timestamp) {
         self.cur_bombs += 1;
+        self.last_bomb_time = now;
     }
 
     pub fn bomb_exploded(&mut self) {
@@ -263,6 +602,43 @@ impl Player {
         }
     }
 
+    /// The version of the world last sent to this player, if any.
+    pub fn world_version(&self) -> Option<u64> {
+        self.world_version
+    }
+
+    pub fn set_world_version(&mut self, version: u64) {
+        self.world_version = Some(version);
+    }
+
+    /// The top left position of the chunk last sent to this player, if any.
+    pub fn chunk_topleft(&self) -> Option<MapPosition> {
+        self.chunk_topleft
+    }
+
+    pub fn set_chunk_topleft(&mut self, topleft: MapPosition) {
+        self.chunk_topleft = Some(topleft);
+    }
+
+    /// The view size requested by the client. This has not been validated.
+    pub fn view_size(&self) -> Option<SizeInTiles> {
+        self.view_size
+    }
+
+    pub fn set_view_size(&mut self, size: SizeInTiles) {
+        self.view_size = Some(size);
+    }
+
+    /// True if the client said it can be sent only the parts of the world
+    /// that have changed.
+    pub fn partial_world(&self) -> bool {
+        self.partial_world
+    }
+
+    pub fn set_partial_world(&mut self, enabled: bool) {
+        self.partial_world = enabled;
+    }
+
     pub fn ws(&mut self) -> &mut PlayerComm {
         &mut self.ws
     }
@@ -271,12 +647,19 @@ impl Player {
         self.state = PlayerState::Dying;
     }
 
-    pub fn update_with_temp_action(&mut self, tmp_action: &Action, delta_time: f64) {
+    pub fn update_with_temp_action(
+        &mut self,
+        tmp_action: &Action,
+        delta_time: f64,
+        clock: &GameClock,
+        effects: &EffectConfig,
+    )
+    {
         std::mem::swap(&mut self.effects, &mut self.effects_cache);
         self.effects.clear();
         while !self.effects_cache.is_empty() {
             if let Some(mut x) = self.effects_cache.pop() {
-                x.tick(delta_time);
+                x.tick(clock);
                 if x.active {
                     self.effects.push(x);
                 } else {
@@ -285,47 +668,86 @@ impl Player {
             }
         }
 
-        let effective_speed = if self.speed < 50.0 {
-            50.0
-        } else if self.speed > 300.0 {
-            300.0
+        let effective_speed = if self.speed < effects.min_speed() {
+            effects.min_speed()
+        } else if self.speed > effects.max_speed() {
+            effects.max_speed()
         } else {
             self.speed
         };
 
+        // Diagonal moves cover the same distance as straight ones.
+        let effective_speed = if tmp_action.x() != 0 && tmp_action.y() != 0 {
+            effective_speed * std::f64::consts::FRAC_1_SQRT_2
+        } else {
+            effective_speed
+        };
+
         self.position.x += tmp_action.x() as f64 * delta_time * effective_speed;
         self.position.y += tmp_action.y() as f64 * delta_time * effective_speed;
     }
 
-    pub fn add_effect(&mut self, effect: Effect) {
+    /// Apply a new effect. If the player already has the maximum number of
+    /// effects of this type, the one closest to expiring is refreshed instead.
+    pub fn add_effect(&mut self, mut effect: Effect, config: &EffectConfig) {
+        let effect_type = effect.effect_type;
+        let stacks = self
+            .effects
+            .iter()
+            .filter(|x| x.effect_type == effect_type)
+            .count();
+        if stacks >= config.max_stacks() {
+            if let Some(existing) = self
+                .effects
+                .iter_mut()
+                .filter(|x| x.effect_type == effect_type)
+                .min_by_key(|x| x.expires())
+            {
+                existing.refresh(&effect);
+            }
+            return;
+        }
+
         match effect.effect_type {
             EffectType::SpeedUp => {
-                self.speed += 50.0;
+                effect.set_applied(config.speed_delta());
+                self.speed += effect.applied();
             }
             EffectType::SlowDown => {
-                self.speed -= 50.0;
+                effect.set_applied(-config.speed_delta());
+                self.speed += effect.applied();
             }
             EffectType::Invincibility => {
                 self.add_flag(PlayerFlags::Invincible);
             }
+            EffectType::FreezeMobs | EffectType::ReverseControls => {}
+            EffectType::Ghost => {
+                self.add_flag(PlayerFlags::Ghost);
+            }
         }
         self.effects.push(effect);
     }
 
     fn undo_effect(&mut self, effect: &Effect) {
         match effect.effect_type {
-            EffectType::SpeedUp => {
-                self.speed -= 50.0;
-            }
-            EffectType::SlowDown => {
-                self.speed += 50.0;
+            EffectType::SpeedUp | EffectType::SlowDown => {
+                // Reverse exactly what was applied, in case the config has changed.
+                self.speed -= effect.applied();
             }
             EffectType::Invincibility => {
                 self.del_flag(&PlayerFlags::Invincible);
             }
+            EffectType::FreezeMobs | EffectType::ReverseControls => {}
+            EffectType::Ghost => {
+                self.del_flag(&PlayerFlags::Ghost);
+            }
         }
     }
 
+    pub fn has_effect(&self, effect_type: EffectType) -> bool {
+        self.effects.iter().any(|x| x.effect_type == effect_type)
+    }
+
     pub fn add_flag(&mut self, flag: PlayerFlags) {
         self.flags.push(flag);
     }
@@ -348,74 +770,226 @@ impl Player {
         self.flags.contains(&flag)
     }
 
-    pub fn add_random_effect(&mut self) -> String {
-        let effect = Effect::new(
-            EffectType::random(),
-            rand::thread_rng().gen_range(3.0f64, 10.0f64),
-        );
-        let name = effect.name();
-        self.add_effect(effect);
-        name
+    pub fn add_random_effect(&mut self, clock: &GameClock, config: &EffectConfig) -> PowerUp {
+        let (min, max) = (config.min_random_duration(), config.max_random_duration());
+        let duration = if min < max {
+            rand::thread_rng().gen_range(min, max)
+        } else {
+            min
+        };
+        let effect = Effect::new(EffectType::random(), duration, clock);
+        let powerup = PowerUp::with_stat(&effect.name(), PowerUpStat::Effect, duration);
+        self.add_effect(effect, config);
+        powerup
+    }
+
+    /// Make the player invincible for the configured time after spawning.
+    pub fn set_invincible(&mut self, clock: &GameClock, config: &EffectConfig) {
+        let duration = config.spawn_invincibility();
+        if duration > 0.0 {
+            let effect = Effect::new(EffectType::Invincibility, duration, clock);
+            self.add_effect(effect, config);
+        }
+    }
+
+    /// Move the player to a new spawn point, for example after the map has
+    /// changed. Their stats are kept. The caller should send them the new
+    /// world. Returns false if there was nowhere to spawn them.
+    pub fn relocate(&mut self, world: &World, effects: &EffectConfig) -> bool {
+        self.prepare_spawn(world, effects);
+        match world.get_spawn_point() {
+            Some(spawn_point) => {
+                self.set_position(PixelPositionF64::from_map_position(spawn_point, world));
+                self.refresh_map_position(world);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Clear anything left over from where the player was before moving them
+    /// to a spawn point.
+    fn prepare_spawn(&mut self, world: &World, effects: &EffectConfig) {
+        self.action.clear();
+        self.inputs.clear();
+        self.last_action.clear();
+        // Any bombs they had placed are gone.
+        self.cur_bombs = 0;
+        self.world_version = None;
+        self.chunk_topleft = None;
+        self.set_invincible(world.clock(), effects);
+    }
+
+    /// Put the stats, effects and flags back to how every player starts.
+    fn reset_stats(&mut self) {
+        self.state = PlayerState::Active;
+        self.speed = DEFAULT_SPEED;
+        self.range = BombRange::from(DEFAULT_RANGE);
+        self.bomb_time = BombTime::from(DEFAULT_BOMB_TIME);
+        self.max_bombs = DEFAULT_MAX_BOMBS;
+        self.short_fuse_charges = 0;
+        self.last_bomb_time = Timestamp::zero();
+        self.flags.clear();
+        self.effects.clear();
+        self.kill_timer = 2.0;
     }
 
-    pub fn set_invincible(&mut self) {
-        let effect = Effect::new(EffectType::Invincibility, 5.0);
-        self.add_effect(effect);
+    /// Put a player who has joined back to how they started, at a new spawn
+    /// point, ready for the next round. The caller should send them the new
+    /// world. Returns false if there was nowhere to spawn them.
+    pub fn reset_for_round(&mut self, world: &World, effects: &EffectConfig) -> bool {
+        self.reset_stats();
+        self.reset_score();
+        self.relocate(world, effects)
+    }
+
+    /// Bring the player back to life at the specified spawn point, as they
+    /// were when they first joined. Their name, connection, kills and deaths
+    /// are kept but their score starts again from zero.
+    pub fn respawn(&mut self, spawn: MapPosition, world: &World, effects: &EffectConfig) {
+        self.reset_stats();
+        self.score = 0;
+        self.score_decay = 0.0;
+        self.combo_count = 0;
+        self.prepare_spawn(world, effects);
+        self.set_position(PixelPositionF64::from_map_position(spawn, world));
+        self.refresh_map_position(world);
     }
 
     pub async fn handle_player_input(
         &mut self,
         world: &mut World,
         delta_time: f64,
+        config: &GameConfig,
     ) -> ZResult<bool>
     {
         if !self.has_joined() {
-            return self.handle_player_join(world).await;
+            return self.handle_player_join(world, config).await;
         }
 
-        self.action.clear();
-        match self.ws.recv_one().await {
-            Ok(None) => {
-                // No message waiting.
-                self.action.set_dt(0.0);
-                Ok(true)
+        // Queue everything received since the last tick.
+        loop {
+            match self.ws.recv_one().await {
+                Ok(None) => break,
+                Ok(Some(PlayerMessage::Action(a))) => match self.inputs.push(a) {
+                    InputPush::Queued => {}
+                    InputPush::Stale => {
+                        debug!("Player {:?} sent an out of order action", self.id());
+                    }
+                    InputPush::Overflow => {
+                        warn!("Player {:?} input buffer overflow", self.id());
+                    }
+                },
+                Ok(x) => {
+                    error!("Player {:?} invalid message received: {:?}", self.id(), x);
+                    self.terminate();
+                    return Ok(false);
+                }
+                Err(e) => {
+                    error!("Player {:?} error {:?}", self.id(), e);
+                    self.terminate();
+                    return Ok(false);
+                }
             }
-            Ok(Some(PlayerMessage::Action(mut a))) => {
+        }
+
+        // Apply one action per tick.
+        self.action.clear();
+        match self.inputs.pop() {
+            Some(mut a) => {
+                self.last_input_id = a.id();
                 a.set_dt(delta_time);
+                self.last_action = a.clone();
+                self.held_frames = 0;
                 self.set_action(a);
-                Ok(true)
+                self.input_underrun = false;
             }
-            Ok(x) => {
-                error!("Player {:?} invalid message received: {:?}", self.id(), x);
-                self.terminate();
-                Ok(false)
+            None if self.held_frames < config.input_hold_frames()
+                && !self.last_action.is_empty() =>
+            {
+                // The next input is probably just late, so keep moving the
+                // same way rather than stopping dead. Don't drop more bombs.
+                self.held_frames += 1;
+                let mut a = self.last_action.clone();
+                a.cease_fire();
+                a.set_dt(delta_time);
+                self.set_action(a);
             }
-            Err(e) => {
-                error!("Player {:?} error {:?}", self.id(), e);
-                self.terminate();
-                Ok(false)
+            None => {
+                if !self.input_underrun {
+                    debug!("Player {:?} input buffer underrun", self.id());
+                    self.input_underrun = true;
+                }
+                self.action.set_dt(0.0);
             }
         }
+        Ok(true)
     }
 
-    pub async fn handle_player_join(&mut self, world: &mut World) -> ZResult<bool> {
+    pub async fn handle_player_join(
+        &mut self,
+        world: &mut World,
+        config: &GameConfig,
+    ) -> ZResult<bool>
+    {
         match self.ws.recv_one().await {
             Ok(None) => {
                 // No message waiting.
                 Ok(true)
             }
-            Ok(Some(PlayerMessage::JoinGame(name))) => {
-                info!("Player {:?} is joining with name '{}'", self.id(), name);
-                self.set_name(&sanitise_name(&name));
-                self.set_invincible();
-                let spawn_point = world.get_spawn_point();
+            Ok(Some(PlayerMessage::JoinGame(join))) => {
+                info!(
+                    "Player {:?} is joining with name '{}'",
+                    self.id(),
+                    join.name()
+                );
+                let name = sanitise_name(join.name());
+                if name.trim().chars().count() < config.min_name_length().max(1) {
+                    // Let them try again with a different name.
+                    info!("Player {:?} name '{}' rejected", self.id(), join.name());
+                    self.ws
+                        .send(PlayerMessage::JoinError(format!(
+                            "Please choose a name at least {} characters long",
+                            config.min_name_length().max(1)
+                        )))
+                        .await?;
+                    return Ok(true);
+                }
+                self.set_name(&name);
+                if let Some(size) = join.view_size() {
+                    self.set_view_size(size);
+                }
+                self.set_partial_world(join.partial_world());
+                let spawn_point = match world.get_spawn_point() {
+                    Some(pos) => pos,
+                    None => {
+                        // Old clients don't understand anything else, so use
+                        // the death message to explain.
+                        info!("No spawn point for player {:?}", self.id());
+                        self.terminate();
+                        self.ws
+                            .send(PlayerMessage::Dead(String::from(
+                                "There is no room left in the arena. Please try again later",
+                            )))
+                            .await?;
+                        return Ok(false);
+                    }
+                };
+                self.set_invincible(world.clock(), config.effects());
                 self.set_position(PixelPositionF64::from_map_position(spawn_point, &world));
 
-                let available_images = vec!["p1", "p2", "p3", "p4"];
-                self.image = (*available_images
-                    .choose(&mut rand::thread_rng())
-                    .unwrap_or(&"p1"))
-                .to_string();
+                self.image = match join.image() {
+                    Some(image) if PLAYER_IMAGES.contains(&image) => image.to_string(),
+                    requested => {
+                        if let Some(image) = requested {
+                            debug!("Player {:?} requested unknown image '{}'", self.id(), image);
+                        }
+                        (*PLAYER_IMAGES
+                            .choose(&mut rand::thread_rng())
+                            .unwrap_or(&"p1"))
+                        .to_string()
+                    }
+                };
 
                 self.state = PlayerState::Active;
                 self.active = true;
@@ -443,94 +1017,151 @@ impl Player {
         }
     }
 
-    pub async fn got_item(&mut self, item: CellType) -> ZResult<bool> {
-        match item {
+    pub async fn got_item(
+        &mut self,
+        item: CellType,
+        clock: &GameClock,
+        config: &GameConfig,
+    ) -> ZResult<Option<PowerUp>>
+    {
+        let powerup = match item {
             CellType::ItemBomb => {
                 self.increase_max_bombs();
-                self.ws().send_powerup("+B").await?;
-                Ok(true)
+                PowerUp::with_stat("+B", PowerUpStat::MaxBombs, self.max_bombs() as f64)
             }
             CellType::ItemRange => {
                 self.increase_range();
-                self.ws().send_powerup("+R").await?;
-                Ok(true)
+                PowerUp::with_stat("+R", PowerUpStat::Range, *self.range() as f64)
             }
             CellType::ItemRandom => {
-                let r: u8 = rand::thread_rng().gen_range(0, 10);
-                let mut powerup_name = String::new();
-                match r {
-                    0 => {
-                        if self.max_bombs() < 6 {
-                            self.increase_max_bombs();
-                            powerup_name = "+B".to_owned();
-                        }
-                    }
-                    1 => {
-                        if self.max_bombs() > 1 {
-                            self.decrease_max_bombs();
-                            powerup_name = "-B".to_owned();
-                        }
-                    }
-                    2 => {
-                        if self.range() < BombRange::from(8) {
-                            self.increase_range();
-                            powerup_name = "+R".to_owned();
-                        }
-                    }
-                    3 => {
-                        if self.range() > BombRange::from(1) {
-                            self.decrease_range();
-                            powerup_name = "-R".to_owned();
-                        }
-                    }
-                    4 => {
-                        if self.has_flag(PlayerFlags::WalkThroughBombs) {
-                            self.del_flag(&PlayerFlags::WalkThroughBombs);
-                            powerup_name = "-TB".to_owned();
-                        } else {
-                            self.add_flag(PlayerFlags::WalkThroughBombs);
-                            powerup_name = "+TB".to_owned();
-                        }
-                    }
-                    5 => {
-                        if self.bomb_time() < BombTime::from(4.0) {
-                            self.increase_bomb_time();
-                            powerup_name = "SB".to_owned();
-                        }
-                    }
-                    6 => {
-                        if self.bomb_time() > BombTime::from(2.0) {
-                            self.decrease_bomb_time();
-                            powerup_name = "FB".to_owned();
-                        }
-                    }
-                    7 => {
-                        if self.score() > 100 {
-                            let pwrup: u32 = rand::thread_rng().gen_range(1, 10) * 10;
-                            self.decrease_score(pwrup);
-                            powerup_name = "-$".to_owned();
-                        }
-                    }
-                    8 => {
-                        let pwrup: u32 = rand::thread_rng().gen_range(1, 10) * 10;
-                        self.increase_score(pwrup);
-                        powerup_name = "+$".to_owned();
-                    }
-                    _ => powerup_name = self.add_random_effect(),
-                }
+                let roll: u8 = rand::thread_rng().gen_range(0, 10);
+                self.apply_random_item(roll, clock, config)
+            }
+            _ => return Ok(None),
+        };
 
-                if powerup_name.is_empty() {
-                    powerup_name = self.add_random_effect();
-                }
+        self.ws().send_powerup(powerup.clone()).await?;
+        Ok(Some(powerup))
+    }
 
-                self.ws().send_powerup(&powerup_name).await?;
-                Ok(true)
+    /// Apply the outcome of a random item roll from 0 to 9, returning the
+    /// powerup. Outcomes that can't be applied give a random effect.
+    fn apply_random_item(&mut self, roll: u8, clock: &GameClock, config: &GameConfig) -> PowerUp {
+        let mut powerup = None;
+        match roll {
+            0 => {
+                if self.max_bombs() < 6 {
+                    self.increase_max_bombs();
+                    powerup = Some(PowerUp::with_stat(
+                        "+B",
+                        PowerUpStat::MaxBombs,
+                        self.max_bombs() as f64,
+                    ));
+                }
+            }
+            1 => {
+                if self.max_bombs() > 1 {
+                    self.decrease_max_bombs();
+                    powerup = Some(PowerUp::with_stat(
+                        "-B",
+                        PowerUpStat::MaxBombs,
+                        self.max_bombs() as f64,
+                    ));
+                }
             }
-            _ => Ok(false),
+            2 => {
+                if self.range() < BombRange::from(8) {
+                    self.increase_range();
+                    powerup = Some(PowerUp::with_stat(
+                        "+R",
+                        PowerUpStat::Range,
+                        *self.range() as f64,
+                    ));
+                }
+            }
+            3 => {
+                if self.range() > BombRange::from(1) {
+                    self.decrease_range();
+                    powerup = Some(PowerUp::with_stat(
+                        "-R",
+                        PowerUpStat::Range,
+                        *self.range() as f64,
+                    ));
+                }
+            }
+            4 => {
+                if self.has_flag(PlayerFlags::WalkThroughBombs) {
+                    self.del_flag(&PlayerFlags::WalkThroughBombs);
+                    powerup = Some(PowerUp::with_stat(
+                        "-TB",
+                        PowerUpStat::WalkThroughBombs,
+                        0.0,
+                    ));
+                } else {
+                    self.add_flag(PlayerFlags::WalkThroughBombs);
+                    powerup = Some(PowerUp::with_stat(
+                        "+TB",
+                        PowerUpStat::WalkThroughBombs,
+                        1.0,
+                    ));
+                }
+            }
+            5 => {
+                if self.bomb_time() < BombTime::from(4.0) {
+                    self.increase_bomb_time();
+                    powerup = Some(PowerUp::with_stat(
+                        "SB",
+                        PowerUpStat::BombTime,
+                        *self.bomb_time(),
+                    ));
+                }
+            }
+            6 => {
+                if self.bomb_time() > BombTime::from(2.0) {
+                    self.decrease_bomb_time();
+                    powerup = Some(PowerUp::with_stat(
+                        "FB",
+                        PowerUpStat::BombTime,
+                        *self.bomb_time(),
+                    ));
+                }
+            }
+            7 => {
+                if self.score() > 100 {
+                    let pwrup = config.scores().random_points(&mut rand::thread_rng());
+                    self.decrease_score(pwrup);
+                    powerup = Some(PowerUp::with_stat(
+                        "-$",
+                        PowerUpStat::Score,
+                        self.score() as f64,
+                    ));
+                }
+            }
+            8 => {
+                let pwrup = config.scores().random_points(&mut rand::thread_rng());
+                self.increase_score(pwrup);
+                powerup = Some(PowerUp::with_stat(
+                    "+$",
+                    PowerUpStat::Score,
+                    self.score() as f64,
+                ));
+            }
+            _ => powerup = Some(self.add_random_effect(clock, config.effects())),
         }
+
+        powerup.unwrap_or_else(|| self.add_random_effect(clock, config.effects()))
     }
 
-    pub fn update(&mut self, world: &World, delta_time: f64) {
+    /// Move the player according to their current action. Players can't move
+    /// onto any of the occupied tiles.
+    pub fn update(
+        &mut self,
+        world: &World,
+        delta_time: f64,
+        config: &GameConfig,
+        occupied: &HashSet<MapPosition>,
+    )
+    {
         if let PlayerState::Dying = self.state {
             // We're dying. Just update the timer and get out.
             self.kill_timer -= delta_time;
@@ -543,19 +1174,26 @@ impl Player {
             return;
         }
 
-        let map_pos = self.position().to_map_position(&world);
-        if let Some(CellType::Wall) = world.get_cell(map_pos) {
-            // Oops - we're in a wall. Reposition to nearby blank space.
-            let blank = world.find_nearest_blank(map_pos);
-            self.set_position(PixelPositionF64::from_map_position(blank, &world));
-        }
+        let map_pos = self.map_position(world);
+        self.eject_from_wall(map_pos, world);
 
         let mut tmp_action = self.action().clone();
-        self.fix_position_and_tmpaction(&mut tmp_action, map_pos, world);
+        if self.has_effect(EffectType::ReverseControls) {
+            // Reverse before the wall checks below so that they see the real direction.
+            tmp_action.setxy(-tmp_action.x(), -tmp_action.y());
+        }
+        self.fix_position_and_tmpaction(&mut tmp_action, map_pos, world, occupied);
+
+        let diagonal = config.diagonal_movement()
+            && tmp_action.x() != 0
+            && tmp_action.y() != 0
+            && self.can_move_diagonally(map_pos, &tmp_action, world, occupied);
 
-        // Lock to gridlines.
+        // Lock to gridlines, unless moving diagonally.
         let tolerance = world.sizes().tile_size().width as f64 * 0.3;
-        if tmp_action.x() != 0 {
+        if diagonal {
+            // Both axes are free.
+        } else if tmp_action.x() != 0 {
             // Moving horizontally, make sure we're on a gridline.
             let target_y = PixelPositionF64::from_map_position(map_pos, &world).y;
             if target_y > self.position().y + tolerance {
@@ -579,8 +1217,54 @@ impl Player {
             }
         }
 
-        self.update_with_temp_action(&tmp_action, delta_time);
-        self.fix_position_and_tmpaction(&mut tmp_action, map_pos, world);
+        self.update_with_temp_action(&tmp_action, delta_time, world.clock(), config.effects());
+        self.fix_position_and_tmpaction(&mut tmp_action, map_pos, world, occupied);
+
+        // The ghost effect may have just worn off.
+        self.eject_from_wall(self.map_position(world), world);
+        self.refresh_map_position(world);
+    }
+
+    /// A diagonal move is only allowed if the tiles on both sides of the
+    /// corner are passable as well as the destination.
+    fn can_move_diagonally(
+        &self,
+        map_pos: MapPosition,
+        action: &Action,
+        world: &World,
+        occupied: &HashSet<MapPosition>,
+    ) -> bool
+    {
+        [
+            PositionOffset::new(action.x(), 0),
+            PositionOffset::new(0, action.y()),
+            PositionOffset::new(action.x(), action.y()),
+        ]
+        .iter()
+        .all(|offset| self.can_move_to(map_pos + *offset, world, occupied))
+    }
+
+    fn can_move_to(
+        &self,
+        position: MapPosition,
+        world: &World,
+        occupied: &HashSet<MapPosition>,
+    ) -> bool
+    {
+        self.can_pass(position, world) && !occupied.contains(&position)
+    }
+
+    /// If we're inside a wall or block, reposition to nearby blank space.
+    /// Ghosts are allowed to stay there until the effect wears off.
+    fn eject_from_wall(&mut self, map_pos: MapPosition, world: &World) {
+        if self.has_flag(PlayerFlags::Ghost) {
+            return;
+        }
+
+        if let Some(CellType::Wall) | Some(CellType::Mystery) = world.get_cell(map_pos) {
+            let blank = world.find_nearest_blank(map_pos);
+            self.set_position(PixelPositionF64::from_map_position(blank, &world));
+        }
     }
 
     fn fix_position_and_tmpaction(
@@ -588,12 +1272,13 @@ impl Player {
         tmp_action: &mut Action,
         map_pos: MapPosition,
         world: &World,
+        occupied: &HashSet<MapPosition>,
     )
     {
         // Try X movement.
         if tmp_action.x() != 0 {
             let try_pos = map_pos + PositionOffset::new(tmp_action.x(), 0);
-            if !self.can_pass(try_pos, &world) {
+            if !self.can_move_to(try_pos, world, occupied) {
                 // Can't pass horizontally, so lock X position.
                 let target_x = PixelPositionF64::from_map_position(map_pos, world).x;
                 if (tmp_action.x() < 0 && self.position.x <= target_x)
@@ -608,7 +1293,7 @@ impl Player {
         if tmp_action.y() != 0 {
             // Try Y movement.
             let try_pos = map_pos + PositionOffset::new(0, tmp_action.y());
-            if !self.can_pass(try_pos, &world) {
+            if !self.can_move_to(try_pos, world, occupied) {
                 // Can't pass vertically, so lock Y position.
                 let target_y = PixelPositionF64::from_map_position(map_pos, world).y;
                 if (tmp_action.y() < 0 && self.position.y <= target_y)
@@ -619,14 +1304,30 @@ impl Player {
                 }
             }
         }
+
+        if tmp_action.x() != 0 && tmp_action.y() != 0 {
+            // Don't allow cutting diagonally across a corner when the tiles on
+            // both sides of it are blocked.
+            let x_pos = map_pos + PositionOffset::new(tmp_action.x(), 0);
+            let y_pos = map_pos + PositionOffset::new(0, tmp_action.y());
+            if !self.can_move_to(x_pos, world, occupied)
+                && !self.can_move_to(y_pos, world, occupied)
+            {
+                tmp_action.setxy(0, 0);
+            }
+        }
     }
 }
 
 impl CanPass for Player {
     fn can_pass(&self, position: MapPosition, world: &World) -> bool {
         match world.get_cell(position) {
-            Some(CellType::Wall) | Some(CellType::Mystery) => false,
-            Some(CellType::Bomb) => self.has_flag(PlayerFlags::WalkThroughBombs),
+            Some(CellType::Wall) | Some(CellType::Mystery) => {
+                self.has_flag(PlayerFlags::Ghost) && !world.is_border(position)
+            }
+            Some(CellType::Bomb) => {
+                self.has_flag(PlayerFlags::WalkThroughBombs) || self.bomb_grace.contains(&position)
+            }
             _ => true,
         }
     }
@@ -640,3 +1341,623 @@ fn sanitise_name(name: &str) -> String {
         .take(30)
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{bomb::Bomb, config::GameConfig};
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    fn test_player() -> Player {
+        PlayerBuilder::new(PlayerId::from(1)).build()
+    }
+
+    #[test]
+    fn test_builder() {
+        let clock = GameClock::new();
+        let mut player = PlayerBuilder::new(PlayerId::from(3))
+            .name("builder")
+            .range(4)
+            .max_bombs(2)
+            .build();
+        assert_eq!(player.id(), PlayerId::from(3));
+        assert_eq!(player.name(), "builder");
+        assert_eq!(player.bomb_time(), BombTime::from(DEFAULT_BOMB_TIME));
+
+        let bomb = Bomb::new(&player, MapPosition::new(1, 1), &clock);
+        player.bomb_placed(clock.now());
+        assert_eq!(bomb.range(), BombRange::from(4));
+        assert_eq!(bomb.pid(), PlayerId::from(3));
+        assert!(player.has_bomb_remaining());
+    }
+
+    #[test]
+    fn test_spawn_invincibility_duration() {
+        let mut config = GameConfig::new();
+        let world = World::new(15, 15, &config);
+        let mut effects = config.effects().clone();
+        effects.set_spawn_invincibility(1.5);
+        config.set_effects(effects);
+        let mut player = test_player();
+        player.reset_for_round(&world, config.effects());
+        assert!(player.has_flag(PlayerFlags::Invincible));
+        let effect = &player.effects[0];
+        assert_eq!(effect.effect_type, EffectType::Invincibility);
+        assert_eq!(effect.remaining, 1.5);
+        assert_eq!(effect.expires().millis_since(world.clock().now()), 1500);
+
+        // Or not at all.
+        let mut effects = config.effects().clone();
+        effects.set_spawn_invincibility(0.0);
+        config.set_effects(effects);
+        player.reset_for_round(&world, config.effects());
+        assert!(!player.has_flag(PlayerFlags::Invincible));
+        assert!(player.effects.is_empty());
+    }
+
+    #[test]
+    fn test_respawn() {
+        let config = GameConfig::new();
+        let world = World::new(15, 15, &config);
+        let (mut player, _client) = PlayerBuilder::new(PlayerId::from(5))
+            .name("phoenix")
+            .build_local();
+        player.reset_for_round(&world, config.effects());
+        player.award_kill(10, world.clock().now(), config.scores());
+        player.increase_range();
+        player.terminate();
+
+        let spawn = MapPosition::new(3, 3);
+        player.respawn(spawn, &world, config.effects());
+        assert!(player.is_active());
+        assert_eq!(player.name(), "phoenix");
+        assert_eq!(player.ws().id(), PlayerId::from(5));
+        assert_eq!(player.map_position(&world), spawn);
+        assert_eq!(player.score(), 0);
+        assert_eq!(player.kills(), 1);
+        assert_eq!(player.range(), BombRange::from(DEFAULT_RANGE));
+        assert!(player.has_flag(PlayerFlags::Invincible));
+    }
+
+    #[test]
+    fn test_stall_does_not_pass_wall() {
+        let config = GameConfig::new();
+        let mut world = World::new(15, 15, &config);
+        let wall = MapPosition::new(4, 1);
+        world.set_cell(wall, CellType::Mystery);
+        let start = PixelPositionF64::from_map_position(MapPosition::new(1, 1), &world);
+
+        let stall = 10.0;
+        let move_after_stall = |delta_time| {
+            let mut player = test_player();
+            player.set_position(start);
+            player.action_mut().setxy(1, 0);
+            player.update(&world, delta_time, &config, &HashSet::new());
+            player.map_position(&world)
+        };
+
+        // Without the clamp, the player would skip straight past the wall.
+        let map_pos = move_after_stall(stall);
+        assert!(map_pos.x > wall.x, "player moved to {:?}", map_pos);
+
+        let map_pos = move_after_stall(config.clamp_delta_time(stall));
+        assert_eq!(map_pos.y, 1);
+        assert!(map_pos.x < wall.x, "player moved to {:?}", map_pos);
+    }
+
+    #[test]
+    fn test_cannot_cut_corner() {
+        let config = GameConfig::new();
+        let mut world = World::new(15, 15, &config);
+        // Block the tile to the right. The tile below is a wall, leaving a
+        // single tile gap diagonally down and to the right.
+        let start = MapPosition::new(4, 3);
+        let gap = MapPosition::new(5, 4);
+        world.set_cell(MapPosition::new(5, 3), CellType::Mystery);
+        assert_eq!(world.get_cell(MapPosition::new(4, 4)), Some(CellType::Wall));
+        assert_eq!(world.get_cell(gap), Some(CellType::Empty));
+
+        let clock = *world.clock();
+        let mut player = test_player();
+        for _ in 0..config.effects().max_stacks() {
+            let effect = Effect::new(EffectType::SpeedUp, 10.0, &clock);
+            player.add_effect(effect, config.effects());
+        }
+        // Start off centre, as close to the corner as the gridline tolerance allows.
+        let mut position = PixelPositionF64::from_map_position(start, &world);
+        position.x += 9.0;
+        position.y += 9.0;
+        player.set_position(position);
+        player.action_mut().setxy(1, 1);
+
+        for _ in 0..60 {
+            player.update(&world, 1.0 / 30.0, &config, &HashSet::new());
+            assert_eq!(player.map_position(&world), start);
+        }
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {
+    }
+
+    #[test]
+    fn test_map_position_cache() {
+        // Games holding players can be run as tokio tasks.
+        assert_send_sync::<Player>();
+
+        let world = World::new(15, 15, &GameConfig::new());
+        let mut player = test_player();
+        let (first, second) = (MapPosition::new(1, 1), MapPosition::new(3, 1));
+        player.set_position(PixelPositionF64::from_map_position(first, &world));
+        player.refresh_map_position(&world);
+        assert_eq!(player.map_position(&world), first);
+
+        // A stale cache is never used.
+        player.set_position(PixelPositionF64::from_map_position(second, &world));
+        assert_eq!(player.map_position(&world), second);
+    }
+
+    #[test]
+    fn test_client_delta_time_ignored() {
+        let config = GameConfig::new();
+        let mut world = World::new(15, 15, &config);
+        let (mut player, mut client) = PlayerBuilder::new(PlayerId::from(1)).build_local();
+        player.reset_for_round(&world, config.effects());
+        let start = PixelPositionF64::from_map_position(MapPosition::new(1, 1), &world);
+        player.set_position(start);
+
+        let message = json!({
+            "code": "ACTION",
+            "data": {"x": 1, "y": 0, "fire": false, "id": 1, "deltaTime": 1000.0}
+        });
+        block_on(client.send(serde_json::from_value(message).unwrap())).unwrap();
+        let delta_time = 1.0 / 30.0;
+        block_on(player.handle_player_input(&mut world, delta_time, &config)).unwrap();
+        assert_eq!(
+            serde_json::to_value(player.action()).unwrap()["deltaTime"],
+            delta_time
+        );
+
+        player.update(&world, delta_time, &config, &HashSet::new());
+        let moved = player.position().x - start.x;
+        assert!((moved - player.speed() * delta_time).abs() < 1e-9);
+        assert_eq!(player.position().y, start.y);
+    }
+
+    #[test]
+    fn test_input_held_then_released() {
+        let mut config = GameConfig::new();
+        config.set_input_hold_frames(3);
+        let mut world = World::new(15, 15, &config);
+        let (mut player, mut client) = PlayerBuilder::new(PlayerId::from(1)).build_local();
+        player.reset_for_round(&world, config.effects());
+
+        let message = json!({
+            "code": "ACTION",
+            "data": {"x": 1, "y": 0, "fire": true, "id": 1}
+        });
+        block_on(client.send(serde_json::from_value(message).unwrap())).unwrap();
+        let delta_time = 1.0 / 30.0;
+        block_on(player.handle_player_input(&mut world, delta_time, &config)).unwrap();
+        assert_eq!(player.action().x(), 1);
+        assert!(player.action().fire());
+
+        // Nothing else arrives, so the movement is repeated without firing.
+        for _ in 0..3 {
+            block_on(player.handle_player_input(&mut world, delta_time, &config)).unwrap();
+            assert_eq!(player.action().x(), 1);
+            assert!(!player.action().fire());
+        }
+
+        block_on(player.handle_player_input(&mut world, delta_time, &config)).unwrap();
+        assert!(player.action().is_empty());
+    }
+
+    #[test]
+    fn test_name_rejected() {
+        fn join(name: &str, config: &GameConfig) -> (Player, Vec<serde_json::Value>) {
+            let mut world = World::new(15, 15, config);
+            let (mut player, mut client) = PlayerBuilder::new(PlayerId::from(1)).build_local();
+            block_on(client.join(name)).unwrap();
+            let result = block_on(player.handle_player_input(&mut world, 1.0 / 30.0, config));
+            assert!(result.unwrap());
+            let mut codes = Vec::new();
+            while let Some(message) = client.try_recv() {
+                codes.push(serde_json::to_value(&message).unwrap()["code"].clone());
+            }
+            (player, codes)
+        }
+
+        let mut config = GameConfig::new();
+        for name in &["", "   ", "@#$%&"] {
+            let (player, codes) = join(name, &config);
+            assert!(!player.has_joined(), "name '{}'", name);
+            assert_eq!(codes, vec!["JOINERROR"]);
+        }
+
+        config.set_min_name_length(3);
+        let (player, codes) = join("Al", &config);
+        assert!(!player.has_joined());
+        assert_eq!(codes, vec!["JOINERROR"]);
+        let (player, codes) = join("Bob", &config);
+        assert!(player.has_joined());
+        assert_eq!(player.name(), "Bob");
+        assert_eq!(codes, vec!["SPAWNPLAYER"]);
+    }
+
+    #[test]
+    fn test_requested_image() {
+        let config = GameConfig::new();
+        let mut world = World::new(15, 15, &config);
+        for (requested, expected) in &[("p3", Some("p3")), ("boss", None)] {
+            let (mut player, mut client) = PlayerBuilder::new(PlayerId::from(1)).build_local();
+            let message = json!({
+                "code": "JOINGAME",
+                "data": {"name": "Bob", "image": requested}
+            });
+            block_on(client.send(serde_json::from_value(message).unwrap())).unwrap();
+            block_on(player.handle_player_input(&mut world, 1.0 / 30.0, &config)).unwrap();
+            assert!(player.is_active());
+            match expected {
+                Some(image) => assert_eq!(player.image, *image),
+                None => assert!(PLAYER_IMAGES.contains(&player.image.as_str())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_position_jump_rejected() {
+        let config = GameConfig::new();
+        let mut world = World::new(15, 15, &config);
+        let mut player = test_player();
+        let start = PixelPositionF64::from_map_position(MapPosition::new(1, 1), &world);
+        player.set_position(start);
+        let delta_time = 1.0 / 30.0;
+        let tile_width = world.sizes().tile_size().width as f64;
+        let tolerance = tile_width / 2.0;
+
+        // A normal step is fine.
+        player.action_mut().setxy(1, 0);
+        player.update(&world, delta_time, &config, &HashSet::new());
+        assert!(player.validate_move(delta_time, tolerance, config.effects()));
+        let stepped = player.position();
+        assert!(stepped.x > start.x);
+
+        // Jumping several tiles between ticks is not, even with a normal
+        // step on top.
+        player.position_mut().y += 4.0 * tile_width;
+        world.set_cell(player.map_position(&world), CellType::Empty);
+        player.update(&world, delta_time, &config, &HashSet::new());
+        assert!(!player.validate_move(delta_time, tolerance, config.effects()));
+        assert_eq!(player.position(), stepped);
+    }
+
+    #[test]
+    fn test_gridline_snap_is_not_a_jump() {
+        let config = GameConfig::new();
+        let world = World::new(15, 15, &config);
+        let mut player = test_player();
+        let centre = PixelPositionF64::from_map_position(MapPosition::new(1, 1), &world);
+        let tile_width = world.sizes().tile_size().width as f64;
+        let mut start = centre;
+        start.y += tile_width * 0.25;
+        player.set_position(start);
+        let delta_time = 1.0 / 30.0;
+
+        // Moving right snaps the player up onto the gridline first. That
+        // counts towards the move, but is within the game's tolerance.
+        player.action_mut().setxy(1, 0);
+        player.update(&world, delta_time, &config, &HashSet::new());
+        assert_eq!(player.position().y, centre.y);
+        assert!(player.position().x > centre.x);
+        assert!(player.position().distance_to(start) > tile_width * 0.25);
+        assert!(player.validate_move(delta_time, tile_width / 2.0, config.effects()));
+    }
+
+    /// Move a player at (1, 1) down and to the right for a few frames,
+    /// returning the distance moved along each axis.
+    fn move_diagonally(world: &World, config: &GameConfig) -> (f64, f64) {
+        let mut player = test_player();
+        let start = PixelPositionF64::from_map_position(MapPosition::new(1, 1), world);
+        player.set_position(start);
+        player.action_mut().setxy(1, 1);
+        for _ in 0..3 {
+            player.update(world, 1.0 / 30.0, config, &HashSet::new());
+        }
+        (player.position().x - start.x, player.position().y - start.y)
+    }
+
+    #[test]
+    fn test_diagonal_movement() {
+        let mut config = GameConfig::new();
+        let mut world = World::new(15, 15, &config);
+        world.set_cell(MapPosition::new(2, 2), CellType::Empty);
+
+        // Only one axis at a time unless enabled.
+        let (dx, dy) = move_diagonally(&world, &config);
+        assert!(dx == 0.0 || dy == 0.0, "moved ({}, {})", dx, dy);
+
+        config.set_diagonal_movement(true);
+        let (dx, dy) = move_diagonally(&world, &config);
+        assert!(dx > 0.0 && dy > 0.0, "moved ({}, {})", dx, dy);
+        assert!((dx - dy).abs() < 1e-9);
+
+        // No cutting corners.
+        for blocked in &[
+            MapPosition::new(2, 1),
+            MapPosition::new(1, 2),
+            MapPosition::new(2, 2),
+        ] {
+            let mut world = World::new(15, 15, &config);
+            world.set_cell(MapPosition::new(2, 2), CellType::Empty);
+            world.set_cell(*blocked, CellType::Mystery);
+            let (dx, dy) = move_diagonally(&world, &config);
+            assert!(
+                dx == 0.0 || dy == 0.0,
+                "moved ({}, {}) with {:?} blocked",
+                dx,
+                dy,
+                blocked
+            );
+        }
+    }
+
+    #[test]
+    fn test_occupied_tile_blocks_movement() {
+        let config = GameConfig::new();
+        let world = World::new(15, 15, &config);
+        let start = MapPosition::new(1, 1);
+        let occupied: HashSet<MapPosition> = vec![MapPosition::new(2, 1)].into_iter().collect();
+
+        let mut player = test_player();
+        player.set_position(PixelPositionF64::from_map_position(start, &world));
+        player.action_mut().setxy(1, 0);
+        for _ in 0..30 {
+            player.update(&world, 1.0 / 30.0, &config, &occupied);
+        }
+        assert_eq!(player.map_position(&world), start);
+
+        // Once the other player has gone, we can move there.
+        for _ in 0..30 {
+            player.update(&world, 1.0 / 30.0, &config, &HashSet::new());
+        }
+        assert_eq!(player.map_position(&world).y, start.y);
+        assert!(player.map_position(&world).x > start.x);
+    }
+
+    #[test]
+    fn test_effects_stack_up_to_cap() {
+        let mut config = EffectConfig::new();
+        config.set_max_stacks(2);
+        let clock = GameClock::new();
+        let mut player = test_player();
+
+        for _ in 0..4 {
+            player.add_effect(Effect::new(EffectType::SpeedUp, 5.0, &clock), &config);
+        }
+        assert_eq!(player.effects.len(), 2);
+        assert_eq!(player.speed, 300.0);
+
+        // Other effect types have their own stacks.
+        player.add_effect(Effect::new(EffectType::Invincibility, 5.0, &clock), &config);
+        assert_eq!(player.effects.len(), 3);
+        assert!(player.has_flag(PlayerFlags::Invincible));
+    }
+
+    #[test]
+    fn test_effect_at_cap_refreshes_soonest_to_expire() {
+        let mut config = EffectConfig::new();
+        config.set_max_stacks(2);
+        let clock = GameClock::new();
+        let mut player = test_player();
+
+        player.add_effect(Effect::new(EffectType::SlowDown, 2.0, &clock), &config);
+        player.add_effect(Effect::new(EffectType::SlowDown, 6.0, &clock), &config);
+        player.add_effect(Effect::new(EffectType::SlowDown, 4.0, &clock), &config);
+
+        let mut remaining: Vec<f64> = player.effects.iter().map(|x| x.remaining).collect();
+        remaining.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(remaining, vec![4.0, 6.0]);
+        assert_eq!(player.speed, 100.0);
+
+        // A shorter effect does not cut an existing one short.
+        player.add_effect(Effect::new(EffectType::SlowDown, 1.0, &clock), &config);
+        let mut remaining: Vec<f64> = player.effects.iter().map(|x| x.remaining).collect();
+        remaining.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(remaining, vec![4.0, 6.0]);
+    }
+
+    #[test]
+    fn test_expired_effects_are_undone_once() {
+        let mut config = EffectConfig::new();
+        config.set_max_stacks(2);
+        let mut clock = GameClock::new();
+        let mut player = test_player();
+        let action = Action::new();
+
+        for _ in 0..3 {
+            player.add_effect(Effect::new(EffectType::SpeedUp, 3.0, &clock), &config);
+            player.add_effect(Effect::new(EffectType::Invincibility, 3.0, &clock), &config);
+        }
+        assert_eq!(player.speed, 300.0);
+
+        clock.advance(3.5);
+        player.update_with_temp_action(&action, 0.0, &clock, &config);
+        assert!(player.effects.is_empty());
+        assert_eq!(player.speed, 200.0);
+        assert!(!player.has_flag(PlayerFlags::Invincible));
+    }
+
+    #[test]
+    fn test_ghost_ejected_from_wall_on_expiry() {
+        let config = GameConfig::new();
+        let mut world = World::new(15, 15, &config);
+        let wall = MapPosition::new(2, 2);
+        assert_eq!(world.get_cell(wall), Some(CellType::Wall));
+
+        let mut clock = *world.clock();
+        let mut player = test_player();
+        let effect = Effect::new(EffectType::Ghost, 1.0, &clock);
+        player.add_effect(effect, config.effects());
+        assert!(player.can_pass(wall, &world));
+        assert!(!player.can_pass(MapPosition::new(0, 2), &world));
+
+        player.set_position(PixelPositionF64::from_map_position(wall, &world));
+        player.update(&world, 0.1, &config, &HashSet::new());
+        assert_eq!(player.map_position(&world), wall);
+
+        clock.advance(1.5);
+        world.set_clock(&clock);
+        player.update(&world, 0.1, &config, &HashSet::new());
+        assert!(!player.has_flag(PlayerFlags::Ghost));
+        assert!(!player.can_pass(wall, &world));
+        let map_pos = player.map_position(&world);
+        assert_eq!(world.get_cell(map_pos), Some(CellType::Empty));
+    }
+
+    #[test]
+    fn test_config_change_does_not_affect_undo() {
+        let mut config = EffectConfig::new();
+        let mut clock = GameClock::new();
+        let mut player = test_player();
+        let action = Action::new();
+
+        player.add_effect(Effect::new(EffectType::SpeedUp, 2.0, &clock), &config);
+        assert_eq!(player.speed, 250.0);
+
+        // Rebalance mid-game. Later effects use the new value.
+        config.set_speed_delta(80.0);
+        player.add_effect(Effect::new(EffectType::SlowDown, 4.0, &clock), &config);
+        assert_eq!(player.speed, 170.0);
+
+        clock.advance(3.0);
+        player.update_with_temp_action(&action, 0.0, &clock, &config);
+        assert_eq!(player.speed, 120.0);
+
+        clock.advance(3.0);
+        player.update_with_temp_action(&action, 0.0, &clock, &config);
+        assert!(player.effects.is_empty());
+        assert_eq!(player.speed, 200.0);
+    }
+
+    #[test]
+    fn test_reverse_controls() {
+        let config = GameConfig::new();
+        let world = World::new(15, 15, &config);
+        let start = PixelPositionF64::from_map_position(MapPosition::new(5, 1), &world);
+        let mut player = test_player();
+        player.set_position(start);
+        player.action_mut().setxy(1, 0);
+        player.update(&world, 0.1, &config, &HashSet::new());
+        assert!(player.position().x > start.x);
+
+        let effect = Effect::new(EffectType::ReverseControls, 5.0, world.clock());
+        player.add_effect(effect, config.effects());
+        player.set_position(start);
+        player.update(&world, 0.1, &config, &HashSet::new());
+        assert!(player.position().x < start.x);
+        assert_eq!(player.position().y, start.y);
+    }
+
+    #[test]
+    fn test_apply_random_item() {
+        let config = GameConfig::new();
+        let clock = GameClock::new();
+        let effect_names: Vec<String> = EffectType::get_enum_values()
+            .into_iter()
+            .map(|x| EffectType::from(x).name())
+            .collect();
+
+        let mut player = test_player();
+        assert_eq!(
+            player.apply_random_item(0, &clock, &config),
+            PowerUp::with_stat("+B", PowerUpStat::MaxBombs, 2.0)
+        );
+        assert_eq!(player.max_bombs(), 2);
+        assert_eq!(player.apply_random_item(1, &clock, &config).name(), "-B");
+        assert_eq!(player.max_bombs(), 1);
+        assert_eq!(player.apply_random_item(2, &clock, &config).name(), "+R");
+        assert_eq!(player.range(), BombRange::from(2));
+        assert_eq!(player.apply_random_item(3, &clock, &config).name(), "-R");
+        assert_eq!(player.range(), BombRange::from(1));
+        assert_eq!(player.apply_random_item(4, &clock, &config).name(), "+TB");
+        assert!(player.has_flag(PlayerFlags::WalkThroughBombs));
+        assert_eq!(player.apply_random_item(4, &clock, &config).name(), "-TB");
+        assert!(!player.has_flag(PlayerFlags::WalkThroughBombs));
+        assert_eq!(player.apply_random_item(5, &clock, &config).name(), "SB");
+        assert_eq!(player.bomb_time(), BombTime::from(4.0));
+        assert_eq!(player.apply_random_item(6, &clock, &config).name(), "FB");
+        assert_eq!(player.bomb_time(), BombTime::from(3.0));
+        assert_eq!(player.apply_random_item(8, &clock, &config).name(), "+$");
+        assert!(player.score() > 0);
+        player.increase_score(500);
+        let score = player.score();
+        assert_eq!(player.apply_random_item(7, &clock, &config).name(), "-$");
+        assert!(player.score() < score);
+        assert!(player.effects.is_empty());
+
+        let powerup = player.apply_random_item(9, &clock, &config);
+        assert_eq!(powerup.stat(), Some(PowerUpStat::Effect));
+        let name = powerup.name().to_string();
+        assert!(effect_names.contains(&name), "unexpected powerup {}", name);
+        assert_eq!(player.effects.len(), 1);
+
+        // Outcomes that can't be applied fall back to a random effect.
+        let mut player = test_player();
+        for roll in &[1, 3, 7] {
+            let name = player
+                .apply_random_item(*roll, &clock, &config)
+                .name()
+                .to_string();
+            assert!(effect_names.contains(&name), "unexpected powerup {}", name);
+        }
+        assert_eq!(player.max_bombs(), 1);
+        assert_eq!(player.range(), BombRange::from(1));
+        assert_eq!(player.score(), 0);
+    }
+
+    #[test]
+    fn test_decay_score() {
+        let mut player = test_player();
+        player.increase_score(1000);
+
+        // 10 points per second for 5 seconds.
+        for _ in 0..20 {
+            player.decay_score(10.0, 0.25);
+        }
+        assert_eq!(player.score(), 950);
+
+        // Partial points carry over between frames.
+        for _ in 0..150 {
+            player.decay_score(10.0, 1.0 / 30.0);
+        }
+        let score = player.score();
+        assert!(score == 900 || score == 901, "score {}", score);
+
+        // The score never goes below zero.
+        player.decay_score(10.0, 1000.0);
+        assert_eq!(player.score(), 0);
+    }
+
+    #[test]
+    fn test_kill_combo() {
+        let config = ScoreConfig::new();
+        let mut clock = GameClock::new();
+        let mut player = test_player();
+
+        player.award_kill(500, clock.now(), &config);
+        assert_eq!(player.score(), 500);
+        clock.advance(1.0);
+        player.award_kill(500, clock.now(), &config);
+        assert_eq!(player.combo_count(), 2);
+        assert_eq!(player.score(), 1500);
+
+        // Once the window lapses the combo starts again.
+        clock.advance(config.combo_window() + 0.5);
+        player.expire_combo(clock.now(), &config);
+        assert_eq!(player.combo_count(), 0);
+        player.award_kill(500, clock.now(), &config);
+        assert_eq!(player.combo_count(), 1);
+        assert_eq!(player.score(), 2000);
+    }
+}
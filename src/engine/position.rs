@@ -1,6 +1,6 @@
 use crate::engine::world::World;
 use rand::{thread_rng, Rng};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::ops::{Add, Mul, Sub};
 
 // Get the difference between two i32 values.
@@ -19,23 +19,23 @@ pub struct PositionOffset {
 }
 
 impl PositionOffset {
-    pub fn new(x: i32, y: i32) -> Self {
+    pub const fn new(x: i32, y: i32) -> Self {
         PositionOffset { x, y }
     }
 
-    pub fn up(dist: i32) -> Self {
+    pub const fn up(dist: i32) -> Self {
         PositionOffset { x: 0, y: -dist }
     }
 
-    pub fn down(dist: i32) -> Self {
+    pub const fn down(dist: i32) -> Self {
         PositionOffset { x: 0, y: dist }
     }
 
-    pub fn left(dist: i32) -> Self {
+    pub const fn left(dist: i32) -> Self {
         PositionOffset { x: -dist, y: 0 }
     }
 
-    pub fn right(dist: i32) -> Self {
+    pub const fn right(dist: i32) -> Self {
         PositionOffset { x: dist, y: 0 }
     }
 }
@@ -62,7 +62,7 @@ impl Add<PositionOffset> for PositionOffset {
     }
 }
 
-#[derive(Default, Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize)]
+#[derive(Default, Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MapPosition {
     pub x: i32,
     pub y: i32,
@@ -276,3 +276,22 @@ impl SizeInTiles {
         SizeInTiles { width, height }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negative_offsets_do_not_underflow() {
+        // Positions are signed, so stepping off the edge of the map gives a
+        // negative position rather than wrapping around.
+        let pos = MapPosition::new(0, 0) + PositionOffset::new(-1, -2);
+        assert_eq!(pos, MapPosition::new(-1, -2));
+        assert_eq!(pos.left(1).x, -2);
+        assert_eq!(pos.distance_to(MapPosition::new(1, 1)), 5);
+        assert_eq!(
+            MapPosition::new(0, 0) - MapPosition::new(2, 3),
+            PositionOffset::new(-2, -3)
+        );
+    }
+}
@@ -1,27 +1,43 @@
 use crate::{
     engine::{
         bomb::{Bomb, BombId},
-        config::GameConfig,
+        config::{GameConfig, ScoreConfig},
+        entityindex::{EntityIndex, NearbyEntities},
         explosion::Explosion,
+        player::PlayerId,
         position::{MapPosition, PositionOffset, SizeInPixels, SizeInTiles},
-        types::{BombList, ExplosionList, PlayerList},
+        types::{BombList, ExplosionList, MobList, PlayerList},
         worlddata::{
+            CellVersions,
             InternalCellData,
             InternalMobData,
             InternalWorldData,
             MobSpawner,
             WorldChunk,
             WorldData,
+            WorldDelta,
         },
         worldzone::WorldZoneData,
     },
-    traits::celltypes::{CanPass, CellType},
-    utils::misc::Timestamp,
+    tools::indexedset::IndexedSet,
+    traits::celltypes::CellType,
+    utils::misc::{GameClock, Timestamp},
 };
 use log::*;
 use rand::Rng;
 use serde::Serialize;
-use std::collections::{HashSet, VecDeque};
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+};
+
+/// The directions an explosion travels in, one tile at a time.
+const BLAST_DIRECTIONS: [PositionOffset; 4] = [
+    PositionOffset::up(1),
+    PositionOffset::down(1),
+    PositionOffset::left(1),
+    PositionOffset::right(1),
+];
 
 #[derive(Debug, Serialize)]
 pub struct WorldSize {
@@ -68,44 +84,82 @@ pub struct World {
     data_internal: InternalWorldData,
     data_mob: InternalMobData,
     zones: WorldZoneData,
+    version: u64,
+    // Log of (version, position) for every cell change not yet sent to all players.
+    changes: VecDeque<(u64, MapPosition)>,
+    // The version at which each cell last changed.
+    cell_versions: CellVersions,
+    entities: EntityIndex,
+    // Blank tiles with at least 2 blank neighbours, kept up to date by set_cell().
+    spawn_points: IndexedSet<MapPosition>,
+    // A copy of the game clock, updated by the game every tick.
+    clock: GameClock,
+    // If false, destroyed blocks never leave an item behind.
+    powerups_enabled: bool,
+}
+
+/// Generate the fixed part of a map: the outer wall and the grid of pillars
+/// inside it. Everything else is left empty.
+pub fn generate_layout(width: i32, height: i32) -> WorldData {
+    let mut data = WorldData::new(width, height);
+    let wall = CellType::Wall as u8;
+    for x in 0..width {
+        data.set_at(MapPosition::new(x, 0), wall);
+        data.set_at(MapPosition::new(x, height - 1), wall);
+    }
+
+    for y in 0..height {
+        data.set_at(MapPosition::new(0, y), wall);
+        data.set_at(MapPosition::new(width - 1, y), wall);
+
+        if y % 2 == 0 {
+            for x in 1..((width as f64 / 2.0) as i32) {
+                data.set_at(MapPosition::new(x * 2, y), wall);
+            }
+        }
+    }
+    data
 }
 
 impl World {
     pub fn new(width: i32, height: i32, config: &GameConfig) -> Self {
+        let sizes = WorldSize::new(width, height, config);
+        let entities = EntityIndex::new(SizeInTiles::new(16, 16), sizes.map_size);
         let mut world = World {
-            sizes: WorldSize::new(width, height, config),
-            data: WorldData::new(width, height),
+            sizes,
+            data: generate_layout(width, height),
             data_internal: InternalWorldData::new(width, height),
             data_mob: InternalMobData::new(width, height),
             zones: WorldZoneData::new(16, 16, width, height, 0.2),
+            version: 0,
+            changes: VecDeque::new(),
+            cell_versions: CellVersions::new(width, height),
+            entities,
+            spawn_points: IndexedSet::new(),
+            clock: GameClock::new(),
+            powerups_enabled: config.powerups_enabled(),
         };
 
-        // Create walls.
-        for x in 0..world.sizes.map_size.width {
-            world.set_cell(MapPosition::new(x, 0), CellType::Wall);
-            world.set_cell(
-                MapPosition::new(x, world.sizes.map_size.height - 1),
-                CellType::Wall,
-            );
-        }
-
-        for y in 0..world.sizes.map_size.height {
-            world.set_cell(MapPosition::new(0, y), CellType::Wall);
-            world.set_cell(
-                MapPosition::new(world.sizes.map_size.width - 1, y),
-                CellType::Wall,
-            );
-
-            if y % 2 == 0 {
-                for x in 1..((world.sizes.map_size.width as f64 / 2.0) as i32) {
-                    world.set_cell(MapPosition::new(x * 2, y), CellType::Wall);
-                }
-            }
-        }
-
+        world.rebuild_spawn_points();
         world
     }
 
+    /// Replace the map with a freshly generated one of the specified size, and
+    /// return the new mob spawners.
+    pub fn reset(&mut self, width: i32, height: i32, config: &GameConfig) -> Vec<MobSpawner> {
+        let old_version = self.version;
+        let clock = self.clock;
+        *self = World::new(width, height, config);
+        let mob_spawners = self.add_mob_spawners();
+        self.populate_initial(&[]);
+        // Keep the version increasing so that nothing cached against the old
+        // map can be mistaken for the new one.
+        self.version += old_version + 1;
+        self.cell_versions.set_all(self.version);
+        self.clock = clock;
+        mob_spawners
+    }
+
     pub fn sizes(&self) -> &WorldSize {
         &self.sizes
     }
@@ -114,6 +168,30 @@ impl World {
         &self.data
     }
 
+    /// The world version is bumped every time a cell changes.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The world version at which the cell last changed.
+    pub fn cell_version(&self, pos: MapPosition) -> u64 {
+        self.cell_versions.get_at(pos).unwrap_or(0)
+    }
+
+    /// Returns true if the entire map fits inside a single chunk.
+    pub fn fits_in_chunk(&self) -> bool {
+        self.sizes.map_size.width <= self.sizes.chunk_size.width
+            && self.sizes.map_size.height <= self.sizes.chunk_size.height
+    }
+
+    pub fn clock(&self) -> &GameClock {
+        &self.clock
+    }
+
+    pub fn set_clock(&mut self, clock: &GameClock) {
+        self.clock = *clock;
+    }
+
     pub fn zones(&self) -> &WorldZoneData {
         &self.zones
     }
@@ -122,15 +200,33 @@ impl World {
         &mut self.zones
     }
 
-    fn get_index(&self, pos: MapPosition) -> usize {
-        ((pos.y * self.sizes.map_size.width) + pos.x) as usize
+    /// The index of the position in the map data, or None if it is off the map.
+    fn get_index(&self, pos: MapPosition) -> Option<usize> {
+        self.data.get_index(pos)
+    }
+
+    /// True if the position is on or outside the outer wall of the map.
+    pub fn is_border(&self, pos: MapPosition) -> bool {
+        pos.x <= 0
+            || pos.y <= 0
+            || pos.x >= self.sizes.map_size.width - 1
+            || pos.y >= self.sizes.map_size.height - 1
     }
 
+    /// Get the cell at the specified position, or None if it is off the map
+    /// or the data there is invalid.
     pub fn get_cell(&self, pos: MapPosition) -> Option<CellType> {
-        self.data.get_at(pos).map(CellType::from)
+        self.data
+            .get_at(pos)
+            .and_then(|value| CellType::try_from(value).ok())
     }
 
     pub fn set_cell(&mut self, pos: MapPosition, value: CellType) {
+        let old_value = self.data.get_at(pos);
+        if old_value.is_none() || old_value == Some(value as u8) {
+            return;
+        }
+
         if let Some(CellType::Mystery) = self.get_cell(pos) {
             self.zones.del_block_at_map_xy(pos);
         }
@@ -138,6 +234,49 @@ impl World {
             self.zones.add_block_at_map_xy(pos);
         }
         self.data.set_at(pos, value as u8);
+        self.version += 1;
+        self.changes.push_back((self.version, pos));
+        self.cell_versions.set_at(pos, self.version);
+        self.update_spawn_points(pos);
+    }
+
+    /// Get the cells within the chunk at `topleft` that have changed since
+    /// `since_version`.
+    pub fn get_chunk_delta(
+        &self,
+        since_version: u64,
+        topleft: MapPosition,
+        chunk_size: SizeInTiles,
+    ) -> WorldDelta
+    {
+        let mut seen = HashSet::new();
+        let mut delta = WorldDelta::new();
+        for (_, pos) in self.changes.iter().skip_while(|(v, _)| *v <= since_version) {
+            if pos.x < topleft.x
+                || pos.x >= topleft.x + chunk_size.width
+                || pos.y < topleft.y
+                || pos.y >= topleft.y + chunk_size.height
+            {
+                continue;
+            }
+
+            if seen.insert(*pos) {
+                if let Some(cell) = self.get_cell(*pos) {
+                    delta.push(*pos, cell);
+                }
+            }
+        }
+        delta
+    }
+
+    /// Discard logged changes that every player has already been sent.
+    pub fn clear_changes_up_to(&mut self, version: u64) {
+        while let Some((v, _)) = self.changes.front() {
+            if *v > version {
+                break;
+            }
+            self.changes.pop_front();
+        }
     }
 
     /// This is here for debugging only.
@@ -232,7 +371,7 @@ impl World {
     }
 
     pub fn set_mob_data(&mut self, pos: MapPosition, timestamp: Timestamp) {
-        match self.data_mob.get_at(pos) {
+        match self.data_mob.get_at(pos, self.clock.now()) {
             Some(ts) => {
                 // Only overwrite the bomb timestamp if it's sooner
                 // i.e. if this one was deployed before the existing one
@@ -245,85 +384,157 @@ impl World {
     }
 
     pub fn get_mob_data(&self, pos: MapPosition) -> Option<Timestamp> {
-        self.data_mob.get_at(pos)
+        self.data_mob.get_at(pos, self.clock.now())
+    }
+
+    /// Get every tile within range of centre that is in the path of a bomb,
+    /// along with the number of seconds until it explodes.
+    pub fn danger_tiles(
+        &self,
+        centre: MapPosition,
+        xrange: i32,
+        yrange: i32,
+    ) -> Vec<(MapPosition, f64)>
+    {
+        let now = self.clock.now();
+        let map_size = self.sizes.map_size;
+        let mut tiles = Vec::new();
+        for y in (centre.y - yrange + 1).max(0)..(centre.y + yrange).min(map_size.height) {
+            for x in (centre.x - xrange + 1).max(0)..(centre.x + xrange).min(map_size.width) {
+                let pos = MapPosition::new(x, y);
+                if let Some(ts) = self.get_mob_data(pos) {
+                    let secs = ts.millis_since(now).max(0) as f64 / 1000.0;
+                    tiles.push((pos, secs));
+                }
+            }
+        }
+        tiles
     }
 
     pub fn get_mob_data_update(&mut self, pos: MapPosition) -> Option<Timestamp> {
-        self.data_mob.get_at_fix(pos)
+        self.data_mob.get_at_fix(pos, self.clock.now())
     }
 
     pub fn clear_mob_data(&mut self, pos: MapPosition) {
         self.data_mob.set_at(pos, None);
     }
 
-    pub fn get_spawn_point(&self) -> MapPosition {
-        for _ in 0..1000 {
-            let tx = rand::thread_rng().gen_range(0, self.sizes.map_size.width);
-            let ty = rand::thread_rng().gen_range(0, self.sizes.map_size.height);
-            let pos = self.find_nearest_blank(MapPosition::new(tx, ty));
+    /// Get a random blank tile with at least 2 blank neighbours, or None if
+    /// there are none.
+    pub fn get_spawn_point(&self) -> Option<MapPosition> {
+        self.spawn_points.choose(&mut rand::thread_rng())
+    }
 
-            let mut count = 0;
-            if let Some(CellType::Empty) = self.get_cell(MapPosition::new(pos.x - 1, pos.y)) {
-                count += 1;
-            }
-            if let Some(CellType::Empty) = self.get_cell(MapPosition::new(pos.x + 1, pos.y)) {
-                count += 1;
-            }
-            if let Some(CellType::Empty) = self.get_cell(MapPosition::new(pos.x, pos.y - 1)) {
-                count += 1;
-            }
-            if let Some(CellType::Empty) = self.get_cell(MapPosition::new(pos.x, pos.y + 1)) {
-                count += 1;
+    fn is_good_spawn_point(&self, pos: MapPosition) -> bool {
+        if let Some(CellType::Empty) = self.get_cell(pos) {
+            let count = [pos.up(1), pos.down(1), pos.left(1), pos.right(1)]
+                .iter()
+                .filter(|p| matches!(self.get_cell(**p), Some(CellType::Empty)))
+                .count();
+            count >= 2
+        } else {
+            false
+        }
+    }
+
+    /// Re-check the specified tile and its neighbours after it has changed.
+    fn update_spawn_points(&mut self, pos: MapPosition) {
+        for p in &[pos, pos.up(1), pos.down(1), pos.left(1), pos.right(1)] {
+            if self.is_good_spawn_point(*p) {
+                self.spawn_points.insert(*p);
+            } else {
+                self.spawn_points.remove(p);
             }
+        }
+    }
 
-            if count >= 2 {
-                return pos;
+    fn rebuild_spawn_points(&mut self) {
+        self.spawn_points.clear();
+        for y in 0..self.sizes.map_size.height {
+            for x in 0..self.sizes.map_size.width {
+                let pos = MapPosition::new(x, y);
+                if self.is_good_spawn_point(pos) {
+                    self.spawn_points.insert(pos);
+                }
             }
         }
+    }
 
-        MapPosition::new(1, 1)
+    /// The chunk size to use for a player that requested the specified view
+    /// size. Requests are limited to between the default chunk size and
+    /// max_size, and chunks are never larger than the map.
+    pub fn view_size(&self, requested: Option<SizeInTiles>, max_size: SizeInTiles) -> SizeInTiles {
+        let default_size = self.sizes.chunk_size;
+        let size = requested.map_or(default_size, |size| {
+            SizeInTiles::new(
+                size.width.min(max_size.width).max(default_size.width),
+                size.height.min(max_size.height).max(default_size.height),
+            )
+        });
+        SizeInTiles::new(
+            size.width.min(self.sizes.map_size.width),
+            size.height.min(self.sizes.map_size.height),
+        )
     }
 
-    pub fn get_chunk_data(&self, position: MapPosition) -> WorldChunk {
-        let halfwidth = self.sizes.chunk_size.width / 2;
-        let halfheight = self.sizes.chunk_size.height / 2;
-        let maxx = self.sizes.map_size.width - self.sizes.chunk_size.width;
-        let maxy = self.sizes.map_size.height - self.sizes.chunk_size.height;
-        let mut topleft = position + PositionOffset::new(-halfwidth, -halfheight);
+    /// Get the top left position of the chunk of the specified size centred
+    /// on the specified position.
+    pub fn get_chunk_topleft(&self, position: MapPosition, chunk_size: SizeInTiles) -> MapPosition {
+        let halfwidth = chunk_size.width / 2;
+        let halfheight = chunk_size.height / 2;
+        let maxx = (self.sizes.map_size.width - chunk_size.width).max(0);
+        let maxy = (self.sizes.map_size.height - chunk_size.height).max(0);
+
+        // Clamp rect to map bounds. The position may be anywhere, so avoid
+        // overflowing.
+        MapPosition::new(
+            position.x.saturating_sub(halfwidth).max(0).min(maxx),
+            position.y.saturating_sub(halfheight).max(0).min(maxy),
+        )
+    }
 
-        // Clamp rect to map bounds.
-        if topleft.x < 0 {
-            topleft.x = 0;
-        } else if topleft.x > maxx {
-            topleft.x = maxx;
-        }
-        if topleft.y < 0 {
-            topleft.y = 0;
-        } else if topleft.y > maxy {
-            topleft.y = maxy;
-        }
+    pub fn get_chunk_data(&self, position: MapPosition, chunk_size: SizeInTiles) -> WorldChunk {
+        let mut chunk = WorldChunk::new(0, 0, chunk_size.width, chunk_size.height);
+        self.get_chunk_data_into(position, chunk_size, &mut chunk);
+        chunk
+    }
 
-        let mut chunk = WorldChunk::new(
-            topleft.x,
-            topleft.y,
-            self.sizes.chunk_size.width,
-            self.sizes.chunk_size.height,
+    /// Same as get_chunk_data() but copies into an existing chunk, reusing its
+    /// buffer rather than allocating a new one. Chunks are never larger than
+    /// the map.
+    pub fn get_chunk_data_into(
+        &self,
+        position: MapPosition,
+        chunk_size: SizeInTiles,
+        chunk: &mut WorldChunk,
+    )
+    {
+        let chunk_size = SizeInTiles::new(
+            chunk_size.width.max(0).min(self.sizes.map_size.width),
+            chunk_size.height.max(0).min(self.sizes.map_size.height),
         );
+        let topleft = self.get_chunk_topleft(position, chunk_size);
+        chunk.reset(topleft.x, topleft.y, chunk_size.width, chunk_size.height);
 
-        let mut index_read = self.get_index(MapPosition::new(topleft.x, topleft.y));
+        let mut index_read = match self.get_index(topleft) {
+            Some(index) => index,
+            None => return,
+        };
         let mut index_write = 0 as usize;
-        for _ in 0..self.sizes.chunk_size.height {
+        for _ in 0..chunk_size.height {
             chunk.set_slice(
                 index_write,
-                self.data
-                    .get_slice(index_read, self.sizes.chunk_size.width as usize),
+                self.data.get_slice(index_read, chunk_size.width as usize),
             );
 
             index_read += self.sizes.map_size.width as usize;
-            index_write += self.sizes.chunk_size.width as usize;
+            index_write += chunk_size.width as usize;
         }
+    }
 
-        chunk
+    /// Get the entire map as a single chunk. Only sensible for small maps.
+    pub fn get_full_chunk(&self) -> WorldChunk {
+        WorldChunk::from_data(0, 0, self.data.clone())
     }
 
     pub fn is_nearby_map_entity(
@@ -336,34 +547,57 @@ impl World {
         entities.iter().any(|e| e.is_within_range(pos, range))
     }
 
-    pub fn populate_blocks(&mut self, map_positions: &[MapPosition]) {
-        let mut new_blocks = HashSet::new();
-        for zone in self.zones.zone_iter_sorted_by_shortfall() {
-            if zone.quota_reached() {
-                // We're using a sorted iterator, so no point continuing.
-                break;
-            }
+    /// Rebuild the zone block counts from the map, in case they have drifted.
+    /// Returns the number of zones that were corrected.
+    pub fn recount_zones(&mut self) -> usize {
+        self.zones.recount_from(&self.data)
+    }
 
-            let bx = rand::thread_rng().gen_range(0, zone.size().width) + zone.position().x;
-            let by = rand::thread_rng().gen_range(0, zone.size().height) + zone.position().y;
-            let blank = self.find_nearest_blank(MapPosition::new(bx, by));
+    /// Add up to max_blocks mystery blocks, starting with the zones that are
+    /// furthest below their quota, keeping clear of the specified entities.
+    /// Returns the number of blocks added.
+    pub fn populate_blocks(&mut self, map_positions: &[MapPosition], max_blocks: usize) -> usize {
+        debug_assert!(
+            self.zones.block_counts_match(&self.data),
+            "Zone block counts have drifted from the map"
+        );
+        let mut added = 0;
+        while added < max_blocks {
+            // Every block added changes the shortfalls, so sort again each pass.
+            let zones: Vec<(MapPosition, SizeInTiles)> = self
+                .zones
+                .zone_iter_sorted_by_shortfall()
+                .take_while(|zone| !zone.quota_reached())
+                .map(|zone| (zone.position(), zone.size()))
+                .collect();
+            let added_before = added;
+            for (position, size) in zones {
+                if added >= max_blocks {
+                    break;
+                }
 
-            // Avoid top left corner - it's the safe space for spawning players if no blank
-            // spaces were found.
-            if blank.x == 1 && blank.y == 1 {
-                continue;
+                let bx = rand::thread_rng().gen_range(0, size.width) + position.x;
+                let by = rand::thread_rng().gen_range(0, size.height) + position.y;
+                let blank = self.find_nearest_blank(MapPosition::new(bx, by));
+
+                // Avoid top left corner - it's the safe space for spawning players if no blank
+                // spaces were found.
+                if blank.x == 1 && blank.y == 1 {
+                    continue;
+                }
+
+                if !self.is_nearby_map_entity(blank, map_positions, 4) {
+                    self.set_cell(blank, CellType::Mystery);
+                    added += 1;
+                }
             }
 
-            if !self.is_nearby_map_entity(blank, map_positions, 4) {
-                new_blocks.insert(blank);
+            if added == added_before {
+                // Nowhere left to put them, for now.
                 break;
             }
         }
-
-        // Now add the blocks.
-        for block in new_blocks {
-            self.set_cell(block, CellType::Mystery);
-        }
+        added
     }
 
     pub fn populate_initial(&mut self, map_positions: &[MapPosition]) {
@@ -432,23 +666,60 @@ impl World {
         mob_spawners
     }
 
-    pub fn add_bomb(&mut self, bomb: Bomb, bombs: &mut BombList) {
+    /// Rebuild the index of entity positions. Bombs and explosions added to
+    /// the world after this are indexed as they are added.
+    pub fn index_entities(
+        &mut self,
+        players: &PlayerList,
+        mobs: &MobList,
+        bombs: &BombList,
+        explosions: &ExplosionList,
+    )
+    {
+        self.entities.clear();
+        for p in players.values() {
+            let pos = p.map_position(self);
+            self.entities.add_player(pos, p.id());
+        }
+        for m in mobs.iter() {
+            let pos = m.position().to_map_position(self);
+            self.entities.add_mob(pos, m.id());
+        }
+        for b in bombs.iter() {
+            self.entities.add_bomb(b.position(), b.id());
+        }
+        for e in explosions.iter() {
+            self.entities.add_explosion(e.position(), e.id());
+        }
+    }
+
+    /// Get the ids of all indexed entities within the specified grid around
+    /// centre.
+    pub fn query_region(&self, centre: MapPosition, xrange: i32, yrange: i32) -> NearbyEntities {
+        self.entities.query_region(centre, xrange, yrange)
+    }
+
+    pub fn add_bomb(&mut self, bomb: Bomb, bombs: &mut BombList) -> BombId {
         let pos = bomb.position();
         let id = bombs.add(bomb);
+        self.entities.add_bomb(pos, id);
         self.set_cell(pos, CellType::Bomb);
         self.update_bomb_path(id, &bombs);
         self.data_internal.set_at(pos, InternalCellData::Bomb(id));
+        id
     }
 
     pub fn add_explosion(&mut self, explosion: Explosion, explosions: &mut ExplosionList) {
         let pos = explosion.position();
         let id = explosions.add(explosion);
+        self.entities.add_explosion(pos, id);
         self.data_internal
             .set_at(pos, InternalCellData::Explosion(id));
     }
 
     pub fn add_visual_only_explosion(&mut self, pos: MapPosition, explosions: &mut ExplosionList) {
-        explosions.add(Explosion::from(pos));
+        let id = explosions.add(Explosion::new(None, pos, &self.clock));
+        self.entities.add_explosion(pos, id);
     }
 
     pub fn clear_explosion_cell(&mut self, explosion: &Explosion) {
@@ -456,7 +727,7 @@ impl World {
         self.clear_internal_cell(pos);
 
         // Also let mobs know it's "safe" here now
-        if let Some(ts) = self.data_mob.get_at(pos) {
+        if let Some(ts) = self.get_mob_data(pos) {
             if explosion.timestamp() >= ts {
                 self.clear_mob_data(pos);
             }
@@ -473,7 +744,8 @@ impl World {
         let mut bombs_to_follow: VecDeque<BombId> = VecDeque::new();
         bombs_to_follow.push_back(bid);
         let mut seen: HashSet<MapPosition> = HashSet::new();
-        let mut earliest_ts = match bombs.get(bid) {
+        // Duds are no threat to anyone.
+        let mut earliest_ts = match bombs.get(bid).filter(|b| !b.is_dud()) {
             Some(b) => {
                 seen.insert(b.position());
                 b.timestamp()
@@ -485,19 +757,12 @@ impl World {
         let mut path_cells = Vec::new();
 
         while let Some(bomb_id) = bombs_to_follow.pop_front() {
-            if let Some(b) = bombs.get(bomb_id) {
+            if let Some(b) = bombs.get(bomb_id).filter(|b| !b.is_dud()) {
                 if b.timestamp() < earliest_ts {
                     earliest_ts = b.timestamp();
                 }
 
-                for offset in vec![
-                    PositionOffset::up(1),
-                    PositionOffset::down(1),
-                    PositionOffset::left(1),
-                    PositionOffset::right(1),
-                ]
-                .into_iter()
-                {
+                for &offset in BLAST_DIRECTIONS.iter() {
                     for dist in 1..=*b.range() {
                         let pos = b.position() + (offset * dist as i32);
                         if seen.contains(&pos) {
@@ -535,53 +800,86 @@ impl World {
         }
     }
 
+    /// Explode the specified bomb, which should already have been removed from
+    /// the bomb list. Any bombs caught in the blast will also explode.
     pub fn explode_bomb(
         &mut self,
-        bomb_id: BombId,
+        mut bomb: Bomb,
         bombs: &mut BombList,
         explosions: &mut ExplosionList,
         players: &mut PlayerList,
+        scores: &ScoreConfig,
     )
     {
         let mut bombs_to_explode: VecDeque<BombId> = VecDeque::new();
-        bombs_to_explode.push_back(bomb_id);
+        bombs_to_explode.extend(self.explode_single_bomb(&mut bomb, explosions, players, scores));
         while let Some(bomb_id) = bombs_to_explode.pop_front() {
             if let Some(b) = bombs.get_mut(bomb_id) {
-                if let Some(CellType::Bomb) = self.get_cell(b.position()) {
-                    self.set_cell(b.position(), CellType::Empty);
-                    self.clear_internal_cell(b.position());
-                }
-
-                let bombs_cascade = self.explode_bomb_path(b, explosions);
-                // Update player bomb count.
-                if let Some(p) = players.get_mut(&b.pid()) {
-                    p.bomb_exploded();
+                if !b.is_active() {
+                    // Already exploded as part of this chain.
+                    continue;
                 }
 
-                b.terminate();
+                let bombs_cascade = self.explode_single_bomb(b, explosions, players, scores);
                 bombs_to_explode.extend(bombs_cascade);
             }
         }
     }
 
+    fn explode_single_bomb(
+        &mut self,
+        bomb: &mut Bomb,
+        explosions: &mut ExplosionList,
+        players: &mut PlayerList,
+        scores: &ScoreConfig,
+    ) -> Vec<BombId>
+    {
+        if let Some(CellType::Bomb) = self.get_cell(bomb.position()) {
+            self.set_cell(bomb.position(), CellType::Empty);
+            self.clear_internal_cell(bomb.position());
+        }
+
+        let (bombs_cascade, blocks_destroyed) = self.explode_bomb_path(bomb, explosions);
+        // Update player bomb count, and award points for any blocks destroyed.
+        // Bombs that don't belong to a player have an id of 0.
+        if let Some(p) = players.get_mut(&bomb.pid()) {
+            p.bomb_exploded();
+            if bomb.pid() != PlayerId::from(0) {
+                p.increase_score(blocks_destroyed * scores.mystery_block());
+            }
+        }
+
+        bomb.terminate();
+        bombs_cascade
+    }
+
+    /// Add explosions along the path of the bomb. Returns the bombs caught
+    /// in the blast and the number of mystery blocks destroyed.
     pub fn explode_bomb_path(
         &mut self,
         bomb: &Bomb,
         explosions: &mut ExplosionList,
-    ) -> Vec<BombId>
+    ) -> (Vec<BombId>, u32)
     {
-        self.add_explosion(Explosion::from((bomb.clone(), bomb.position())), explosions);
+        if bomb.is_dud() {
+            // It just fizzles out.
+            return (Vec::new(), 0);
+        }
+
+        let clock = self.clock;
+        let explosion = |pos: MapPosition| {
+            let mut explosion = Explosion::new(Some(bomb), pos, &clock);
+            // Fade out towards the edge, without ever reaching 0.
+            let dist = pos.distance_to(bomb.position()) as f64;
+            explosion.set_intensity(1.0 - dist / (*bomb.range() + 1) as f64);
+            explosion
+        };
+        self.add_explosion(explosion(bomb.position()), explosions);
 
         let mut bombs_cascade = Vec::new();
+        let mut blocks_destroyed = 0;
 
-        for offset in vec![
-            PositionOffset::up(1),
-            PositionOffset::down(1),
-            PositionOffset::left(1),
-            PositionOffset::right(1),
-        ]
-        .into_iter()
-        {
+        for &offset in BLAST_DIRECTIONS.iter() {
             for dist in 1..=*bomb.range() {
                 let pos = bomb.position() + (offset * dist as i32);
                 match self.get_cell(pos) {
@@ -593,7 +891,7 @@ impl World {
                             bombs_cascade.push(*bomb_id);
                         } else {
                             // Can't find bomb? Might as well assume the cell is empty.
-                            self.add_explosion(Explosion::from((bomb.clone(), pos)), explosions);
+                            self.add_explosion(explosion(pos), explosions);
                         }
                         break;
                     }
@@ -601,18 +899,20 @@ impl World {
                     Some(CellType::ItemBomb)
                     | Some(CellType::ItemRange)
                     | Some(CellType::ItemRandom) => {
-                        self.add_explosion(Explosion::from((bomb.clone(), pos)), explosions);
+                        self.add_explosion(explosion(pos), explosions);
                         self.set_cell(pos, CellType::Empty);
                     }
                     Some(CellType::Empty) | Some(CellType::MobSpawner) => {
-                        self.add_explosion(Explosion::from((bomb.clone(), pos)), explosions);
+                        self.add_explosion(explosion(pos), explosions);
                     }
 
                     // The following will block an explosion, so stop.
                     Some(CellType::Mystery) => {
                         // This will become a powerup item.
                         let r: f64 = rand::thread_rng().gen();
-                        let item = if r > 0.9 {
+                        let item = if !self.powerups_enabled {
+                            CellType::Empty
+                        } else if r > 0.9 {
                             // 10% chance.
                             CellType::ItemBomb
                         } else if r > 0.8 {
@@ -626,172 +926,643 @@ impl World {
                         } else {
                             CellType::Empty
                         };
-                        self.add_explosion(Explosion::from((bomb.clone(), pos)), explosions);
+                        self.add_explosion(explosion(pos), explosions);
                         self.set_cell(pos, item);
+                        blocks_destroyed += 1;
                         break;
                     }
                     Some(CellType::Wall) | None => break,
                 }
             }
         }
-        bombs_cascade
+        (bombs_cascade, blocks_destroyed)
     }
 }
 
-pub struct PathFindData {
-    position: MapPosition,
-    travelled: u32,
-    initial_offset: Option<PositionOffset>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::player::{Player, PlayerBuilder, PlayerId};
+    use serde_json::json;
+    use std::{collections::HashMap, time::Instant};
 
-impl PathFindData {
-    pub fn new(position: MapPosition) -> Self {
-        Self {
-            position,
-            travelled: 0,
-            initial_offset: None,
+    fn test_player() -> Player {
+        PlayerBuilder::new(PlayerId::from(1)).build()
+    }
+
+    #[test]
+    fn test_generate_layout() {
+        let (width, height) = (15, 11);
+        let data = generate_layout(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let border = x == 0 || y == 0 || x == width - 1 || y == height - 1;
+                let pillar = x % 2 == 0 && y % 2 == 0;
+                let expected = if border || pillar {
+                    CellType::Wall
+                } else {
+                    CellType::Empty
+                };
+                assert_eq!(
+                    data.get_at(MapPosition::new(x, y)),
+                    Some(expected as u8),
+                    "cell {}, {}",
+                    x,
+                    y
+                );
+            }
         }
     }
 
-    pub fn new_from(position: MapPosition, prev: &PathFindData) -> Self {
-        Self {
-            position,
-            travelled: prev.travelled + 1,
-            initial_offset: prev
-                .initial_offset
-                .or_else(|| Some(position - prev.position)),
+    #[test]
+    fn test_explode_bomb_cascade() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let player = test_player();
+        let mut bombs = BombList::new();
+        let mut explosions = ExplosionList::new();
+        let mut players = PlayerList::new();
+
+        // Bombs have range 1, so only the first two are close enough to chain.
+        let first = MapPosition::new(1, 1);
+        let second = MapPosition::new(2, 1);
+        let third = MapPosition::new(5, 1);
+        for pos in &[first, second, third] {
+            world.add_bomb(Bomb::new(&player, *pos, world.clock()), &mut bombs);
         }
+
+        let bomb = bombs
+            .iter()
+            .find(|b| b.position() == first)
+            .unwrap()
+            .clone();
+        bombs.destroy(bomb.id());
+        world.explode_bomb(
+            bomb,
+            &mut bombs,
+            &mut explosions,
+            &mut players,
+            &ScoreConfig::new(),
+        );
+
+        assert_eq!(world.get_cell(first), Some(CellType::Empty));
+        assert_eq!(world.get_cell(second), Some(CellType::Empty));
+        assert_eq!(world.get_cell(third), Some(CellType::Bomb));
+        let active: Vec<MapPosition> = bombs
+            .iter()
+            .filter(|b| b.is_active())
+            .map(|b| b.position())
+            .collect();
+        assert_eq!(active, vec![third]);
+
+        // Each bomb covers its own tile plus every open tile within range.
+        let mut exploded: Vec<MapPosition> = explosions.iter().map(|e| e.position()).collect();
+        exploded.sort_by_key(|p| (p.y, p.x));
+        let expected = vec![
+            first,
+            first,
+            second,
+            MapPosition::new(3, 1),
+            MapPosition::new(1, 2),
+        ];
+        assert_eq!(exploded, expected);
     }
-}
 
-impl World {
-    pub fn get_possible_moves<T>(
-        &self,
-        agent: &T,
-        pf: &PathFindData,
-        seen: &HashSet<MapPosition>,
-    ) -> Vec<PathFindData>
-    where
-        T: CanPass,
-    {
-        let pos = pf.position;
-        let mut possible_moves = Vec::new();
-        for m in &[pos.up(1), pos.right(1), pos.down(1), pos.left(1)] {
-            if seen.contains(&m) {
-                continue;
+    #[test]
+    fn test_powerups_disabled() {
+        let mut config = GameConfig::new();
+        config.set_powerups_enabled(false);
+        let mut world = World::new(15, 15, &config);
+        let player = test_player();
+        let mut bombs = BombList::new();
+        let mut explosions = ExplosionList::new();
+        let bomb_pos = MapPosition::new(1, 1);
+        let block = MapPosition::new(2, 1);
+        let id = world.add_bomb(Bomb::new(&player, bomb_pos, world.clock()), &mut bombs);
+        let bomb = bombs.get(id).unwrap().clone();
+
+        let mut items = 0;
+        for _ in 0..100 {
+            world.set_cell(block, CellType::Mystery);
+            let (_, destroyed) = world.explode_bomb_path(&bomb, &mut explosions);
+            assert_eq!(destroyed, 1);
+            if world.get_cell(block) != Some(CellType::Empty) {
+                items += 1;
             }
+        }
+        assert_eq!(items, 0);
+    }
 
-            if agent.can_pass(*m, self) {
-                possible_moves.push(PathFindData::new_from(*m, pf));
+    #[test]
+    fn test_explosion_through_items() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let mut player = test_player();
+        for _ in 0..3 {
+            player.increase_range();
+        }
+        let mut bombs = BombList::new();
+        let mut explosions = ExplosionList::new();
+        let mut players = PlayerList::new();
+
+        let bomb_pos = MapPosition::new(1, 1);
+        let items = [
+            (MapPosition::new(2, 1), CellType::ItemBomb),
+            (MapPosition::new(3, 1), CellType::ItemRange),
+            (MapPosition::new(4, 1), CellType::ItemRandom),
+        ];
+        let last = MapPosition::new(5, 1);
+        let out_of_range = MapPosition::new(6, 1);
+        for (pos, item) in items.iter() {
+            world.set_cell(*pos, *item);
+        }
+        world.set_cell(last, CellType::Empty);
+        world.set_cell(out_of_range, CellType::Empty);
+
+        let id = world.add_bomb(Bomb::new(&player, bomb_pos, world.clock()), &mut bombs);
+        // Mobs know the items won't stop the blast.
+        assert!(world.get_mob_data(last).is_some());
+        assert!(world.get_mob_data(out_of_range).is_none());
+
+        let bomb = bombs.get(id).unwrap().clone();
+        bombs.destroy(id);
+        world.explode_bomb(
+            bomb,
+            &mut bombs,
+            &mut explosions,
+            &mut players,
+            &ScoreConfig::new(),
+        );
+
+        for (pos, _) in items.iter() {
+            assert_eq!(world.get_cell(*pos), Some(CellType::Empty));
+        }
+        for x in 2..=5 {
+            let pos = MapPosition::new(x, 1);
+            match world.get_internal_cell(pos) {
+                Some(InternalCellData::Explosion(eid)) => {
+                    assert_eq!(explosions.get(*eid).unwrap().position(), pos);
+                }
+                x => panic!("Expected explosion at {:?}, got {:?}", pos, x),
             }
         }
-        possible_moves
+        assert!(explosions.iter().all(|e| e.position() != out_of_range));
     }
 
-    pub fn path_find<T>(
-        &self,
-        agent: &T,
-        pos_from: MapPosition,
-        pos_to: MapPosition,
-        range: u32,
-    ) -> Option<PositionOffset>
-    where
-        T: CanPass,
-    {
-        if pos_to == pos_from {
-            return None;
+    #[test]
+    fn test_dud_bomb() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let player = test_player();
+        let mut bombs = BombList::new();
+        let mut explosions = ExplosionList::new();
+        let mut players = PlayerList::new();
+        let dud_pos = MapPosition::new(5, 5);
+        let live_pos = MapPosition::new(6, 5);
+        for x in 4..=8 {
+            world.set_cell(MapPosition::new(x, 5), CellType::Empty);
         }
 
-        let mut open_list: Vec<PathFindData> = Vec::with_capacity(4);
-        open_list.push(PathFindData::new(pos_from));
+        let mut dud = Bomb::new(&player, dud_pos, world.clock());
+        dud.set_dud(true);
+        let dud_id = world.add_bomb(dud, &mut bombs);
+        // Mobs have nothing to fear from it.
+        assert!(world.danger_tiles(dud_pos, 4, 4).is_empty());
+
+        // A dud goes quietly.
+        let dud = bombs.get(dud_id).unwrap().clone();
+        bombs.destroy(dud_id);
+        world.explode_bomb(
+            dud,
+            &mut bombs,
+            &mut explosions,
+            &mut players,
+            &ScoreConfig::new(),
+        );
+        assert!(explosions.is_empty());
+        assert_eq!(world.get_cell(dud_pos), Some(CellType::Empty));
+        assert!(matches!(
+            world.get_internal_cell(dud_pos),
+            Some(InternalCellData::Empty)
+        ));
+
+        // A live bomb's blast fizzles out the dud rather than carrying on
+        // through it.
+        let mut dud = Bomb::new(&player, dud_pos, world.clock());
+        dud.set_dud(true);
+        let dud_id = world.add_bomb(dud, &mut bombs);
+        let live_id = world.add_bomb(Bomb::new(&player, live_pos, world.clock()), &mut bombs);
+        let bomb = bombs.get(live_id).unwrap().clone();
+        bombs.destroy(live_id);
+        world.explode_bomb(
+            bomb,
+            &mut bombs,
+            &mut explosions,
+            &mut players,
+            &ScoreConfig::new(),
+        );
+        assert!(!explosions.is_empty());
+        assert!(explosions.iter().all(|e| e.position().x > dud_pos.x));
+        assert!(!bombs.get(dud_id).unwrap().is_active());
+        assert_eq!(world.get_cell(dud_pos), Some(CellType::Empty));
+    }
 
-        let mut seen: HashSet<MapPosition> = HashSet::new();
-        seen.insert(pos_from);
-
-        while !open_list.is_empty() {
-            open_list.sort_by_cached_key(|a| a.travelled + a.position.distance_to(pos_to));
-            let mut processed = 0;
-            for element in &open_list {
-                if element.position == pos_to {
-                    return element.initial_offset;
-                }
-                seen.insert(element.position);
-                processed += 1;
-                if element.travelled < range {
-                    let mut new_moves = self.get_possible_moves(agent, element, &seen);
-                    if !new_moves.is_empty() {
-                        open_list.append(&mut new_moves);
-                        // Since we've added new elements, sort the list before going further.
-                        break;
-                    }
-                }
-            }
+    #[test]
+    fn test_invalid_cell_data() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let pos = MapPosition::new(3, 3);
+        world.data.set_at(pos, 42);
+        assert_eq!(world.get_cell(pos), None);
+        assert!(!world.is_good_spawn_point(pos));
+        assert!(world.get_full_chunk().ser_raw().is_ok());
+    }
+
+    #[test]
+    fn test_danger_tiles() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let player = test_player();
+        let mut bombs = BombList::new();
+        let pos = MapPosition::new(5, 5);
+        world.set_cell(pos.right(1), CellType::Empty);
+        world.set_cell(pos.down(1), CellType::Wall);
+        world.add_bomb(Bomb::new(&player, pos, world.clock()), &mut bombs);
+
+        let danger: HashMap<MapPosition, f64> =
+            world.danger_tiles(pos, 4, 4).into_iter().collect();
+        let secs = danger[&pos.right(1)];
+        assert!(secs > 0.0 && secs <= 3.0);
+        assert!(!danger.contains_key(&pos.down(1)));
+        assert!(!danger.contains_key(&pos.right(1).down(1)));
+
+        // Nothing outside the region is included.
+        assert!(world
+            .danger_tiles(MapPosition::new(12, 12), 3, 3)
+            .is_empty());
+    }
 
-            // Remove processed items.
-            open_list = open_list.split_off(processed);
+    #[test]
+    fn test_explosion_intensity() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let mut player = test_player();
+        player.increase_range();
+        let mut bombs = BombList::new();
+        let mut explosions = ExplosionList::new();
+        let centre = MapPosition::new(1, 1);
+        let edge = MapPosition::new(1, 3);
+        for y in 1..=3 {
+            world.set_cell(MapPosition::new(1, y), CellType::Empty);
         }
+        let id = world.add_bomb(Bomb::new(&player, centre, world.clock()), &mut bombs);
+        let bomb = bombs.get(id).unwrap().clone();
+        world.explode_bomb_path(&bomb, &mut explosions);
+
+        let intensity = |pos: MapPosition| {
+            explosions
+                .iter()
+                .find(|e| e.position() == pos)
+                .map(|e| e.intensity())
+                .unwrap()
+        };
+        assert_eq!(intensity(centre), 1.0);
+        assert!(intensity(MapPosition::new(1, 2)) < intensity(centre));
+        assert!(intensity(edge) < intensity(MapPosition::new(1, 2)));
+        assert!(intensity(edge) > 0.0);
+    }
+
+    #[test]
+    fn test_explosions_share_blast_id() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let player = test_player();
+        let mut bombs = BombList::new();
+        let mut explosions = ExplosionList::new();
+        let mut players = PlayerList::new();
+        let first = MapPosition::new(1, 1);
+        let second = MapPosition::new(2, 1);
+        let first_id = world.add_bomb(Bomb::new(&player, first, world.clock()), &mut bombs);
+        let second_id = world.add_bomb(Bomb::new(&player, second, world.clock()), &mut bombs);
+
+        let bomb = bombs.get(first_id).unwrap().clone();
+        bombs.destroy(first_id);
+        world.explode_bomb(
+            bomb,
+            &mut bombs,
+            &mut explosions,
+            &mut players,
+            &ScoreConfig::new(),
+        );
 
-        None
+        fn blast_ids(explosions: &ExplosionList, pos: MapPosition) -> Vec<Option<BombId>> {
+            explosions
+                .iter()
+                .filter(|e| e.position() == pos)
+                .map(|e| e.blast_id())
+                .collect()
+        }
+
+        // Only the first bomb reaches (1, 2) and only the chained one reaches (3, 1).
+        let below = MapPosition::new(1, 2);
+        assert_eq!(blast_ids(&explosions, below), vec![Some(first_id)]);
+        let right = MapPosition::new(3, 1);
+        assert_eq!(blast_ids(&explosions, right), vec![Some(second_id)]);
+        assert!(explosions
+            .iter()
+            .all(|e| e.blast_id() == Some(first_id) || e.blast_id() == Some(second_id)));
+
+        let visual = MapPosition::new(5, 5);
+        world.add_visual_only_explosion(visual, &mut explosions);
+        assert_eq!(blast_ids(&explosions, visual), vec![None]);
     }
 
-    pub fn path_find_nearest_safe_space<T>(
-        &self,
-        agent: &T,
-        pos: MapPosition,
-        range: u32,
-    ) -> MapPosition
-    where
-        T: CanPass,
-    {
-        if self.get_mob_data(pos).is_none() {
-            return pos;
+    #[test]
+    fn test_destroying_blocks_awards_points() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        let scores = ScoreConfig::new();
+        let mut bombs = BombList::new();
+        let mut explosions = ExplosionList::new();
+        let mut players = PlayerList::new();
+
+        // Each bomb has walls above and below it, and a block on either side.
+        for id in 0..2 {
+            let player = PlayerBuilder::new(PlayerId::from(id)).build();
+            let centre = MapPosition::new(2, 3 + 4 * id as i32);
+            world.set_cell(centre.left(1), CellType::Mystery);
+            world.set_cell(centre.right(1), CellType::Mystery);
+            world.add_bomb(Bomb::new(&player, centre, world.clock()), &mut bombs);
+            players.insert(player.id(), player);
         }
 
-        let mut open_list: Vec<PathFindData> = Vec::with_capacity(4);
-        open_list.push(PathFindData::new(pos));
+        let exploding: Vec<Bomb> = bombs.iter().cloned().collect();
+        for bomb in exploding {
+            bombs.destroy(bomb.id());
+            world.explode_bomb(bomb, &mut bombs, &mut explosions, &mut players, &scores);
+        }
 
-        let mut seen: HashSet<MapPosition> = HashSet::new();
-        seen.insert(pos);
+        let points = |id: u64| players[&PlayerId::from(id)].score();
+        assert_eq!(points(1), 2 * scores.mystery_block());
+        // Bombs without a player never score.
+        assert_eq!(points(0), 0);
+    }
 
-        // Um, a timestamp of now definitely isn't safe! let's hope we find a better
-        // one.
-        let mut safest_timestamp = Timestamp::default();
-        let mut safest_pos = pos;
+    #[test]
+    fn test_get_chunk_data_into() {
+        let mut world = World::new(101, 101, &GameConfig::new());
+        world.set_cell(MapPosition::new(40, 41), CellType::Mystery);
+        world.set_cell(MapPosition::new(61, 60), CellType::ItemBomb);
+
+        // Start with a buffer of the wrong size, then reuse it.
+        let mut chunk = WorldChunk::new(0, 0, 3, 3);
+        let size = *world.sizes().chunk_size();
+        for pos in &[MapPosition::new(50, 51), MapPosition::new(1, 1)] {
+            world.get_chunk_data_into(*pos, size, &mut chunk);
+            assert_eq!(
+                serde_json::to_value(&chunk).unwrap(),
+                serde_json::to_value(world.get_chunk_data(*pos, size)).unwrap()
+            );
+        }
+    }
 
-        while !open_list.is_empty() {
-            open_list.sort_by_cached_key(|a| a.travelled);
+    /// Apply a delta to the JSON of a chunk, as a client would.
+    fn apply_delta(chunk: &mut serde_json::Value, delta: &WorldDelta) {
+        let tx = chunk["tx"].as_i64().unwrap();
+        let ty = chunk["ty"].as_i64().unwrap();
+        let width = chunk["width"].as_i64().unwrap();
+        for change in serde_json::to_value(delta).unwrap().as_array().unwrap() {
+            let x = change["x"].as_i64().unwrap() - tx;
+            let y = change["y"].as_i64().unwrap() - ty;
+            chunk["data"][(y * width + x) as usize] = change["cell"].clone();
+        }
+    }
 
-            let mut processed = 0;
-            for element in &open_list {
-                match self.get_mob_data(element.position) {
-                    None => {
-                        return element.position;
-                    }
-                    Some(ts) => {
-                        if ts > safest_timestamp {
-                            safest_timestamp = ts;
-                            safest_pos = element.position;
-                        }
+    #[test]
+    fn test_chunk_delta_reproduces_chunk() {
+        let mut world = World::new(101, 101, &GameConfig::new());
+        let size = *world.sizes().chunk_size();
+        let pos = MapPosition::new(50, 51);
+        let topleft = world.get_chunk_topleft(pos, size);
+        let chunk_at =
+            |world: &World| serde_json::to_value(world.get_chunk_data(pos, size)).unwrap();
+
+        // One player was last sent the chunk before these changes.
+        let mut old_chunk = chunk_at(&world);
+        let old_version = world.version();
+        world.set_cell(MapPosition::new(45, 47), CellType::Mystery);
+        world.set_cell(MapPosition::new(51, 51), CellType::Bomb);
+        // Outside the chunk.
+        world.set_cell(MapPosition::new(1, 1), CellType::Mystery);
+
+        // Another player was sent it part way through.
+        let mut newer_chunk = chunk_at(&world);
+        let newer_version = world.version();
+        world.set_cell(MapPosition::new(51, 51), CellType::Empty);
+        world.set_cell(MapPosition::new(53, 55), CellType::ItemRange);
+        world.set_cell(MapPosition::new(53, 55), CellType::ItemBomb);
+
+        let current = chunk_at(&world);
+        assert_ne!(old_chunk, current);
+        apply_delta(
+            &mut old_chunk,
+            &world.get_chunk_delta(old_version, topleft, size),
+        );
+        assert_eq!(old_chunk, current);
+
+        // Once the first player is up to date, the older changes can go.
+        world.clear_changes_up_to(newer_version);
+        apply_delta(
+            &mut newer_chunk,
+            &world.get_chunk_delta(newer_version, topleft, size),
+        );
+        assert_eq!(newer_chunk, current);
+        assert!(world
+            .get_chunk_delta(world.version(), topleft, size)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_get_chunk_data_out_of_range() {
+        let world = World::new(31, 31, &GameConfig::new());
+        let size = SizeInTiles::new(11, 9);
+        let corners = [
+            (i32::MIN, i32::MIN, 0, 0),
+            (i32::MAX, i32::MIN, 20, 0),
+            (-5, 1000, 0, 22),
+        ];
+        for (x, y, tx, ty) in corners.iter() {
+            let chunk = world.get_chunk_data(MapPosition::new(*x, *y), size);
+            let value = serde_json::to_value(chunk).unwrap();
+            assert_eq!((&value["tx"], &value["ty"]), (&json!(tx), &json!(ty)));
+            assert_eq!(value["data"].as_array().unwrap().len(), 11 * 9);
+        }
+
+        // Chunks larger than the map are limited to the whole map.
+        let chunk = world.get_chunk_data(MapPosition::new(100, 100), SizeInTiles::new(50, 50));
+        assert_eq!(
+            serde_json::to_value(chunk).unwrap(),
+            serde_json::to_value(world.get_full_chunk()).unwrap()
+        );
+        assert_eq!(world.get_index(MapPosition::new(31, 0)), None);
+        assert_eq!(world.get_index(MapPosition::new(0, -1)), None);
+    }
+
+    #[test]
+    fn test_spawn_points_match_brute_force() {
+        let mut world = World::new(21, 21, &GameConfig::new());
+        let brute_force = |world: &World| {
+            let mut points = HashSet::new();
+            for y in 0..21 {
+                for x in 0..21 {
+                    let pos = MapPosition::new(x, y);
+                    if world.is_good_spawn_point(pos) {
+                        points.insert(pos);
                     }
                 }
+            }
+            points
+        };
+        let cached = |world: &World| world.spawn_points.iter().copied().collect::<HashSet<_>>();
+        assert_eq!(cached(&world), brute_force(&world));
+
+        let mut rng = rand::thread_rng();
+        let cells = [
+            CellType::Mystery,
+            CellType::Bomb,
+            CellType::ItemBomb,
+            CellType::Empty,
+        ];
+        for _ in 0..200 {
+            let pos = MapPosition::new(rng.gen_range(1, 20), rng.gen_range(1, 20));
+            if world.get_cell(pos) != Some(CellType::Wall) {
+                world.set_cell(pos, cells[rng.gen_range(0, cells.len())]);
+            }
+            assert_eq!(cached(&world), brute_force(&world));
+        }
 
-                seen.insert(element.position);
+        for _ in 0..20 {
+            assert!(world.is_good_spawn_point(world.get_spawn_point().unwrap()));
+        }
+    }
 
-                processed += 1;
-                if element.travelled < range {
-                    let mut new_moves = self.get_possible_moves(agent, element, &seen);
-                    if !new_moves.is_empty() {
-                        open_list.append(&mut new_moves);
-                        // Since we've added new elements, sort the list before going further.
-                        break;
-                    }
+    #[test]
+    fn test_no_spawn_point_on_full_map() {
+        let mut world = World::new(15, 15, &GameConfig::new());
+        assert!(world.get_spawn_point().is_some());
+        for y in 1..14 {
+            for x in 1..14 {
+                world.set_cell(MapPosition::new(x, y), CellType::Wall);
+            }
+        }
+        assert_eq!(world.get_spawn_point(), None);
+    }
+
+    #[test]
+    fn test_recount_zones() {
+        let mut world = World::new(47, 47, &GameConfig::new());
+        world.populate_blocks(&[], 1);
+        assert!(world.zones.block_counts_match(&world.data));
+        assert_eq!(world.recount_zones(), 0);
+
+        // Sneak a block in without going through set_cell().
+        let pos = world.find_nearest_blank(MapPosition::new(20, 20));
+        world.data.set_at(pos, CellType::Mystery as u8);
+        assert!(!world.zones.block_counts_match(&world.data));
+        assert_eq!(world.recount_zones(), 1);
+        assert!(world.zones.block_counts_match(&world.data));
+    }
+
+    #[test]
+    fn test_populate_blocks_refills() {
+        let mut world = World::new(47, 47, &GameConfig::new());
+        let positions: Vec<MapPosition> = (0..47)
+            .flat_map(|y| (0..47).map(move |x| MapPosition::new(x, y)))
+            .collect();
+        let count_blocks = |world: &World| {
+            positions
+                .iter()
+                .filter(|p| world.get_cell(**p) == Some(CellType::Mystery))
+                .count()
+        };
+        for pos in positions.iter() {
+            if world.get_cell(*pos) == Some(CellType::Mystery) {
+                world.set_cell(*pos, CellType::Empty);
+            }
+        }
+        assert_eq!(count_blocks(&world), 0);
+
+        let mut total = 0;
+        for _ in 0..3 {
+            let added = world.populate_blocks(&[], 4);
+            assert!(added > 0 && added <= 4);
+            total += added;
+            assert_eq!(count_blocks(&world), total);
+        }
+
+        // Keep going and every zone reaches its quota.
+        for _ in 0..1000 {
+            if world.populate_blocks(&[], 4) == 0 {
+                break;
+            }
+        }
+        assert!(world.zones.zone_iter().all(|zone| zone.quota_reached()));
+        assert!(world.zones.block_counts_match(&world.data));
+    }
+
+    #[test]
+    fn test_populate_blocks_tops_up_many_zones() {
+        let mut world = World::new(47, 47, &GameConfig::new());
+        for y in 0..47 {
+            for x in 0..47 {
+                let pos = MapPosition::new(x, y);
+                if world.get_cell(pos) == Some(CellType::Mystery) {
+                    world.set_cell(pos, CellType::Empty);
                 }
             }
+        }
+
+        let num_zones = world.zones.zone_count();
+        assert!(num_zones > 1);
+        world.populate_blocks(&[], num_zones);
+        let topped_up = world
+            .zones
+            .zone_iter()
+            .filter(|zone| zone.num_blocks() > 0)
+            .count();
+        assert!(topped_up > 1, "only {} zone(s) got blocks", topped_up);
+    }
 
-            // Remove processed items.
-            open_list = open_list.split_off(processed);
+    /// Compare serializing a chunk for each of 10 clustered players against
+    /// serializing it once and copying the JSON text, as send_data_to_player()
+    /// does. Run with:
+    /// cargo test --release bench_shared_chunk -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_shared_chunk() {
+        let world = World::new(101, 101, &GameConfig::new());
+        let players: Vec<MapPosition> = (0..10).map(|i| MapPosition::new(50 + i, 51)).collect();
+        let size = *world.sizes().chunk_size();
+        let frames = 1000;
+
+        let start = Instant::now();
+        for _ in 0..frames {
+            for pos in &players {
+                let value = serde_json::to_value(world.get_chunk_data(*pos, size)).unwrap();
+                assert!(value.to_string().starts_with('{'));
+            }
         }
+        let separate = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..frames {
+            let mut cache = HashMap::new();
+            for pos in &players {
+                let topleft = world.get_chunk_topleft(*pos, size);
+                let raw = cache
+                    .entry(topleft)
+                    .or_insert_with(|| world.get_chunk_data(*pos, size).ser_raw().unwrap())
+                    .clone();
+                assert!(serde_json::to_string(&raw).unwrap().starts_with('{'));
+            }
+        }
+        let shared = start.elapsed();
 
-        safest_pos
+        println!(
+            "{} frames: per player {:?}, shared {:?}",
+            frames, separate, shared
+        );
     }
 }
@@ -1,10 +1,14 @@
 use crate::{
     engine::{bomb::BombId, explosion::ExplosionId, position::MapPosition},
     error::{ZError, ZResult},
+    traits::{
+        celltypes::CellType,
+        worldobject::{ClientData, ToClientData},
+    },
     utils::misc::Timestamp,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{value::RawValue, Value};
 use std::convert::TryFrom;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +23,18 @@ impl TryFrom<&WorldData> for SerWorldData {
     }
 }
 
+impl ToClientData for SerWorldData {
+    fn to_client_data(&self) -> ZResult<ClientData> {
+        Ok(self.0.clone())
+    }
+
+    fn update_from_client_data(&mut self, _data: ClientData) -> ZResult<()> {
+        Err(ZError::ReadOnly(String::from(
+            "World data can't be changed by clients",
+        )))
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct WorldData {
     data: Vec<u8>,
@@ -35,6 +51,14 @@ impl WorldData {
         }
     }
 
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
     pub fn get_index(&self, pos: MapPosition) -> Option<usize> {
         if pos.x < 0 || pos.x >= self.width || pos.y < 0 || pos.y >= self.height {
             None
@@ -53,6 +77,14 @@ impl WorldData {
         }
     }
 
+    /// Change the dimensions, reusing the existing allocation where possible.
+    /// The contents are not preserved in any meaningful layout.
+    pub fn resize(&mut self, width: i32, height: i32) {
+        self.data.resize((width * height) as usize, 0);
+        self.width = width;
+        self.height = height;
+    }
+
     pub fn get_slice(&self, index: usize, length: usize) -> &[u8] {
         &self.data[index..(index + length)]
     }
@@ -84,9 +116,82 @@ impl WorldChunk {
         }
     }
 
+    pub fn from_data(tx: i32, ty: i32, data: WorldData) -> Self {
+        WorldChunk { tx, ty, data }
+    }
+
+    /// Move and resize the chunk. The data should then be overwritten.
+    pub fn reset(&mut self, tx: i32, ty: i32, width: i32, height: i32) {
+        self.tx = tx;
+        self.ty = ty;
+        self.data.resize(width, height);
+    }
+
     pub fn set_slice(&mut self, index: usize, slice: &[u8]) {
         self.data.set_slice(index, slice);
     }
+
+    /// Serialize straight to JSON text so that it can be shared between
+    /// players without being serialized again.
+    pub fn ser_raw(&self) -> ZResult<Box<RawValue>> {
+        Ok(RawValue::from_string(serde_json::to_string(
+            &self.to_client_data()?,
+        )?)?)
+    }
+}
+
+impl ToClientData for WorldChunk {
+    fn to_client_data(&self) -> ZResult<ClientData> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    fn update_from_client_data(&mut self, _data: ClientData) -> ZResult<()> {
+        Err(ZError::ReadOnly(String::from(
+            "World chunks can't be changed by clients",
+        )))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SerWorldDelta(Value);
+
+impl TryFrom<&WorldDelta> for SerWorldDelta {
+    type Error = ZError;
+
+    fn try_from(delta: &WorldDelta) -> ZResult<Self> {
+        Ok(SerWorldDelta(serde_json::to_value(delta)?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CellChange {
+    #[serde(flatten)]
+    position: MapPosition,
+    cell: CellType,
+}
+
+/// The list of cells that have changed within a chunk since the last update.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct WorldDelta(Vec<CellChange>);
+
+impl WorldDelta {
+    pub fn new() -> Self {
+        WorldDelta::default()
+    }
+
+    pub fn push(&mut self, position: MapPosition, cell: CellType) {
+        self.0.push(CellChange { position, cell });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn ser(&self) -> ZResult<SerWorldDelta> {
+        SerWorldDelta::try_from(self)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -131,6 +236,49 @@ impl InternalWorldData {
     }
 }
 
+/// The world version at which each cell last changed.
+#[derive(Debug, Clone)]
+pub struct CellVersions {
+    data: Vec<u64>,
+    width: i32,
+    height: i32,
+}
+
+impl CellVersions {
+    pub fn new(width: i32, height: i32) -> Self {
+        CellVersions {
+            data: vec![0; (width * height) as usize],
+            width,
+            height,
+        }
+    }
+
+    fn get_index(&self, pos: MapPosition) -> Option<usize> {
+        if pos.x < 0 || pos.x >= self.width || pos.y < 0 || pos.y >= self.height {
+            None
+        } else {
+            Some(((pos.y * self.width) + pos.x) as usize)
+        }
+    }
+
+    pub fn get_at(&self, pos: MapPosition) -> Option<u64> {
+        self.get_index(pos).map(|index| self.data[index])
+    }
+
+    pub fn set_at(&mut self, pos: MapPosition, version: u64) {
+        if let Some(index) = self.get_index(pos) {
+            self.data[index] = version;
+        }
+    }
+
+    /// Mark every cell as changed at the specified version.
+    pub fn set_all(&mut self, version: u64) {
+        for value in self.data.iter_mut() {
+            *value = version;
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct InternalMobData {
     data: Vec<Option<Timestamp>>,
@@ -155,17 +303,17 @@ impl InternalMobData {
         }
     }
 
-    pub fn get_at(&self, pos: MapPosition) -> Option<Timestamp> {
+    pub fn get_at(&self, pos: MapPosition, now: Timestamp) -> Option<Timestamp> {
         self.get_index(pos)
             .map(|index| self.data[index])
             .flatten()
-            .filter(|ts| !ts.is_past())
+            .filter(|ts| !ts.is_past(now))
     }
 
-    pub fn get_at_fix(&mut self, pos: MapPosition) -> Option<Timestamp> {
+    pub fn get_at_fix(&mut self, pos: MapPosition, now: Timestamp) -> Option<Timestamp> {
         let got = self.get_index(pos).map(|index| self.data[index]).flatten();
         if let Some(ts) = got {
-            if ts.is_past() {
+            if ts.is_past(now) {
                 self.set_at(pos, None);
                 None
             } else {
@@ -197,3 +345,31 @@ impl MobSpawner {
         self.position
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_chunk_client_data() {
+        let mut data = WorldData::new(3, 2);
+        let cells = [0, 1, 2, 3, 4, 100];
+        data.set_slice(0, &cells);
+        let mut chunk = WorldChunk::from_data(5, 7, data);
+
+        let client_data = chunk.to_client_data().unwrap();
+        assert_eq!(
+            client_data,
+            json!({"tx": 5, "ty": 7, "data": cells, "width": 3, "height": 2})
+        );
+        let raw = chunk.ser_raw().unwrap();
+        let parsed: Value = serde_json::from_str(raw.get()).unwrap();
+        assert_eq!(parsed, client_data);
+
+        match chunk.update_from_client_data(client_data) {
+            Err(ZError::ReadOnly(_)) => {}
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+}
@@ -1,4 +1,10 @@
-use crate::engine::position::{MapPosition, SizeInTiles};
+use crate::{
+    engine::{
+        position::{MapPosition, SizeInTiles},
+        worlddata::WorldData,
+    },
+    traits::celltypes::CellType,
+};
 use itertools::Itertools;
 use log::*;
 use std::cmp::min;
@@ -52,6 +58,10 @@ impl WorldZone {
         self.size
     }
 
+    pub fn num_blocks(&self) -> i32 {
+        self.num_blocks
+    }
+
     pub fn quota(&self) -> i32 {
         self.block_quota
     }
@@ -147,6 +157,48 @@ impl WorldZoneData {
         }
     }
 
+    /// Count the blocks in each zone by scanning the map.
+    fn count_blocks(&self, data: &WorldData) -> Vec<i32> {
+        let mut counts = vec![0; self.zones.len()];
+        for y in 0..data.height() {
+            for x in 0..data.width() {
+                let pos = MapPosition::new(x, y);
+                if data.get_at(pos) == Some(CellType::Mystery as u8) {
+                    if let Some(zone_index) = self.map_to_zone_index(pos) {
+                        counts[zone_index.0] += 1;
+                    }
+                }
+            }
+        }
+        counts
+    }
+
+    /// Returns true if the incremental block counts match the map.
+    pub fn block_counts_match(&self, data: &WorldData) -> bool {
+        self.count_blocks(data)
+            .iter()
+            .zip(self.zones.iter())
+            .all(|(count, zone)| *count == zone.num_blocks)
+    }
+
+    /// Rebuild the block counts by scanning the map, logging any zone whose
+    /// count had drifted. Returns the number of zones that were corrected.
+    pub fn recount_from(&mut self, data: &WorldData) -> usize {
+        let counts = self.count_blocks(data);
+        let mut corrected = 0;
+        for (count, zone) in counts.into_iter().zip(self.zones.iter_mut()) {
+            if zone.num_blocks != count {
+                warn!(
+                    "Zone at {:?} had {} blocks but should have {}",
+                    zone.position, zone.num_blocks, count
+                );
+                zone.num_blocks = count;
+                corrected += 1;
+            }
+        }
+        corrected
+    }
+
     pub fn add_player_at_map_xy(&mut self, pos: MapPosition) {
         if let Some(zone_index) = self.map_to_zone_index(pos) {
             self.zones[zone_index.0].num_players += 1;
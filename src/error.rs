@@ -9,6 +9,12 @@ pub enum ZError {
     IOError(String),
     WebSocketError(WsError),
     JsonError(String),
+    // An attempt to change state that only the server may change.
+    ReadOnly(String),
+    // A byte in world data that isn't a known cell type.
+    InvalidCell(u8),
+    #[cfg(feature = "sqlite")]
+    DatabaseError(String),
 }
 
 impl fmt::Display for ZError {
@@ -23,8 +29,21 @@ impl From<serde_json::error::Error> for ZError {
     }
 }
 
+impl From<std::io::Error> for ZError {
+    fn from(e: std::io::Error) -> Self {
+        ZError::IOError(e.to_string())
+    }
+}
+
 impl From<WsError> for ZError {
     fn from(e: WsError) -> Self {
         ZError::WebSocketError(e)
     }
 }
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for ZError {
+    fn from(e: rusqlite::Error) -> Self {
+        ZError::DatabaseError(e.to_string())
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{
+    engine::{bomb::BombId, mob::MobId, player::PlayerId, position::MapPosition},
+    error::ZResult,
+    utils::misc::Timestamp,
+};
+use log::*;
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    sync::mpsc::{channel, Sender},
+    thread::JoinHandle,
+};
+
+/// What killed a player.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Weapon {
+    Bomb,
+    Mob,
+    SmartMob,
+    MobSpawner,
+    SuddenDeath,
+}
+
+/// A gameplay event, for analytics.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum GameEvent {
+    PlayerJoined {
+        pid: PlayerId,
+        name: String,
+    },
+    PlayerLeft {
+        pid: PlayerId,
+        name: String,
+    },
+    /// The killer is only set for another player's bomb.
+    PlayerKilled {
+        pid: PlayerId,
+        killer: Option<PlayerId>,
+        weapon: Weapon,
+    },
+    MobKilled {
+        mob: MobId,
+        killer: PlayerId,
+    },
+    PowerUpCollected {
+        pid: PlayerId,
+        powerup: String,
+    },
+    BombPlaced {
+        bomb: BombId,
+        pid: PlayerId,
+        position: MapPosition,
+    },
+    BombExploded {
+        bomb: BombId,
+        pid: PlayerId,
+        position: MapPosition,
+    },
+}
+
+/// A game event along with the simulation time it happened at.
+#[derive(Debug, Clone, Serialize)]
+pub struct TimedEvent {
+    pub time: Timestamp,
+    #[serde(flatten)]
+    pub event: GameEvent,
+}
+
+/// Somewhere to send game events.
+/// Emitting an event must not block the game loop.
+pub trait EventSink: Send {
+    fn emit(&mut self, event: &TimedEvent);
+}
+
+/// Writes each event as a line of JSON. Writing happens on a separate thread.
+pub struct JsonLinesSink {
+    sender: Option<Sender<String>>,
+    writer_thread: Option<JoinHandle<()>>,
+}
+
+impl JsonLinesSink {
+    pub fn new(mut writer: Box<dyn Write + Send>) -> Self {
+        let (sender, receiver) = channel::<String>();
+        let writer_thread = std::thread::spawn(move || {
+            for line in receiver {
+                if let Err(e) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+                    error!("Failed to write game event: {:?}", e);
+                }
+            }
+        });
+
+        JsonLinesSink {
+            sender: Some(sender),
+            writer_thread: Some(writer_thread),
+        }
+    }
+
+    /// Append events to the specified file, or write them to stdout if the
+    /// path is "-".
+    pub fn open(path: &str) -> ZResult<Self> {
+        let writer: Box<dyn Write + Send> = if path == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        Ok(JsonLinesSink::new(writer))
+    }
+}
+
+impl EventSink for JsonLinesSink {
+    fn emit(&mut self, event: &TimedEvent) {
+        match serde_json::to_string(event) {
+            Ok(line) => {
+                if let Some(sender) = &self.sender {
+                    // This only fails if the writer thread has gone.
+                    sender.send(line).ok();
+                }
+            }
+            Err(e) => error!("Failed to serialize game event {:?}: {:?}", event, e),
+        }
+    }
+}
+
+impl Drop for JsonLinesSink {
+    fn drop(&mut self) {
+        // Closing the channel stops the writer once it has written everything.
+        self.sender = None;
+        if let Some(writer_thread) = self.writer_thread.take() {
+            writer_thread.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let buffer = SharedBuffer::default();
+        let mut sink = JsonLinesSink::new(Box::new(buffer.clone()));
+        sink.emit(&TimedEvent {
+            time: Timestamp::zero(),
+            event: GameEvent::PlayerKilled {
+                pid: PlayerId::from(2),
+                killer: Some(PlayerId::from(1)),
+                weapon: Weapon::Bomb,
+            },
+        });
+        sink.emit(&TimedEvent {
+            time: Timestamp::zero(),
+            event: GameEvent::PowerUpCollected {
+                pid: PlayerId::from(1),
+                powerup: String::from("+B"),
+            },
+        });
+        drop(sink);
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, vec![
+            r#"{"time":0,"event":"playerKilled","pid":2,"killer":1,"weapon":"bomb"}"#,
+            r#"{"time":0,"event":"powerUpCollected","pid":1,"powerup":"+B"}"#,
+        ]);
+    }
+}
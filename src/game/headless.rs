@@ -0,0 +1,100 @@
+use crate::component::action::Action;
+use rand::Rng;
+use std::time::Duration;
+
+/// Timing results from a headless run of the game.
+#[derive(Debug, Clone, Default)]
+pub struct HeadlessStats {
+    ticks: u64,
+    total: Duration,
+    slowest: Duration,
+    // The number of bots that died and had to be replaced.
+    respawns: u64,
+}
+
+impl HeadlessStats {
+    pub fn new() -> Self {
+        HeadlessStats::default()
+    }
+
+    pub fn add_tick(&mut self, elapsed: Duration) {
+        self.ticks += 1;
+        self.total += elapsed;
+        self.slowest = self.slowest.max(elapsed);
+    }
+
+    pub fn add_respawn(&mut self) {
+        self.respawns += 1;
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn slowest(&self) -> Duration {
+        self.slowest
+    }
+
+    pub fn respawns(&self) -> u64 {
+        self.respawns
+    }
+
+    pub fn average(&self) -> Duration {
+        if self.ticks == 0 {
+            Duration::from_secs(0)
+        } else {
+            self.total / self.ticks as u32
+        }
+    }
+
+    /// e.g. "1000 ticks in 412.00ms (avg 0.41ms, max 3.20ms), 12 respawns".
+    pub fn summary(&self) -> String {
+        format!(
+            "{} ticks in {:.2}ms (avg {:.2}ms, max {:.2}ms), {} respawns",
+            self.ticks,
+            self.total.as_secs_f64() * 1000.0,
+            self.average().as_secs_f64() * 1000.0,
+            self.slowest.as_secs_f64() * 1000.0,
+            self.respawns
+        )
+    }
+}
+
+/// Update a bot's action the way a restless player might: keep going in the
+/// same direction for a while, occasionally turning or dropping a bomb.
+pub fn update_bot_action<R: Rng>(action: &mut Action, delta_time: f64, rng: &mut R) {
+    let turn = action.is_empty() || rng.gen_range(0, 20) == 0;
+    let (x, y) = if turn {
+        match rng.gen_range(0, 4) {
+            0 => (0, -1),
+            1 => (1, 0),
+            2 => (0, 1),
+            _ => (-1, 0),
+        }
+    } else {
+        (action.x(), action.y())
+    };
+    let fire = rng.gen_range(0, 60) == 0;
+    action.set(x, y, fire);
+    action.set_dt(delta_time);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_average() {
+        let mut stats = HeadlessStats::new();
+        assert_eq!(stats.average(), Duration::from_secs(0));
+        stats.add_tick(Duration::from_millis(1));
+        stats.add_tick(Duration::from_millis(5));
+        assert_eq!(stats.ticks(), 2);
+        assert_eq!(stats.average(), Duration::from_millis(3));
+        assert_eq!(stats.slowest(), Duration::from_millis(5));
+    }
+}
@@ -1,79 +1,320 @@
 use crate::{
-    comms::playercomm::{PlayerConnectEvent, PlayerMessage},
+    comms::{
+        admin::AdminCommand,
+        metrics::Metrics,
+        playercomm::{
+            LocalClient,
+            MatchScore,
+            PlayerComm,
+            PlayerConnectEvent,
+            PlayerMessage,
+            PlayerReceiver,
+        },
+    },
+    component::effect::{Effect, EffectType},
     engine::{
-        bomb::Bomb,
+        bomb::{Bomb, BombTime},
         config::GameConfig,
         explosion::Explosion,
-        mob::Mob,
+        mob::{Mob, MobId},
+        pathfinder::PathFinder,
         player::{Player, PlayerFlags, PlayerId},
-        position::{MapPosition, PixelPositionF64},
+        position::{MapPosition, PixelPositionF64, SizeInTiles},
         types::{BombList, ExplosionList, MobList, PlayerList},
         world::World,
-        worlddata::{InternalCellData, MobSpawner},
+        worlddata::{InternalCellData, MobSpawner, WorldChunk},
     },
     error::ZResult,
-    traits::celltypes::CellType,
+    game::{
+        events::{EventSink, GameEvent, JsonLinesSink, TimedEvent, Weapon},
+        headless::{update_bot_action, HeadlessStats},
+        matchstate::{MatchRound, MatchState},
+        phasetimings::PhaseTimings,
+        snapshot::{BombSnapshot, ExplosionSnapshot, GameSnapshot, MobSnapshot, PlayerSnapshot},
+        stats::{open_stats_store, StatsStore},
+        suddendeath::{SafeBounds, SuddenDeath},
+        timestep::FixedTimestep,
+    },
+    traits::celltypes::{CanPass, CellType},
+    utils::misc::GameClock,
 };
 use futures::future::join_all;
 use log::*;
 use rand::{seq::SliceRandom, thread_rng, Rng};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use tokio::{
-    sync::mpsc::Receiver,
+    sync::mpsc::{channel, error::TryRecvError, Receiver},
     time::{Duration, Instant},
 };
 
+/// Longer announcements are truncated.
+const MAX_ANNOUNCEMENT_LENGTH: usize = 200;
+
+/// How long a player caught at the edge of a blast is slowed down for.
+const INJURY_SECONDS: f64 = 2.0;
+
+/// The result of a player trying to place a bomb.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BombPlacement {
+    Placed,
+    // Out of bombs, cooling down or not on an empty tile.
+    NotReady,
+    // The player would have no way off the tile.
+    NoExit,
+}
+
+#[derive(Serialize)]
+struct SerFrameData<'a> {
+    tick: u64,
+    #[serde(rename = "inputId")]
+    input_id: u32,
+    player: &'a Player,
+    world: Option<&'a RawValue>,
+    players: Vec<&'a Player>,
+    mobs: Vec<&'a Mob>,
+    bombs: Vec<&'a Bomb>,
+    explosions: Vec<&'a Explosion>,
+    #[serde(rename = "safeBounds", skip_serializing_if = "Option::is_none")]
+    safe_bounds: Option<SafeBounds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    danger: Option<Vec<(MapPosition, f64)>>,
+}
+
+fn bomb_exploded_event(bomb: &Bomb) -> GameEvent {
+    GameEvent::BombExploded {
+        bomb: bomb.id(),
+        pid: bomb.pid(),
+        position: bomb.position(),
+    }
+}
+
 pub struct RustonatorGame {
-    width: u32,
-    height: u32,
+    config: GameConfig,
     world: World,
     players: PlayerList,
     mobs: MobList,
     mob_spawners: Vec<MobSpawner>,
     bombs: BombList,
     explosions: ExplosionList,
+    pathfinder: PathFinder,
+    clock: GameClock,
+    // Serialized world chunks for the current frame, keyed by chunk top left and size.
+    chunk_cache: HashMap<(MapPosition, SizeInTiles), Box<RawValue>>,
+    chunk_cache_version: u64,
+    chunk_buffer: WorldChunk,
+    stats: Option<Box<dyn StatsStore>>,
+    metrics: Arc<Metrics>,
+    timings: PhaseTimings,
+    events: Option<Box<dyn EventSink>>,
+    max_mobs: usize,
+    // Names that are not allowed to join.
+    banned: HashSet<String>,
+    sudden_death: Option<SuddenDeath>,
+    // Players caught by sudden death, who will die when next processed.
+    crushed: HashSet<PlayerId>,
+    match_round: Option<MatchRound>,
+    // Sound effect messages to send to nearby players at the end of the frame.
+    sound_events: Vec<(MapPosition, PlayerMessage)>,
+    // The number of simulation steps run so far.
+    tick: u64,
 }
 
 impl RustonatorGame {
     pub fn new(width: u32, height: u32) -> Self {
-        let config = GameConfig::new();
+        Self::with_config(width, height, GameConfig::new())
+    }
+
+    pub fn with_config(width: u32, height: u32, config: GameConfig) -> Self {
         let mut world = World::new(width as i32, height as i32, &config);
         let mob_spawners = world.add_mob_spawners();
         world.populate_initial(&[]);
+        let stats = config
+            .stats_file()
+            .and_then(|path| match open_stats_store(path) {
+                Ok(store) => Some(store),
+                Err(e) => {
+                    error!("Failed to load player stats from {}: {:?}", path, e);
+                    None
+                }
+            });
+        let events = config
+            .event_log()
+            .and_then(|path| match JsonLinesSink::open(path) {
+                Ok(sink) => Some(Box::new(sink) as Box<dyn EventSink>),
+                Err(e) => {
+                    error!("Failed to open event log {}: {:?}", path, e);
+                    None
+                }
+            });
+
+        let sudden_death = config.sudden_death_after().map(|after| {
+            SuddenDeath::new(
+                after,
+                config.sudden_death_interval(),
+                *world.sizes().map_size(),
+            )
+        });
+        let match_round = if config.match_rounds() {
+            Some(MatchRound::new(&config))
+        } else {
+            None
+        };
 
         Self {
-            width,
-            height,
+            config,
             world,
             players: PlayerList::new(),
             mobs: MobList::new(),
             mob_spawners,
-            bombs: BombList::new(),
-            explosions: ExplosionList::new(),
+            bombs: BombList::with_capacity(100),
+            explosions: ExplosionList::with_capacity(500),
+            pathfinder: PathFinder::new(),
+            clock: GameClock::new(),
+            chunk_cache: HashMap::new(),
+            chunk_cache_version: 0,
+            chunk_buffer: WorldChunk::new(0, 0, 0, 0),
+            stats,
+            metrics: Arc::new(Metrics::new()),
+            timings: PhaseTimings::new(),
+            events,
+            max_mobs: (width as f64 * height as f64 * 0.4) as usize,
+            banned: HashSet::new(),
+            sudden_death,
+            crushed: HashSet::new(),
+            match_round,
+            sound_events: Vec::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn config(&self) -> &GameConfig {
+        &self.config
+    }
+
+    /// Metrics for this game, updated every frame.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// The current phase of the match, if matches are played in rounds.
+    pub fn match_state(&self) -> Option<MatchState> {
+        self.match_round.as_ref().map(|round| round.state())
+    }
+
+    /// Send an event to the event log, if enabled.
+    fn emit(&mut self, event: GameEvent) {
+        if let Some(events) = &mut self.events {
+            events.emit(&TimedEvent {
+                time: self.clock.now(),
+                event,
+            });
+        }
+    }
+
+    /// A copy of the current state of the game, for spectators.
+    pub fn snapshot(&self) -> GameSnapshot {
+        self.build_snapshot(false)
+    }
+
+    /// Same as snapshot(), but also includes the internal state of each mob's
+    /// AI. This is for admin and debugging tools only.
+    pub fn debug_snapshot(&self) -> GameSnapshot {
+        self.build_snapshot(true)
+    }
+
+    fn build_snapshot(&self, debug: bool) -> GameSnapshot {
+        GameSnapshot {
+            players: self
+                .players
+                .values()
+                .map(|p| PlayerSnapshot {
+                    id: p.id(),
+                    name: p.name().to_string(),
+                    score: p.score(),
+                    position: p.position(),
+                })
+                .collect(),
+            mobs: self
+                .mobs
+                .iter()
+                .map(|m| MobSnapshot {
+                    id: m.id(),
+                    position: m.position(),
+                    debug: if debug { Some(m.debug_state()) } else { None },
+                })
+                .collect(),
+            bombs: self
+                .bombs
+                .iter()
+                .map(|b| BombSnapshot {
+                    id: b.id(),
+                    position: b.position(),
+                })
+                .collect(),
+            explosions: self
+                .explosions
+                .iter()
+                .map(|e| ExplosionSnapshot {
+                    id: e.id(),
+                    position: e.position(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Add the time since start to the specified phase, if phase timings are
+    /// enabled.
+    fn record_phase(&mut self, phase: &'static str, start: Instant) {
+        if self.config.phase_timings() {
+            self.timings.add(phase, start.elapsed());
         }
     }
 
     pub async fn game_loop(
         &mut self,
         mut player_join_rx: Receiver<PlayerConnectEvent>,
+        mut admin_rx: Receiver<AdminCommand>,
     ) -> ZResult<()>
     {
-        let max_mobs = (self.width as f64 * self.height as f64 * 0.4) as usize;
-
         // Limit max FPS.
         let fps = 30.0;
         let min_timeslice: f64 = 1.0 / fps;
+        // The simulation always advances in steps of this size.
+        let mut timestep = FixedTimestep::new(Duration::from_secs_f64(min_timeslice));
+        let step_time = timestep.step().as_secs_f64();
 
         let mut last_frame = Instant::now();
+        let mut frame_times: Vec<f64> = Vec::new();
         let mut count: u64 = 0;
         let mut first_frame = Instant::now();
 
         let mut add_blocks_timer = Instant::now();
         let mut mob_spawn_timer = Instant::now();
 
-        let mut next_mob_spawn_seconds = thread_rng().gen_range(1.0, 60.0);
+        let mut next_mob_spawn_seconds = self.mob_spawn_interval(thread_rng().gen_range(1.0, 60.0));
 
         loop {
+            if self.is_idle() {
+                // Nobody is playing, so just wait for someone to connect.
+                let idle_interval = Duration::from_secs_f64(self.config.idle_interval());
+                tokio::time::delay_for(idle_interval).await;
+                if !self.player_connect_events(&mut player_join_rx).await {
+                    return Ok(());
+                }
+                while let Ok(command) = admin_rx.try_recv() {
+                    self.handle_admin_command(command).await;
+                }
+                last_frame = Instant::now();
+                continue;
+            }
+
             let mut delta_time = last_frame.elapsed().as_secs_f64();
             if delta_time < min_timeslice {
                 // Only allow new players if we have time.
@@ -92,70 +333,309 @@ impl RustonatorGame {
             }
             last_frame = Instant::now();
 
-            self.player_connect_events(&mut player_join_rx).await;
-            self.process_player_inputs(delta_time).await;
-            self.game_process_explosions_and_bombs(delta_time);
-            self.game_process_mobs(delta_time);
-            self.game_process_players(delta_time).await;
+            let delta_time = self.config.clamp_delta_time(delta_time);
+            let steps = timestep.advance(Duration::from_secs_f64(delta_time));
+
+            let phase_start = Instant::now();
+            if !self.player_connect_events(&mut player_join_rx).await {
+                return Ok(());
+            }
+            while let Ok(command) = admin_rx.try_recv() {
+                self.handle_admin_command(command).await;
+            }
+            self.record_phase("connect", phase_start);
+            if steps > 0 {
+                // Inputs are held for every step run this frame. If there are
+                // no steps they stay queued until the next frame.
+                let phase_start = Instant::now();
+                self.process_player_inputs(step_time).await;
+                self.record_phase("inputs", phase_start);
+            }
+
+            for step in 1..=steps {
+                // Only send frame data after the last step.
+                self.game_step(step_time, step == steps).await;
+            }
 
             // Spawn new mob ?
             if mob_spawn_timer.elapsed().as_secs_f64() > next_mob_spawn_seconds {
-                if self.mobs.len() < max_mobs {
+                if self.mobs.len() < self.max_mobs {
                     self.spawn_mob();
                 }
 
                 mob_spawn_timer = Instant::now();
-                next_mob_spawn_seconds = thread_rng().gen_range(1.0, 60.0);
+                next_mob_spawn_seconds = self.mob_spawn_interval(thread_rng().gen_range(1.0, 60.0));
             }
 
             // Add blocks?
             if add_blocks_timer.elapsed().as_secs() > 10 {
+                let phase_start = Instant::now();
                 let entities: Vec<MapPosition> = self
                     .players
                     .values()
-                    .map(|p| p.position().to_map_position(&self.world))
+                    .map(|p| p.map_position(&self.world))
                     .chain(
                         self.mobs
                             .iter()
                             .map(|m| m.position().to_map_position(&self.world)),
                     )
                     .collect();
-                self.world.populate_blocks(&entities);
+                self.world
+                    .populate_blocks(&entities, self.config.max_blocks_per_populate());
                 add_blocks_timer = Instant::now();
+                self.record_phase("blocks", phase_start);
             }
 
             count += 1;
+            self.timings.end_frame();
+            frame_times.push(last_frame.elapsed().as_secs_f64());
+            self.metrics.set_counts(
+                self.players.len(),
+                self.mobs.len(),
+                self.bombs.len(),
+                self.explosions.len(),
+            );
 
             let elapsed = first_frame.elapsed().as_secs_f64();
             if elapsed > 5.0 {
                 info!("FPS: {:.2}", count as f64 / elapsed);
+                if self.config.phase_timings() {
+                    debug!("Frame timings: {}", self.timings.summary());
+                }
+                self.timings.clear();
+                self.metrics.set_fps(count as f64 / elapsed);
+                self.metrics.set_frame_times(&mut frame_times);
+                frame_times.clear();
                 count = 0;
                 first_frame = Instant::now();
+
+                if let Some(stats) = &mut self.stats {
+                    if let Err(e) = stats.flush() {
+                        error!("Failed to save player stats: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The number of simulation steps run so far. This is sent with each
+    /// frame so that clients can match it against their own predictions.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// The game is idle while nobody is connected. Nothing is simulated.
+    pub fn is_idle(&self) -> bool {
+        self.players.is_empty()
+    }
+
+    /// Advance the simulation by a single step.
+    pub async fn game_step(&mut self, step_time: f64, send_frames: bool) {
+        if self.is_idle() {
+            return;
+        }
+
+        self.tick += 1;
+        self.clock.advance(step_time);
+        self.world.set_clock(&self.clock);
+        let phase_start = Instant::now();
+        self.game_process_explosions_and_bombs(step_time);
+        self.record_phase("bombs", phase_start);
+        self.game_process_sudden_death();
+        self.game_process_match().await;
+        let phase_start = Instant::now();
+        self.game_process_mobs(step_time);
+        self.record_phase("mobs", phase_start);
+        // NOTE: This includes the time recorded for "net".
+        let phase_start = Instant::now();
+        self.game_process_players(step_time, send_frames).await;
+        self.record_phase("players", phase_start);
+    }
+
+    /// Run the simulation for a fixed number of ticks, with bots in place of
+    /// real players, as fast as possible. Bots that die are replaced so that
+    /// the load stays the same throughout. Useful for benchmarking.
+    pub async fn run_headless(&mut self, num_bots: usize, ticks: u64) -> HeadlessStats {
+        let step_time = 1.0 / 30.0;
+        let mut stats = HeadlessStats::new();
+        let mut rng = thread_rng();
+        // Nothing reads what is sent to the bots, but the channels need to
+        // stay open and not fill up.
+        let mut receivers: HashMap<PlayerId, PlayerReceiver> = HashMap::new();
+        let mut next_id = 1;
+
+        for tick in 0..ticks {
+            while self.players.len() < num_bots {
+                let n = next_id;
+                next_id += 1;
+                let id = PlayerId::from(n);
+                let (sender, sent) = channel(10);
+                let (_, receiver) = channel(1);
+                let mut bot = Player::new(id, PlayerComm::new(id, sender, receiver));
+                bot.set_name(&format!("bot{}", n));
+                if !bot.reset_for_round(&self.world, self.config.effects()) {
+                    break;
+                }
+                if tick > 0 {
+                    stats.add_respawn();
+                }
+                self.players.insert(id, bot);
+                receivers.insert(id, sent);
+            }
+
+            for bot in self.players.values_mut().filter(|p| p.is_active()) {
+                update_bot_action(bot.action_mut(), step_time, &mut rng);
+            }
+
+            let tick_start = Instant::now();
+            self.game_step(step_time, true).await;
+            if tick % 30 == 0 && self.mobs.len() < self.max_mobs {
+                self.spawn_mob();
+            }
+            stats.add_tick(tick_start.elapsed());
+
+            let players = &self.players;
+            receivers.retain(|id, _| players.contains_key(id));
+            for receiver in receivers.values_mut() {
+                while receiver.try_recv().is_ok() {}
             }
         }
+
+        stats
     }
 
-    pub async fn player_connect_events(&mut self, players_rx: &mut Receiver<PlayerConnectEvent>) {
+    fn add_player_comm(&mut self, mut comm: PlayerComm) {
+        comm.set_powerup_details(self.config.powerup_details());
+        self.players.insert(comm.id(), Player::new(comm.id(), comm));
+    }
+
+    /// Add a player that is controlled from code rather than over a
+    /// websocket, e.g. for a practice game. They still need to join.
+    pub fn add_local_player(&mut self, id: PlayerId) -> LocalClient {
+        let (comm, client) = PlayerComm::local(id);
+        self.add_player_comm(comm);
+        client
+    }
+
+    /// Returns false once nothing can send any more events, meaning the
+    /// game should stop.
+    pub async fn player_connect_events(
+        &mut self,
+        players_rx: &mut Receiver<PlayerConnectEvent>,
+    ) -> bool
+    {
         // Have any players joined?
-        if let Ok(x) = players_rx.try_recv() {
-            match x {
+        match players_rx.try_recv() {
+            Err(TryRecvError::Closed) => return false,
+            Err(TryRecvError::Empty) => {}
+            Ok(x) => match x {
                 PlayerConnectEvent::Connected(p) => {
                     info!("Player connected: {:?}", p);
-                    self.players.insert(p.id(), Player::new(p.id(), p));
+                    self.add_player_comm(p);
                 }
                 PlayerConnectEvent::Disconnected(pid) => {
                     info!("Player {:?} disconnected", pid);
-                    self.players.retain(|player_id, _| player_id != &pid);
+                    if let Some(p) = self.players.remove(&pid) {
+                        if p.has_joined() {
+                            if let Some(stats) = &mut self.stats {
+                                stats.record_game(p.name(), p.score());
+                            }
+                            self.emit(GameEvent::PlayerLeft {
+                                pid,
+                                name: p.name().to_string(),
+                            });
+                        }
+                    }
+                }
+            },
+        }
+        true
+    }
+
+    pub async fn handle_admin_command(&mut self, command: AdminCommand) {
+        match command {
+            AdminCommand::Kick(pid) => self.kick_player(pid).await,
+            AdminCommand::Ban(name) => {
+                let pids: Vec<PlayerId> = self
+                    .players
+                    .values()
+                    .filter(|p| p.name() == name)
+                    .map(|p| p.id())
+                    .collect();
+                for pid in pids {
+                    self.kick_player(pid).await;
                 }
+                self.banned.insert(name);
+            }
+            AdminCommand::SpawnMob => self.spawn_mob(),
+            AdminCommand::SetMaxMobs(max_mobs) => self.max_mobs = max_mobs,
+            AdminCommand::NewMap(width, height) => self.regenerate_world(width, height).await,
+            AdminCommand::Broadcast(text) => self.broadcast(&text).await,
+        }
+    }
+
+    /// Send an announcement to every connected player.
+    pub async fn broadcast(&mut self, text: &str) {
+        let text: String = text
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_ANNOUNCEMENT_LENGTH)
+            .collect();
+        info!("Announcement: {}", text);
+        let mut futs = Vec::new();
+        for p in self.players.values_mut() {
+            let message = PlayerMessage::Announcement(text.clone());
+            futs.push(Box::pin(p.ws().send(message)));
+        }
+        for result in join_all(futs).await {
+            if let Err(e) = result {
+                error!("Error sending announcement: {:?}", e);
+            }
+        }
+    }
+
+    /// Remove the player from the game and disconnect them.
+    async fn kick_player(&mut self, pid: PlayerId) {
+        if let Some(mut p) = self.players.remove(&pid) {
+            info!("Kicking player {:?} ({})", pid, p.name());
+            p.terminate();
+            if let Err(e) = p.ws().disconnect().await {
+                error!("Error disconnecting player {:?}: {:?}", pid, e);
+            }
+            if p.has_joined() {
+                if let Some(stats) = &mut self.stats {
+                    stats.record_game(p.name(), p.score());
+                }
+                self.emit(GameEvent::PlayerLeft {
+                    pid,
+                    name: p.name().to_string(),
+                });
             }
         }
     }
 
     pub async fn process_player_inputs(&mut self, delta_time: f64) {
         let mut quit = Vec::new();
+        let mut joined = Vec::new();
         for p in self.players.values_mut() {
-            if let Ok(false) | Err(_) = p.handle_player_input(&mut self.world, delta_time).await {
+            let had_joined = p.has_joined();
+            let result = p
+                .handle_player_input(&mut self.world, delta_time, &self.config)
+                .await;
+            if let Ok(false) | Err(_) = result {
                 quit.push(p.id());
+            } else if !had_joined && p.has_joined() {
+                joined.push((p.id(), p.name().to_string()));
+            }
+        }
+
+        for (pid, name) in joined {
+            let banned = self.banned.contains(&name);
+            self.emit(GameEvent::PlayerJoined { pid, name });
+            if banned {
+                self.kick_player(pid).await;
+            } else if self.config.cell_legend() {
+                self.send_cell_legend(pid).await;
             }
         }
 
@@ -164,50 +644,157 @@ impl RustonatorGame {
         }
     }
 
-    pub fn game_process_explosions_and_bombs(&mut self, delta_time: f64) {
-        // Update remaining time for all bombs and explosions.
-        for explosion in self.explosions.iter_mut() {
-            explosion.update(delta_time);
-            if !explosion.is_active() {
-                self.world.clear_explosion_cell(explosion);
+    async fn send_cell_legend(&mut self, pid: PlayerId) {
+        let legend = CellType::legend()
+            .into_iter()
+            .map(|(code, name)| (code, name.to_string()))
+            .collect();
+        if let Some(p) = self.players.get_mut(&pid) {
+            if let Err(e) = p.ws().send(PlayerMessage::CellLegend(legend)).await {
+                error!("Error sending cell legend to player {:?}: {:?}", pid, e);
             }
         }
+    }
 
-        self.explosions.retain(|_, e| e.is_active());
-
-        let mut explode_new = Vec::new();
-        for bomb in self.bombs.iter_mut() {
-            if bomb.tick(delta_time) {
-                // Bomb exploded.
-                explode_new.push(bomb.id());
+    pub fn game_process_explosions_and_bombs(&mut self, delta_time: f64) {
+        // Update remaining time for all bombs and explosions.
+        let world = &mut self.world;
+        let now = self.clock.now();
+        self.explosions.retain(|_, explosion| {
+            explosion.update(now);
+            if !explosion.is_active() {
+                world.clear_explosion_cell(explosion);
             }
-        }
+            explosion.is_active()
+        });
 
-        for bomb_id in explode_new.into_iter() {
+        // Remove exploding bombs in the same pass that ticks them.
+        let exploded = self.bombs.retain_and_collect(|_, b| !b.tick(delta_time));
+        let mut events: Vec<GameEvent> = exploded.iter().map(bomb_exploded_event).collect();
+        for bomb in exploded.into_iter() {
             self.world.explode_bomb(
-                bomb_id,
+                bomb,
                 &mut self.bombs,
                 &mut self.explosions,
                 &mut self.players,
+                self.config.scores(),
             );
         }
 
-        self.bombs.retain(|_, b| b.is_active());
+        // Remove any bombs caught in the chain reaction.
+        let chained = self.bombs.retain_and_collect(|_, b| b.is_active());
+        events.extend(chained.iter().map(bomb_exploded_event));
+        for event in events {
+            self.emit(event);
+        }
     }
 
-    pub fn create_bomb_for_player(&mut self, player: &mut Player) {
-        if !player.has_bomb_remaining() {
-            return;
+    pub fn create_bomb_for_player(&mut self, player: &mut Player) -> BombPlacement {
+        let now = self.clock.now();
+        if !player.has_bomb_remaining()
+            || player.is_bomb_cooling_down(now, self.config.bomb_cooldown())
+        {
+            return BombPlacement::NotReady;
         }
 
-        let pos = player.position().to_map_position(&self.world);
+        let pos = player.map_position(&self.world);
         if let Some(CellType::Empty) = self.world.get_cell(pos) {
-            let bomb = Bomb::new(player, pos);
-            player.bomb_placed();
-            self.world.add_bomb(bomb, &mut self.bombs);
+            if self.config.require_bomb_exit() && !self.has_exit(player, pos) {
+                return BombPlacement::NoExit;
+            }
+
+            let mut bomb = Bomb::new(player, pos, &self.clock);
+            bomb.roll_dud(self.config.dud_chance());
+            if player.action().short_fuse() && player.use_short_fuse_charge() {
+                bomb.set_fuse(BombTime::MIN, &self.clock);
+            }
+            if self.config.bomb_exit_grace() {
+                // The player being processed isn't in the list.
+                bomb.add_overlapping(player.id());
+                for p in self.players.values().filter(|p| p.is_active()) {
+                    if p.overlaps_tile(pos, &self.world) {
+                        bomb.add_overlapping(p.id());
+                    }
+                }
+            }
+            player.bomb_placed(now);
+            let id = self.world.add_bomb(bomb, &mut self.bombs);
+            self.add_sound_event(pos, PlayerMessage::BombPlaced(pos));
+            self.emit(GameEvent::BombPlaced {
+                bomb: id,
+                pid: player.id(),
+                position: pos,
+            });
+            BombPlacement::Placed
+        } else {
+            BombPlacement::NotReady
+        }
+    }
+
+    /// True if the player could step off the specified tile.
+    fn has_exit(&self, player: &Player, pos: MapPosition) -> bool {
+        [pos.up(1), pos.right(1), pos.down(1), pos.left(1)]
+            .iter()
+            .any(|p| player.can_pass(*p, &self.world))
+    }
+
+    fn add_sound_event(&mut self, pos: MapPosition, message: PlayerMessage) {
+        if self.config.sound_events() {
+            self.sound_events.push((pos, message));
         }
     }
 
+    /// Send the sound effect messages from this frame to every player who
+    /// can see where they happened.
+    async fn send_sound_events(&mut self) {
+        if self.sound_events.is_empty() {
+            return;
+        }
+
+        let events = std::mem::take(&mut self.sound_events);
+        for p in self.players.values_mut().filter(|p| p.is_active()) {
+            let map_pos = p.map_position(&self.world);
+            let view = self
+                .world
+                .view_size(p.view_size(), self.config.max_view_size());
+            for (pos, message) in events.iter() {
+                if !pos.is_within_grid(map_pos, view.width, view.height) {
+                    continue;
+                }
+                if let Err(e) = p.ws().send(message.clone()).await {
+                    error!("Error sending sound event to {:?}: {:?}", p.id(), e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Get the mob closest to the specified position, and its distance in
+    /// tiles.
+    pub fn nearest_mob_to(&self, pos: MapPosition) -> Option<(MobId, u32)> {
+        self.mobs
+            .iter()
+            .map(|m| {
+                (
+                    m.id(),
+                    m.position().to_map_position(&self.world).distance_to(pos),
+                )
+            })
+            .min_by_key(|(_, distance)| *distance)
+    }
+
+    /// Get the number of seconds until the next mob spawn, given the usual
+    /// interval. If spawns are scaled, this is shared between the players.
+    pub fn mob_spawn_interval(&self, base: f64) -> f64 {
+        if !self.config.scale_mob_spawns() {
+            return base;
+        }
+
+        let active = self.players.values().filter(|p| p.is_active()).count();
+        let min_interval = self.config.min_mob_spawn_interval().min(base);
+        (base / active.max(1) as f64).max(min_interval)
+    }
+
     /// Spawn mob at a random mob spawner, and assign it a new target.
     pub fn spawn_mob(&mut self) {
         let mob_positions: Vec<MapPosition> = self
@@ -215,28 +802,239 @@ impl RustonatorGame {
             .iter()
             .map(|m| m.position().to_map_position(&self.world))
             .collect();
-        let mut spawners = self.mob_spawners.clone();
-        spawners.shuffle(&mut rand::thread_rng());
-        for spawner in spawners {
-            if !self
-                .world
-                .is_nearby_map_entity(spawner.position(), &mob_positions, 3)
-            {
-                let mut mob = Mob::new();
-                mob.set_position(PixelPositionF64::from_map_position(
-                    spawner.position(),
-                    &self.world,
-                ));
-                mob.choose_new_target(&self.world, &self.players);
-                self.mobs.add(mob);
-                break;
+        // Don't spawn a mob where it could kill someone straight away.
+        let player_positions: Vec<MapPosition> = self
+            .players
+            .values()
+            .filter(|p| p.is_active())
+            .map(|p| p.map_position(&self.world))
+            .collect();
+        // Visit the spawners in random order without copying the list.
+        let spawner = self
+            .mob_spawners
+            .choose_multiple(&mut thread_rng(), self.mob_spawners.len())
+            .map(|s| s.position())
+            // Spawners may have been walled in by sudden death.
+            .filter(|pos| self.world.get_cell(*pos) == Some(CellType::MobSpawner))
+            .find(|pos| {
+                !self.world.is_nearby_map_entity(*pos, &mob_positions, 3)
+                    && !self.world.is_nearby_map_entity(*pos, &player_positions, 3)
+            });
+        if let Some(pos) = spawner {
+            let mut mob = Mob::new();
+            mob.set_position(PixelPositionF64::from_map_position(pos, &self.world));
+            mob.choose_new_target(&self.world, &self.players, &mut self.pathfinder);
+            self.mobs.add(mob);
+        }
+    }
+
+    /// Wall in the next ring of the arena if it is due. Anything caught there
+    /// is crushed.
+    pub fn game_process_sudden_death(&mut self) {
+        let elapsed = self.clock.elapsed();
+        let positions = match self.sudden_death.as_mut() {
+            Some(sudden_death) => match sudden_death.update(elapsed) {
+                Some(ring) => sudden_death.ring_positions(ring),
+                None => return,
+            },
+            None => return,
+        };
+        let ring: HashSet<MapPosition> = positions.iter().copied().collect();
+
+        // Bombs are removed without exploding.
+        for bomb in self
+            .bombs
+            .retain_and_collect(|_, b| !ring.contains(&b.position()))
+        {
+            self.world.clear_internal_cell(bomb.position());
+            if let Some(p) = self.players.get_mut(&bomb.pid()) {
+                p.bomb_exploded();
+            }
+        }
+
+        for pos in positions {
+            self.world.set_cell(pos, CellType::Wall);
+        }
+
+        for p in self.players.values() {
+            let map_pos = p.map_position(&self.world);
+            if p.is_active() && ring.contains(&map_pos) {
+                self.crushed.insert(p.id());
+                self.world
+                    .add_visual_only_explosion(map_pos, &mut self.explosions);
+            }
+        }
+
+        for mob in self.mobs.iter_mut() {
+            let map_pos = mob.position().to_map_position(&self.world);
+            if ring.contains(&map_pos) {
+                mob.terminate();
+                self.world
+                    .add_visual_only_explosion(map_pos, &mut self.explosions);
+            }
+        }
+    }
+
+    /// Move the match on to its next phase if it is due.
+    pub async fn game_process_match(&mut self) {
+        let alive = self.players.values().filter(|p| p.is_active()).count();
+        let elapsed = self.clock.elapsed();
+        let (state, sole_survivor) = match self.match_round.as_mut() {
+            Some(round) => match round.update(elapsed, alive) {
+                Some(state) => (state, round.is_last_player_standing(alive)),
+                None => return,
+            },
+            None => return,
+        };
+
+        match state {
+            MatchState::Warmup => self.reset_round().await,
+            MatchState::Active => {
+                // Anything scored during warmup doesn't count.
+                for p in self.players.values_mut() {
+                    p.reset_score();
+                }
+            }
+            MatchState::Ended => self.end_round(sole_survivor).await,
+        }
+    }
+
+    /// Announce the winner and final scores to every player. The winner is
+    /// the sole survivor, if there is one, otherwise the highest scorer.
+    async fn end_round(&mut self, sole_survivor: bool) {
+        let mut scores: Vec<MatchScore> = self
+            .players
+            .values()
+            .filter(|p| p.has_joined())
+            .map(|p| MatchScore {
+                pid: p.id(),
+                name: p.name().to_string(),
+                score: p.score(),
+            })
+            .collect();
+        scores.sort_by_key(|s| Reverse(s.score));
+
+        let winner = if sole_survivor {
+            self.players
+                .values()
+                .find(|p| p.is_active())
+                .map(|p| p.id())
+        } else {
+            match scores.as_slice() {
+                [first, second, ..] if first.score == second.score => None,
+                [first, ..] => Some(first.pid),
+                [] => None,
+            }
+        };
+        info!("Round ended, winner: {:?}", winner);
+
+        let mut futs = Vec::new();
+        for p in self.players.values_mut().filter(|p| p.has_joined()) {
+            futs.push(Box::pin(p.ws().send(PlayerMessage::MatchEnded {
+                winner,
+                scores: scores.clone(),
+            })));
+        }
+        for result in join_all(futs).await {
+            if let Err(e) = result {
+                error!("Error sending match results: {:?}", e);
+            }
+        }
+    }
+
+    /// Start the next round on a new map. Players stay connected and are
+    /// respawned with their score and powerups reset.
+    async fn reset_round(&mut self) {
+        let size = *self.world.sizes().map_size();
+        self.mob_spawners = self.world.reset(size.width, size.height, &self.config);
+        self.clear_world_entities();
+        self.respawn_players(true).await;
+    }
+
+    /// Replace the world with a new map of the specified size. Connected
+    /// players keep their stats and are moved to new spawn points.
+    pub async fn regenerate_world(&mut self, width: u32, height: u32) {
+        info!("Generating a new {}x{} map", width, height);
+        self.mob_spawners = self
+            .world
+            .reset(width as i32, height as i32, &self.config);
+        self.clear_world_entities();
+        self.respawn_players(false).await;
+    }
+
+    /// Remove everything that belonged to the previous map.
+    fn clear_world_entities(&mut self) {
+        self.mobs.clear();
+        self.bombs.clear();
+        self.explosions.clear();
+        self.crushed.clear();
+        self.chunk_cache.clear();
+        self.sound_events.clear();
+        let elapsed = self.clock.elapsed();
+        if let Some(after) = self.config.sudden_death_after() {
+            self.sudden_death = Some(SuddenDeath::new(
+                elapsed + after,
+                self.config.sudden_death_interval(),
+                *self.world.sizes().map_size(),
+            ));
+        }
+    }
+
+    /// Move every active player to a new spawn point and send them the new
+    /// map. If this is a new round their stats are reset as well.
+    async fn respawn_players(&mut self, new_round: bool) {
+        // Players who are already dying are left to die.
+        for p in self.players.values_mut().filter(|p| p.is_active()) {
+            let spawned = if new_round {
+                p.reset_for_round(&self.world, self.config.effects())
+            } else {
+                p.relocate(&self.world, self.config.effects())
+            };
+            if !spawned {
+                p.terminate();
+                let reason = String::from("There is no room left in the arena");
+                if let Err(e) = p.ws().send(PlayerMessage::Dead(reason)).await {
+                    error!("Error sending death to player {:?}: {:?}", p.id(), e);
+                }
+                continue;
+            }
+            let message = match (p.ser(), self.world.data().ser()) {
+                (Ok(player), Ok(world)) => PlayerMessage::SpawnPlayer(player, world),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("Error serializing new map: {:?}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = p.ws().send(message).await {
+                error!("Error sending new map to player {:?}: {:?}", p.id(), e);
+                p.terminate();
             }
         }
     }
 
     pub fn game_process_mobs(&mut self, delta_time: f64) {
+        // Mobs near any player with the FreezeMobs effect stay where they are.
+        let freezers: Vec<MapPosition> = self
+            .players
+            .values()
+            .filter(|p| !p.is_dead() && p.has_effect(EffectType::FreezeMobs))
+            .map(|p| p.map_position(&self.world))
+            .collect();
+        let radius = self.config.effects().freeze_radius();
+
+        let interpolation = self.config.interpolation();
+        let mut events = Vec::new();
         for mob in self.mobs.iter_mut() {
-            mob.update(delta_time, &self.players, &self.world);
+            if interpolation {
+                mob.record_prev_position();
+            }
+            let map_pos = mob.position().to_map_position(&self.world);
+            let frozen = freezers
+                .iter()
+                .any(|pos| map_pos.is_within_grid(*pos, radius, radius));
+            if !frozen {
+                mob.update(delta_time, &self.players, &self.world, &mut self.pathfinder);
+            }
 
             // Check if mob is dead.
             if let Some(InternalCellData::Explosion(explosion_id)) = self
@@ -250,11 +1048,17 @@ impl RustonatorGame {
                         // Award points to the player that killed this mob.
                         if let Some(p) = self.players.get_mut(&explosion.pid()) {
                             if !p.is_dead() {
-                                if mob.is_smart() {
-                                    p.increase_score(2000);
+                                let scores = self.config.scores();
+                                let points = if mob.is_smart() {
+                                    scores.smart_mob_kill()
                                 } else {
-                                    p.increase_score(500);
-                                }
+                                    scores.mob_kill()
+                                };
+                                p.award_kill(points, self.clock.now(), scores);
+                                events.push(GameEvent::MobKilled {
+                                    mob: mob.id(),
+                                    killer: p.id(),
+                                });
                             }
                         }
                     }
@@ -262,13 +1066,20 @@ impl RustonatorGame {
             }
         }
 
+        for event in events {
+            self.emit(event);
+        }
+
         // Remove dead mobs.
         self.mobs.retain(|_, m| m.is_active());
     }
 
-    pub async fn game_process_players(&mut self, delta_time: f64) {
+    pub async fn game_process_players(&mut self, delta_time: f64, send_frames: bool) {
         // Update players.
         self.world.zones_mut().clear_players();
+        self.chunk_cache.clear();
+        self.world
+            .index_entities(&self.players, &self.mobs, &self.bombs, &self.explosions);
         let player_ids: Vec<PlayerId> = self.players.keys().copied().collect();
         for pid in player_ids {
             // This is why people move to an ECS :(
@@ -280,15 +1091,12 @@ impl RustonatorGame {
                 }
             };
 
-            if player.is_active() && player.action().fire() {
-                self.create_bomb_for_player(&mut player);
-
-                // Prevent more bombs until the player releases fire.
-                player.action_mut().cease_fire();
-            }
-
-            player.update(&self.world, delta_time);
-            if let Err(e) = self.process_player_move(&mut player).await {
+            // Whatever happens while processing the player, they must go
+            // back in the list.
+            if let Err(e) = self
+                .process_player(&mut player, delta_time, send_frames)
+                .await
+            {
                 error!(
                     "Error processing move for player: {:?} ({}): {:?}",
                     player.id(),
@@ -297,14 +1105,29 @@ impl RustonatorGame {
                 );
                 player.terminate();
             }
+            self.players.insert(pid, player);
+        }
 
-            // Reinsert player.
-            self.players.insert(player.id(), player);
+        if send_frames {
+            self.send_sound_events().await;
         }
 
+        // Any world changes that all players have now been sent can be discarded.
+        let world_version = self.world.version();
+        let oldest_version = self
+            .players
+            .values()
+            .map(|p| p.world_version().unwrap_or(world_version))
+            .min()
+            .unwrap_or(world_version);
+        self.world.clear_changes_up_to(oldest_version);
+
         // Remove dead players.
         let mut futs = Vec::new();
         for p in self.players.values_mut().filter(|p| p.is_dead()) {
+            if let Some(stats) = &mut self.stats {
+                stats.record_game(p.name(), p.score());
+            }
             futs.push(Box::pin(p.ws().disconnect()));
         }
         join_all(futs).await;
@@ -312,13 +1135,125 @@ impl RustonatorGame {
         self.players.retain(|_, p| !p.is_dead());
     }
 
-    async fn process_player_move(&mut self, player: &mut Player) -> ZResult<()> {
+    /// Update a single player for this frame. The player must already have
+    /// been removed from the list.
+    async fn process_player(
+        &mut self,
+        player: &mut Player,
+        delta_time: f64,
+        send_frames: bool,
+    ) -> ZResult<()>
+    {
+        let pid = player.id();
+        if self.config.interpolation() {
+            player.record_prev_position();
+        }
+
+        if player.is_active() && player.action().fire() {
+            if self.create_bomb_for_player(player) == BombPlacement::NoExit {
+                let pos = player.map_position(&self.world);
+                player.ws().send(PlayerMessage::BombBlocked(pos)).await?;
+            }
+
+            // Prevent more bombs until the player releases fire.
+            player.action_mut().cease_fire();
+        }
+
+        // The player being processed isn't in the list.
+        let occupied: HashSet<MapPosition> = if self.config.solid_players() {
+            self.players
+                .values()
+                .filter(|p| p.is_active())
+                .map(|p| p.map_position(&self.world))
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        if self.config.bomb_exit_grace() {
+            player.set_bomb_grace(
+                self.bombs
+                    .iter()
+                    .filter(|b| b.is_overlapping(pid))
+                    .map(|b| b.position())
+                    .collect(),
+            );
+        }
+        player.update(&self.world, delta_time, &self.config, &occupied);
+        if self.config.bomb_exit_grace() {
+            // Once they've stepped off, the bomb is solid to them too.
+            for bomb in self.bombs.iter_mut().filter(|b| b.is_overlapping(pid)) {
+                if !player.overlaps_tile(bomb.position(), &self.world) {
+                    bomb.release_overlapping(pid);
+                }
+            }
+        }
+        let scores = self.config.scores();
+        player.expire_combo(self.clock.now(), scores);
+        if player.is_active() && scores.decay_rate() > 0.0 {
+            player.decay_score(scores.decay_rate(), delta_time);
+        }
+        self.process_player_move(player, delta_time, send_frames)
+            .await
+    }
+
+    /// Scatter items for the player's extra bombs and range onto blank tiles
+    /// around them, in proportion to each, up to the configured maximum.
+    fn scatter_powerups(&mut self, player: &Player) {
+        let extra_bombs = player.max_bombs().saturating_sub(1);
+        let extra_range = player.range().saturating_sub(1);
+        let total = extra_bombs + extra_range;
+        let max_items = self.config.max_dropped_powerups();
+        let (bombs, ranges) = if total > max_items {
+            let bombs = extra_bombs * max_items / total;
+            (bombs, (max_items - bombs).min(extra_range))
+        } else {
+            (extra_bombs, extra_range)
+        };
+
+        let map_pos = player.map_position(&self.world);
+        let items = vec![CellType::ItemBomb; bombs as usize]
+            .into_iter()
+            .chain(vec![CellType::ItemRange; ranges as usize]);
+        for item in items {
+            let pos = self.world.find_nearest_blank(map_pos);
+            if self.world.get_cell(pos) != Some(CellType::Empty) {
+                // The map is full.
+                break;
+            }
+            self.world.set_cell(pos, item);
+        }
+    }
+
+    async fn process_player_move(
+        &mut self,
+        player: &mut Player,
+        delta_time: f64,
+        send_frame: bool,
+    ) -> ZResult<()>
+    {
+        // Nothing should ever move a player further than their speed allows,
+        // apart from snapping onto a gridline, which is up to 0.3 of a tile.
+        let tolerance = self.world.sizes().tile_size().width as f64 / 2.0;
+        if !player.validate_move(delta_time, tolerance, self.config.effects()) {
+            warn!(
+                "Player {:?} ({}) moved too far, moving them back",
+                player.id(),
+                player.name()
+            );
+        }
+
         let mut reason = String::new();
         let mut died = false;
+        let mut killer = None;
+        let mut weapon = Weapon::Bomb;
 
-        if player.is_active() {
+        if self.crushed.remove(&player.id()) {
+            died = true;
+            reason = String::from("You were crushed by the walls");
+            weapon = Weapon::SuddenDeath;
+        } else if player.is_active() {
             // Did we collect anything?
-            let map_pos = player.position().to_map_position(&self.world);
+            let map_pos = player.map_position(&self.world);
             match self.world.get_cell(map_pos) {
                 Some(CellType::Empty) | None => {}
                 Some(CellType::MobSpawner) => {
@@ -326,36 +1261,47 @@ impl RustonatorGame {
                         // You ded.
                         died = true;
                         reason = String::from("You touched a robot spawner");
+                        weapon = Weapon::MobSpawner;
 
-                        // Create explosion but don't add it to the world. It is for display only.
-                        let explosion = Explosion::new(None, map_pos);
-                        self.explosions.add(explosion);
+                        // This explosion is for display only.
+                        self.world
+                            .add_visual_only_explosion(map_pos, &mut self.explosions);
                     }
                 }
-                Some(ct) => {
-                    if player.got_item(ct).await? {
-                        self.world.set_cell(map_pos, CellType::Empty);
-                    }
+                Some(ct) => self.collect_item(player, map_pos, ct).await?,
+            }
+
+            // Near misses count too, if enabled.
+            for pos in player.nearby_tiles(self.config.pickup_radius(), &self.world) {
+                if let Some(ct) = self.world.get_cell(pos) {
+                    self.collect_item(player, pos, ct).await?;
                 }
             }
 
             // Did we touch something we shouldn't have?
             if !player.has_flag(PlayerFlags::Invincible) {
-                // Mob?
+                // Mob? Anything within half a tile can be at most one tile
+                // away in each direction, so only those mobs need checking.
+                // Mobs don't move while players are processed, so the index
+                // is still up to date.
                 let range = self.world.sizes().tile_size().width as f64 / 2.0;
-                for mob in self.mobs.iter() {
+                let nearby = self.world.query_region(map_pos, 2, 2);
+                let mobs = &self.mobs;
+                for mob in nearby.mobs.iter().filter_map(|id| mobs.get(*id)) {
                     if player.position().distance_to(mob.position()) <= range {
                         // You ded.
                         died = true;
-                        reason = if mob.is_smart() {
-                            String::from("You were killed by a robot overlord")
+                        if mob.is_smart() {
+                            reason = String::from("You were killed by a robot overlord");
+                            weapon = Weapon::SmartMob;
                         } else {
-                            String::from("You were killed by a robot")
-                        };
+                            reason = String::from("You were killed by a robot");
+                            weapon = Weapon::Mob;
+                        }
 
-                        // Create explosion but don't add it to the world. It is for display only.
-                        let explosion = Explosion::new(None, map_pos);
-                        self.explosions.add(explosion);
+                        // This explosion is for display only.
+                        self.world
+                            .add_visual_only_explosion(map_pos, &mut self.explosions);
                     }
                 }
 
@@ -364,8 +1310,18 @@ impl RustonatorGame {
                     self.world.get_internal_cell(map_pos)
                 {
                     if let Some(explosion) = self.explosions.get(*explosion_id) {
-                        if explosion.is_harmful() {
+                        if explosion.is_harmful()
+                            && explosion.intensity() < self.config.lethal_intensity()
+                        {
+                            // Only injured.
+                            if !player.has_effect(EffectType::SlowDown) {
+                                let effect =
+                                    Effect::new(EffectType::SlowDown, INJURY_SECONDS, &self.clock);
+                                player.add_effect(effect, self.config.effects());
+                            }
+                        } else if explosion.is_harmful() {
                             died = true;
+                            weapon = Weapon::Bomb;
 
                             // Award points to the player that killed this mob.
                             if explosion.pid() == player.id() {
@@ -373,7 +1329,12 @@ impl RustonatorGame {
                             } else if let Some(p) = self.players.get_mut(&explosion.pid()) {
                                 if !p.is_dead() {
                                     reason = format!("You were killed by '{}'", p.name());
-                                    p.increase_score(1000);
+                                    killer = Some(p.id());
+                                    let scores = self.config.scores();
+                                    p.award_kill(scores.player_kill(), self.clock.now(), scores);
+                                    if let Some(stats) = &mut self.stats {
+                                        stats.record_kill(p.name());
+                                    }
                                 } else {
                                     let pname = explosion.pname();
                                     let pname_str = if pname.is_empty() {
@@ -400,8 +1361,16 @@ impl RustonatorGame {
             }
         }
 
-        // Send frame update.
-        self.send_data_to_player(player).await?;
+        if died && self.config.drop_powerups_on_death() && self.config.powerups_enabled() {
+            self.scatter_powerups(player);
+        }
+
+        // Send frame update. This is always sent before a death message.
+        if send_frame || died {
+            let phase_start = Instant::now();
+            self.send_data_to_player(player).await?;
+            self.record_phase("net", phase_start);
+        }
 
         if died {
             debug!(
@@ -411,6 +1380,15 @@ impl RustonatorGame {
                 player.score(),
                 reason
             );
+            player.add_death();
+            if let Some(stats) = &mut self.stats {
+                stats.record_death(player.name());
+            }
+            self.emit(GameEvent::PlayerKilled {
+                pid: player.id(),
+                killer,
+                weapon,
+            });
             player.terminate();
             player.ws().send(PlayerMessage::Dead(reason)).await?;
         }
@@ -418,25 +1396,120 @@ impl RustonatorGame {
         Ok(())
     }
 
-    async fn send_data_to_player(&self, player: &mut Player) -> ZResult<()> {
-        let map_pos = player.position().to_map_position(&self.world);
-        let chunkwidth = self.world.sizes().chunk_size().width;
-        let chunkheight = self.world.sizes().chunk_size().height;
-        let local_players: Vec<&Player> = self
+    /// Give the player the item on the specified tile, if there is one.
+    async fn collect_item(
+        &mut self,
+        player: &mut Player,
+        pos: MapPosition,
+        ct: CellType,
+    ) -> ZResult<()>
+    {
+        if let Some(powerup) = player.got_item(ct, &self.clock, &self.config).await? {
+            self.world.set_cell(pos, CellType::Empty);
+            self.add_sound_event(pos, PlayerMessage::ItemCollected {
+                pos,
+                kind: ct.name().to_string(),
+            });
+            self.emit(GameEvent::PowerUpCollected {
+                pid: player.id(),
+                powerup: powerup.name().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Get the serialized chunk centred on the specified position. Chunks are
+    /// cached until the end of the frame or until the world changes, so that
+    /// players in the same area with the same view size share one copy.
+    fn get_chunk_raw(
+        &mut self,
+        map_pos: MapPosition,
+        chunk_size: SizeInTiles,
+    ) -> ZResult<Box<RawValue>>
+    {
+        if self.chunk_cache_version != self.world.version() {
+            self.chunk_cache.clear();
+            self.chunk_cache_version = self.world.version();
+        }
+
+        let key = (
+            self.world.get_chunk_topleft(map_pos, chunk_size),
+            chunk_size,
+        );
+        if let Some(value) = self.chunk_cache.get(&key) {
+            return Ok(value.clone());
+        }
+
+        self.world
+            .get_chunk_data_into(map_pos, chunk_size, &mut self.chunk_buffer);
+        let value = self.chunk_buffer.ser_raw()?;
+        self.chunk_cache.insert(key, value.clone());
+        Ok(value)
+    }
+
+    async fn send_data_to_player(&mut self, player: &mut Player) -> ZResult<()> {
+        let map_pos = player.map_position(&self.world);
+        let chunk_size = self
+            .world
+            .view_size(player.view_size(), self.config.max_view_size());
+        // Older clients need the world in every frame.
+        let partial = player.partial_world();
+        let full_world = self.config.small_map_full_world() && self.world.fits_in_chunk();
+        let world_chunk = if partial && full_world {
+            // Only send the map if it has changed since this player last saw it.
+            if player.world_version() == Some(self.world.version()) {
+                None
+            } else {
+                player.set_world_version(self.world.version());
+                Some(self.world.get_full_chunk().ser_raw()?)
+            }
+        } else if partial && self.config.world_delta_updates() {
+            let topleft = self.world.get_chunk_topleft(map_pos, chunk_size);
+            let version = self.world.version();
+            match player.world_version() {
+                Some(v) if player.chunk_topleft() == Some(topleft) => {
+                    let delta = self.world.get_chunk_delta(v, topleft, chunk_size);
+                    if !delta.is_empty() {
+                        player
+                            .ws()
+                            .send(PlayerMessage::WorldDelta(delta.ser()?))
+                            .await?;
+                    }
+                    player.set_world_version(version);
+                    None
+                }
+                _ => {
+                    // New player or the chunk has moved, so send the whole chunk.
+                    player.set_world_version(version);
+                    player.set_chunk_topleft(topleft);
+                    Some(self.get_chunk_raw(map_pos, chunk_size)?)
+                }
+            }
+        } else {
+            Some(self.get_chunk_raw(map_pos, chunk_size)?)
+        };
+
+        let chunkwidth = chunk_size.width;
+        let chunkheight = chunk_size.height;
+        // Pad the query by a tile since players processed earlier this frame
+        // may have moved since they were indexed.
+        let nearby = self
+            .world
+            .query_region(map_pos, chunkwidth + 1, chunkheight + 1);
+        let local_players: Vec<&Player> = nearby
             .players
-            .values()
+            .iter()
+            .filter_map(|id| self.players.get(id))
             .filter(|p| {
-                p.position().to_map_position(&self.world).is_within_grid(
-                    map_pos,
-                    chunkwidth,
-                    chunkheight,
-                )
+                p.map_position(&self.world)
+                    .is_within_grid(map_pos, chunkwidth, chunkheight)
             })
             .collect();
 
-        let local_mobs: Vec<&Mob> = self
+        let local_mobs: Vec<&Mob> = nearby
             .mobs
             .iter()
+            .filter_map(|id| self.mobs.get(*id))
             .filter(|m| {
                 m.position().to_map_position(&self.world).is_within_grid(
                     map_pos,
@@ -446,34 +1519,1049 @@ impl RustonatorGame {
             })
             .collect();
 
-        let local_bombs: Vec<&Bomb> = self
+        let local_bombs: Vec<&Bomb> = nearby
             .bombs
             .iter()
+            .filter_map(|id| self.bombs.get(*id))
             .filter(|b| {
                 b.position()
                     .is_within_grid(map_pos, chunkwidth, chunkheight)
             })
             .collect();
 
-        let local_explosions: Vec<&Explosion> = self
+        let local_explosions: Vec<&Explosion> = nearby
             .explosions
             .iter()
+            .filter_map(|id| self.explosions.get(*id))
             .filter(|e| {
                 e.position()
                     .is_within_grid(map_pos, chunkwidth, chunkheight)
             })
             .collect();
 
-        let ser_data = serde_json::json!({
-            "player": player,
-            "world": self.world.get_chunk_data(map_pos),
-            "players": local_players,
-            "mobs": local_mobs,
-            "bombs": local_bombs,
-            "explosions": local_explosions
-        });
+        let ser_data = SerFrameData {
+            tick: self.tick,
+            input_id: player.last_input_id(),
+            player,
+            world: world_chunk.as_deref(),
+            players: local_players,
+            mobs: local_mobs,
+            bombs: local_bombs,
+            explosions: local_explosions,
+            safe_bounds: self
+                .sudden_death
+                .as_ref()
+                .filter(|s| s.rings() > 0)
+                .map(|s| s.safe_bounds()),
+            danger: if self.config.danger_tiles() {
+                Some(self.world.danger_tiles(map_pos, chunkwidth, chunkheight))
+            } else {
+                None
+            },
+        };
+        let ser_data = RawValue::from_string(serde_json::to_string(&ser_data)?)?;
 
         player.ws().send(PlayerMessage::FrameData(ser_data)).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        comms::playercomm::LocalClient,
+        component::{action::Action, effect::Effect},
+        engine::{config::ScoreConfig, player::PlayerBuilder},
+    };
+    use serde_json::json;
+
+    fn add_mob_at(game: &mut RustonatorGame, pos: MapPosition) {
+        let mut mob = Mob::new();
+        mob.set_position(PixelPositionF64::from_map_position(pos, &game.world));
+        game.mobs.add(mob);
+    }
+
+    /// Add a player, ready to play, on an empty tile at the specified position.
+    fn add_player_at(game: &mut RustonatorGame, id: PlayerId, pos: MapPosition) -> LocalClient {
+        let (mut player, client) = PlayerBuilder::new(id).build_local();
+        player.reset_for_round(&game.world, game.config.effects());
+        game.world.set_cell(pos, CellType::Empty);
+        player.set_position(PixelPositionF64::from_map_position(pos, &game.world));
+        game.players.insert(id, player);
+        client
+    }
+
+    #[test]
+    fn test_spawn_mob_avoids_nearby_mobs() {
+        let mut game = RustonatorGame::new(47, 47);
+        let spawners: Vec<MapPosition> = game.mob_spawners.iter().map(|s| s.position()).collect();
+        assert!(spawners.len() > 1);
+
+        // Occupy every spawner except the last one.
+        let (free, occupied) = spawners.split_last().unwrap();
+        for pos in occupied {
+            add_mob_at(&mut game, *pos);
+        }
+
+        for _ in 0..20 {
+            game.spawn_mob();
+            let world = &game.world;
+            let spawned = game
+                .mobs
+                .retain_and_collect(|_, m| occupied.contains(&m.position().to_map_position(world)));
+            assert_eq!(spawned.len(), 1);
+            assert_eq!(spawned[0].position().to_map_position(world), *free);
+        }
+
+        // Once every spawner is occupied, nothing spawns.
+        add_mob_at(&mut game, *free);
+        let count = game.mobs.len();
+        game.spawn_mob();
+        assert_eq!(game.mobs.len(), count);
+    }
+
+    #[test]
+    fn test_spawn_mob_avoids_players() {
+        let mut game = RustonatorGame::new(47, 47);
+        let spawners: Vec<MapPosition> = game.mob_spawners.iter().map(|s| s.position()).collect();
+        assert!(spawners.len() > 1);
+
+        // Put a player next to every spawner except the last one.
+        let (free, occupied) = spawners.split_last().unwrap();
+        let mut clients = Vec::new();
+        for (i, pos) in occupied.iter().enumerate() {
+            let id = PlayerId::from(i as u64 + 1);
+            clients.push(add_player_at(&mut game, id, pos.right(1)));
+        }
+
+        for _ in 0..20 {
+            game.spawn_mob();
+            let spawned = game.mobs.retain_and_collect(|_, _| false);
+            assert_eq!(spawned.len(), 1);
+            assert_eq!(spawned[0].position().to_map_position(&game.world), *free);
+        }
+    }
+
+    #[test]
+    fn test_mob_spawn_interval_scales_with_players() {
+        let mut config = GameConfig::new();
+        config.set_scale_mob_spawns(true);
+        config.set_min_mob_spawn_interval(4.0);
+        let mut game = RustonatorGame::with_config(15, 15, config);
+        let mut clients = Vec::new();
+        let mut intervals = vec![game.mob_spawn_interval(30.0)];
+        for i in 1..=10 {
+            let id = PlayerId::from(i);
+            let (mut player, client) = PlayerBuilder::new(id).build_local();
+            player.reset_for_round(&game.world, game.config.effects());
+            game.players.insert(id, player);
+            clients.push(client);
+            intervals.push(game.mob_spawn_interval(30.0));
+        }
+        assert_eq!(intervals[..4], [30.0, 30.0, 15.0, 10.0]);
+        assert!(intervals.windows(2).all(|w| w[1] <= w[0]));
+        assert_eq!(intervals[10], 4.0);
+
+        // Off by default.
+        let players = std::mem::take(&mut game.players);
+        let mut game = RustonatorGame::new(15, 15);
+        game.players = players;
+        assert_eq!(game.mob_spawn_interval(30.0), 30.0);
+    }
+
+    #[test]
+    fn test_frozen_mob_does_not_move() {
+        let mut game = RustonatorGame::new(15, 15);
+        let mob_pos = MapPosition::new(1, 1);
+        add_mob_at(&mut game, mob_pos);
+
+        let id = PlayerId::from(1);
+        let mut player = PlayerBuilder::new(id).build();
+        player.set_position(PixelPositionF64::from_map_position(
+            MapPosition::new(3, 3),
+            &game.world,
+        ));
+        let effect = Effect::new(EffectType::FreezeMobs, 5.0, &game.clock);
+        player.add_effect(effect, game.config.effects());
+        game.players.insert(id, player);
+
+        for _ in 0..30 {
+            game.game_process_mobs(0.1);
+            let world = &game.world;
+            let positions: Vec<MapPosition> = game
+                .mobs
+                .iter()
+                .map(|m| m.position().to_map_position(world))
+                .collect();
+            assert_eq!(positions, vec![mob_pos]);
+        }
+    }
+
+    #[test]
+    fn test_scatter_powerups() {
+        let mut config = GameConfig::new();
+        config.set_drop_powerups_on_death(true);
+        config.set_max_dropped_powerups(4);
+        let mut game = RustonatorGame::with_config(15, 15, config);
+        let count_items = |world: &World| {
+            (0..15)
+                .flat_map(|y| (0..15).map(move |x| MapPosition::new(x, y)))
+                .filter(|pos| {
+                    matches!(
+                        world.get_cell(*pos),
+                        Some(CellType::ItemBomb) | Some(CellType::ItemRange)
+                    )
+                })
+                .count()
+        };
+        let before = count_items(&game.world);
+
+        let id = PlayerId::from(1);
+        let mut player = PlayerBuilder::new(id).build();
+        let death_pos = game.world.find_nearest_blank(MapPosition::new(7, 7));
+        player.set_position(PixelPositionF64::from_map_position(death_pos, &game.world));
+
+        // A player with no extra powerups drops nothing.
+        game.scatter_powerups(&player);
+        assert_eq!(count_items(&game.world), before);
+
+        // 3 extra bombs and 2 extra range, capped to 4 items.
+        for _ in 0..3 {
+            player.increase_max_bombs();
+        }
+        for _ in 0..2 {
+            player.increase_range();
+        }
+        game.scatter_powerups(&player);
+        assert_eq!(count_items(&game.world), before + 4);
+        assert_eq!(game.world.get_cell(death_pos), Some(CellType::ItemBomb));
+    }
+
+    #[test]
+    fn test_mob_kill_awards_configured_score() {
+        let mut scores = ScoreConfig::new();
+        scores.set_mob_kill(scores.mob_kill() * 2);
+        scores.set_smart_mob_kill(scores.smart_mob_kill() * 2);
+        let mut config = GameConfig::new();
+        config.set_scores(scores);
+        let mut game = RustonatorGame::with_config(15, 15, config);
+
+        let id = PlayerId::from(1);
+        let player = PlayerBuilder::new(id).build();
+        let pos = MapPosition::new(1, 1);
+        let bomb = Bomb::new(&player, pos, &game.clock);
+        let explosion = Explosion::new(Some(&bomb), pos, &game.clock);
+        game.world.add_explosion(explosion, &mut game.explosions);
+        game.players.insert(id, player);
+        add_mob_at(&mut game, pos);
+        let smart = game.mobs.iter().all(|m| m.is_smart());
+
+        game.game_process_mobs(0.001);
+        assert!(game.mobs.is_empty());
+        let expected = if smart { 4000 } else { 1000 };
+        assert_eq!(game.players[&id].score(), expected);
+    }
+
+    #[test]
+    fn test_chained_bomb_kill_credits_chained_owner() {
+        let mut game = RustonatorGame::new(15, 15);
+        let mut clients = Vec::new();
+        for (i, (x, y)) in [(1, 13), (13, 13), (5, 5)].iter().enumerate() {
+            let id = PlayerId::from(i as u64 + 1);
+            clients.push(add_player_at(&mut game, id, MapPosition::new(*x, *y)));
+        }
+        // Let the spawn invincibility wear off.
+        game.clock.advance(10.0);
+        game.world.set_clock(&game.clock);
+
+        // Player 1's bomb sets off player 2's bomb, which reaches player 3.
+        for (x, y) in &[(2, 5), (3, 4), (3, 6), (4, 4), (4, 6)] {
+            game.world
+                .set_cell(MapPosition::new(*x, *y), CellType::Empty);
+        }
+        let first = MapPosition::new(3, 5);
+        let second = MapPosition::new(4, 5);
+        let first_id = game.world.add_bomb(
+            Bomb::new(&game.players[&PlayerId::from(1)], first, &game.clock),
+            &mut game.bombs,
+        );
+        game.world.add_bomb(
+            Bomb::new(&game.players[&PlayerId::from(2)], second, &game.clock),
+            &mut game.bombs,
+        );
+        let bomb = game.bombs.get(first_id).unwrap().clone();
+        game.bombs.destroy(first_id);
+        game.world.explode_bomb(
+            bomb,
+            &mut game.bombs,
+            &mut game.explosions,
+            &mut game.players,
+            game.config.scores(),
+        );
+
+        let victim_pos = MapPosition::new(5, 5);
+        let explosion = match game.world.get_internal_cell(victim_pos) {
+            Some(InternalCellData::Explosion(id)) => game.explosions.get(*id).unwrap(),
+            x => panic!("Expected an explosion, got {:?}", x),
+        };
+        assert_eq!(explosion.pid(), PlayerId::from(2));
+
+        futures::executor::block_on(game.game_process_players(0.001, false));
+        assert!(!game.players[&PlayerId::from(3)].is_active());
+        assert_eq!(game.players[&PlayerId::from(1)].score(), 0);
+        assert!(game.players[&PlayerId::from(2)].score() > 0);
+    }
+
+    #[test]
+    fn test_kills_and_deaths() {
+        let mut game = RustonatorGame::new(15, 15);
+        let mut clients = Vec::new();
+        for (i, (x, y)) in [(1, 13), (5, 5), (9, 9)].iter().enumerate() {
+            let id = PlayerId::from(i as u64 + 1);
+            clients.push(add_player_at(&mut game, id, MapPosition::new(*x, *y)));
+        }
+        game.clock.advance(10.0);
+        game.world.set_clock(&game.clock);
+
+        // Player 1 gets player 2, and player 3 gets themselves.
+        for (owner, pos) in &[(1, (5, 5)), (3, (9, 9))] {
+            let owner = &game.players[&PlayerId::from(*owner)];
+            let pos = MapPosition::new(pos.0, pos.1);
+            let bomb = Bomb::new(owner, pos, &game.clock);
+            let explosion = Explosion::new(Some(&bomb), pos, &game.clock);
+            game.world.add_explosion(explosion, &mut game.explosions);
+        }
+        // Player 1 also gets a mob.
+        add_mob_at(&mut game, MapPosition::new(5, 5));
+        game.game_process_mobs(0.001);
+        futures::executor::block_on(game.game_process_players(0.001, false));
+
+        let counts: Vec<(u32, u32)> = (1..=3)
+            .map(|i| {
+                let p = &game.players[&PlayerId::from(i)];
+                (p.kills(), p.deaths())
+            })
+            .collect();
+        assert_eq!(counts, vec![(2, 0), (0, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn test_bomb_cooldown() {
+        let mut game = RustonatorGame::new(15, 15);
+        let id = PlayerId::from(1);
+        let mut player = PlayerBuilder::new(id).build();
+        player.increase_max_bombs();
+        let positions = [
+            MapPosition::new(1, 1),
+            MapPosition::new(3, 1),
+            MapPosition::new(5, 1),
+        ];
+        for pos in positions.iter() {
+            game.world.set_cell(*pos, CellType::Empty);
+        }
+
+        let mut place_at = |game: &mut RustonatorGame, pos: MapPosition| {
+            player.set_position(PixelPositionF64::from_map_position(pos, &game.world));
+            game.create_bomb_for_player(&mut player);
+        };
+        place_at(&mut game, positions[0]);
+        game.clock.advance(game.config.bomb_cooldown() / 2.0);
+        place_at(&mut game, positions[1]);
+        assert_eq!(game.bombs.len(), 1);
+
+        game.clock.advance(game.config.bomb_cooldown());
+        place_at(&mut game, positions[2]);
+        assert_eq!(game.bombs.len(), 2);
+    }
+
+    #[test]
+    fn test_bomb_needs_exit() {
+        let mut config = GameConfig::new();
+        config.set_require_bomb_exit(true);
+        let mut game = RustonatorGame::with_config(15, 15, config);
+        let id = PlayerId::from(1);
+        let mut player = PlayerBuilder::new(id).build();
+
+        // A one-tile pocket.
+        let pos = MapPosition::new(3, 3);
+        game.world.set_cell(pos, CellType::Empty);
+        for p in [pos.up(1), pos.right(1), pos.down(1), pos.left(1)].iter() {
+            game.world.set_cell(*p, CellType::Mystery);
+        }
+        player.set_position(PixelPositionF64::from_map_position(pos, &game.world));
+        assert_eq!(
+            game.create_bomb_for_player(&mut player),
+            BombPlacement::NoExit
+        );
+        assert_eq!(game.bombs.len(), 0);
+
+        game.world.set_cell(pos.left(1), CellType::Empty);
+        assert_eq!(
+            game.create_bomb_for_player(&mut player),
+            BombPlacement::Placed
+        );
+        assert_eq!(game.bombs.len(), 1);
+    }
+
+    #[test]
+    fn test_short_fuse() {
+        let mut game = RustonatorGame::new(15, 15);
+        let id = PlayerId::from(1);
+        let mut player = PlayerBuilder::new(id).build();
+        player.increase_max_bombs();
+        player.add_short_fuse_charges(1);
+        player.action_mut().set_short_fuse(true);
+        let positions = [MapPosition::new(1, 1), MapPosition::new(3, 1)];
+        let mut fuses = Vec::new();
+        for pos in positions.iter() {
+            game.world.set_cell(*pos, CellType::Empty);
+            player.set_position(PixelPositionF64::from_map_position(*pos, &game.world));
+            let placed = game.clock.now();
+            game.create_bomb_for_player(&mut player);
+            let bomb = game.bombs.iter().find(|b| b.position() == *pos).unwrap();
+            fuses.push(bomb.timestamp().millis_since(placed));
+            game.clock.advance(game.config.bomb_cooldown());
+        }
+
+        assert_eq!(player.short_fuse_charges(), 0);
+        assert_eq!(fuses[0], BombTime::MIN.millis() as i64);
+        // Out of charges, so the next bomb gets the player's usual fuse.
+        assert_eq!(fuses[1], player.bomb_time().millis() as i64);
+        assert!(fuses[0] < fuses[1]);
+    }
+
+    #[test]
+    fn test_bomb_exit_grace() {
+        let mut config = GameConfig::new();
+        config.set_bomb_exit_grace(true);
+        let mut game = RustonatorGame::with_config(15, 15, config);
+        let bomb_pos = MapPosition::new(5, 5);
+        for x in 3..=8 {
+            game.world.set_cell(MapPosition::new(x, 5), CellType::Empty);
+        }
+        let tile_width = game.world.sizes().tile_size().width as f64;
+        let centre = PixelPositionF64::from_map_position(bomb_pos, &game.world);
+
+        let mut clients = Vec::new();
+        let mut players = Vec::new();
+        for i in 1..=2 {
+            let id = PlayerId::from(i);
+            let (mut player, client) = PlayerBuilder::new(id).build_local();
+            player.reset_for_round(&game.world, game.config.effects());
+            player.set_position(centre);
+            players.push(player);
+            clients.push(client);
+        }
+        let other = players.pop().unwrap();
+        let mut owner = players.pop().unwrap();
+        let other_id = other.id();
+        game.players.insert(other_id, other);
+        game.create_bomb_for_player(&mut owner);
+        game.players.insert(owner.id(), owner);
+        assert_eq!(game.world.get_cell(bomb_pos), Some(CellType::Bomb));
+
+        // Half way off the bomb, the other player can still step back onto it.
+        let start_x = centre.x + tile_width * 0.75;
+        let other = game.players.get_mut(&other_id).unwrap();
+        other.set_position(PixelPositionF64::new(start_x, centre.y));
+        other.action_mut().setxy(-1, 0);
+        futures::executor::block_on(game.game_process_players(0.05, false));
+        assert!(game.players[&other_id].position().x < start_x);
+
+        // Once they've stepped right off it, it's solid.
+        let off_x = centre.x + tile_width;
+        let other = game.players.get_mut(&other_id).unwrap();
+        other.set_position(PixelPositionF64::new(off_x, centre.y));
+        other.action_mut().setxy(0, 0);
+        futures::executor::block_on(game.game_process_players(0.05, false));
+        let other = game.players.get_mut(&other_id).unwrap();
+        other.set_position(PixelPositionF64::new(start_x, centre.y));
+        other.action_mut().setxy(-1, 0);
+        futures::executor::block_on(game.game_process_players(0.05, false));
+        assert_eq!(game.players[&other_id].position().x, off_x);
+    }
+
+    #[test]
+    fn test_pickup_radius() {
+        for &(radius, collected) in &[(0.0, false), (0.25, true)] {
+            let mut config = GameConfig::new();
+            config.set_pickup_radius(radius);
+            let mut game = RustonatorGame::with_config(15, 15, config);
+            let id = PlayerId::from(1);
+            let (mut player, _client) = PlayerBuilder::new(id).build_local();
+            player.reset_for_round(&game.world, game.config.effects());
+
+            // Skimming past the item below, most of the way onto its tile.
+            let pos = MapPosition::new(3, 1);
+            let near = pos.down(1);
+            let far = pos.down(2);
+            game.world.set_cell(pos, CellType::Empty);
+            game.world.set_cell(near, CellType::ItemBomb);
+            game.world.set_cell(far, CellType::ItemBomb);
+            let tile_height = game.world.sizes().tile_size().height as f64;
+            let mut skim = PixelPositionF64::from_map_position(pos, &game.world);
+            skim.y += tile_height * 0.4;
+            player.set_position(skim);
+            assert_eq!(player.map_position(&game.world), pos);
+            game.players.insert(id, player);
+
+            futures::executor::block_on(game.game_process_players(0.001, false));
+            let expected = if collected { 2 } else { 1 };
+            assert_eq!(game.players[&id].max_bombs(), expected);
+            // Never anything two tiles away.
+            assert_eq!(game.world.get_cell(far), Some(CellType::ItemBomb));
+        }
+    }
+
+    #[test]
+    fn test_bomb_placed_sound_event() {
+        let mut config = GameConfig::new();
+        config.set_sound_events(true);
+        let mut game = RustonatorGame::with_config(31, 31, config);
+        let mut clients = Vec::new();
+        let positions = [
+            MapPosition::new(1, 1),
+            MapPosition::new(3, 1),
+            MapPosition::new(29, 29),
+        ];
+        for (i, pos) in positions.iter().enumerate() {
+            let id = PlayerId::from(i as u64 + 1);
+            clients.push(add_player_at(&mut game, id, *pos));
+        }
+
+        let id = PlayerId::from(1);
+        let mut player = game.players.remove(&id).unwrap();
+        game.create_bomb_for_player(&mut player);
+        game.players.insert(id, player);
+        futures::executor::block_on(game.send_sound_events());
+
+        let expected = json!({"code": "BOMBPLACED", "data": {"x": 1, "y": 1}});
+        assert_eq!(received_codes(&mut clients[0]), vec![expected.clone()]);
+        assert_eq!(received_codes(&mut clients[1]), vec![expected]);
+        // Too far away to hear it.
+        assert!(received_codes(&mut clients[2]).is_empty());
+    }
+
+    #[test]
+    fn test_idle_without_players() {
+        let mut game = RustonatorGame::new(15, 15);
+        let id = PlayerId::from(1);
+        let player = PlayerBuilder::new(id).build();
+        let pos = MapPosition::new(1, 1);
+        game.world.set_cell(pos, CellType::Empty);
+        game.world
+            .add_bomb(Bomb::new(&player, pos, &game.clock), &mut game.bombs);
+        add_mob_at(&mut game, MapPosition::new(5, 5));
+        let mob_position = game.mobs.iter().next().unwrap().position();
+
+        assert!(game.is_idle());
+        for _ in 0..300 {
+            futures::executor::block_on(game.game_step(1.0 / 30.0, true));
+        }
+        assert_eq!(game.clock.elapsed(), 0.0);
+        assert_eq!(game.bombs.len(), 1);
+        assert!(game.explosions.is_empty());
+        assert_eq!(game.mobs.iter().next().unwrap().position(), mob_position);
+
+        // Everything resumes as soon as someone connects.
+        game.players.insert(id, player);
+        assert!(!game.is_idle());
+        for _ in 0..300 {
+            futures::executor::block_on(game.game_step(1.0 / 30.0, false));
+        }
+        assert!(game.bombs.is_empty());
+    }
+
+    #[test]
+    fn test_tick_sent_with_frames() {
+        let mut game = RustonatorGame::new(15, 15);
+        let id = PlayerId::from(1);
+        let (mut player, mut client) = PlayerBuilder::new(id).build_local();
+        player.reset_for_round(&game.world, game.config.effects());
+        game.players.insert(id, player);
+
+        assert_eq!(game.tick(), 0);
+        for tick in 1..=3 {
+            futures::executor::block_on(game.game_step(1.0 / 30.0, true));
+            assert_eq!(game.tick(), tick);
+            let frames: Vec<serde_json::Value> = received_codes(&mut client)
+                .into_iter()
+                .filter(|m| m["code"] == "FRAMEDATA")
+                .collect();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0]["data"]["tick"], tick);
+            assert_eq!(frames[0]["data"]["inputId"], 0);
+        }
+    }
+
+    #[test]
+    fn test_local_player() {
+        let mut game = RustonatorGame::new(15, 15);
+        let id = PlayerId::from(1);
+        let mut client = game.add_local_player(id);
+        futures::executor::block_on(async {
+            client.join("practice").await.unwrap();
+            game.process_player_inputs(1.0 / 30.0).await;
+        });
+        assert!(game.players[&id].is_active());
+        assert_eq!(game.players[&id].name(), "practice");
+
+        // Clear a path and drive the player along it.
+        let start = MapPosition::new(1, 1);
+        for x in 1..=6 {
+            game.world.set_cell(MapPosition::new(x, 1), CellType::Empty);
+        }
+        game.players
+            .get_mut(&id)
+            .unwrap()
+            .set_position(PixelPositionF64::from_map_position(start, &game.world));
+        let mut right = Action::new();
+        right.setxy(1, 0);
+        for _ in 0..30 {
+            futures::executor::block_on(async {
+                client.send_action(right.clone()).await.unwrap();
+                game.process_player_inputs(1.0 / 30.0).await;
+                game.game_step(1.0 / 30.0, true).await;
+            });
+            while client.try_recv().is_some() {}
+        }
+        let map_pos = game.players[&id].map_position(&game.world);
+        assert_eq!(map_pos.y, 1);
+        assert!(map_pos.x >= 4, "player only reached {:?}", map_pos);
+    }
+
+    #[test]
+    fn test_processing_error_keeps_player() {
+        let mut game = RustonatorGame::new(15, 15);
+        // Nobody is listening, so sending the frame fails.
+        let id = PlayerId::from(1);
+        let mut player = PlayerBuilder::new(id).build();
+        player.reset_for_round(&game.world, game.config.effects());
+        game.players.insert(id, player);
+
+        futures::executor::block_on(game.game_process_players(1.0 / 30.0, true));
+        let player = &game.players[&id];
+        assert!(!player.is_active());
+        assert!(!player.is_dead());
+    }
+
+    #[test]
+    fn test_prev_position_lags_one_update() {
+        let mut config = GameConfig::new();
+        config.set_interpolation(true);
+        let mut game = RustonatorGame::with_config(15, 15, config);
+        let id = PlayerId::from(1);
+        let (mut player, _client) = PlayerBuilder::new(id).build_local();
+        player.reset_for_round(&game.world, game.config.effects());
+        game.players.insert(id, player);
+        add_mob_at(&mut game, MapPosition::new(7, 7));
+        assert_eq!(game.mobs.iter().next().unwrap().prev_position(), None);
+
+        for _ in 0..30 {
+            let mob_position = game.mobs.iter().next().unwrap().position();
+            let player_position = game.players[&id].position();
+            futures::executor::block_on(game.game_step(1.0 / 30.0, false));
+            let mob = game.mobs.iter().next().unwrap();
+            assert_eq!(mob.prev_position(), Some(mob_position));
+            assert_eq!(game.players[&id].prev_position(), Some(player_position));
+        }
+
+        let json = serde_json::to_value(game.mobs.iter().next().unwrap()).unwrap();
+        assert!(json.get("prev").is_some());
+    }
+
+    #[test]
+    fn test_run_headless() {
+        let mut game = RustonatorGame::new(47, 47);
+        let stats = futures::executor::block_on(game.run_headless(10, 100));
+        assert_eq!(stats.ticks(), 100);
+        assert_eq!(game.tick(), 100);
+        // Bots that died on the last tick haven't been replaced yet.
+        assert!(game.players.len() <= 10);
+        assert!(game.players.values().all(|p| p.name().starts_with("bot")));
+    }
+
+    #[test]
+    fn test_nearest_mob_to() {
+        let mut game = RustonatorGame::new(15, 15);
+        assert_eq!(game.nearest_mob_to(MapPosition::new(1, 1)), None);
+        for (x, y) in &[(3, 3), (9, 9), (11, 1), (5, 13)] {
+            add_mob_at(&mut game, MapPosition::new(*x, *y));
+        }
+        let mob_at = |game: &RustonatorGame, id: MobId| {
+            game.mobs
+                .get(id)
+                .unwrap()
+                .position()
+                .to_map_position(&game.world)
+        };
+
+        let (id, distance) = game.nearest_mob_to(MapPosition::new(1, 1)).unwrap();
+        assert_eq!(mob_at(&game, id), MapPosition::new(3, 3));
+        assert_eq!(distance, 4);
+        let (id, distance) = game.nearest_mob_to(MapPosition::new(12, 2)).unwrap();
+        assert_eq!(mob_at(&game, id), MapPosition::new(11, 1));
+        assert_eq!(distance, 2);
+        let (id, distance) = game.nearest_mob_to(MapPosition::new(9, 9)).unwrap();
+        assert_eq!(mob_at(&game, id), MapPosition::new(9, 9));
+        assert_eq!(distance, 0);
+    }
+
+    #[test]
+    fn test_mob_collision() {
+        let mut game = RustonatorGame::new(15, 15);
+        let mut clients = Vec::new();
+        for (i, (x, y)) in [(1, 1), (7, 7)].iter().enumerate() {
+            let id = PlayerId::from(i as u64 + 1);
+            clients.push(add_player_at(&mut game, id, MapPosition::new(*x, *y)));
+        }
+        game.clock.advance(10.0);
+        game.world.set_clock(&game.clock);
+
+        // A mob right next to player 1, and one a tile away from player 2.
+        let mut pos = PixelPositionF64::from_map_position(MapPosition::new(1, 1), &game.world);
+        pos.x += 5.0;
+        let mut mob = Mob::new();
+        mob.set_position(pos);
+        game.mobs.add(mob);
+        add_mob_at(&mut game, MapPosition::new(7, 8));
+        futures::executor::block_on(game.game_process_players(0.001, false));
+
+        assert_eq!(game.players[&PlayerId::from(1)].deaths(), 1);
+        assert_eq!(game.players[&PlayerId::from(2)].deaths(), 0);
+    }
+
+    #[test]
+    fn test_broadcast_reaches_all_players() {
+        let mut game = RustonatorGame::new(15, 15);
+        let mut clients = Vec::new();
+        for i in 1..=3 {
+            let id = PlayerId::from(i);
+            let (player, client) = PlayerBuilder::new(id).build_local();
+            game.players.insert(id, player);
+            clients.push(client);
+        }
+
+        let long_text = "x".repeat(MAX_ANNOUNCEMENT_LENGTH * 2);
+        futures::executor::block_on(game.broadcast("Restarting\n soon"));
+        futures::executor::block_on(game.broadcast(&long_text));
+        for client in clients.iter_mut() {
+            let messages = received_codes(client);
+            assert_eq!(messages.len(), 2);
+            assert_eq!(messages[0]["code"], "ANNOUNCEMENT");
+            assert_eq!(messages[0]["data"], "Restarting soon");
+            let truncated = messages[1]["data"].as_str().unwrap();
+            assert_eq!(truncated.len(), MAX_ANNOUNCEMENT_LENGTH);
+        }
+    }
+
+    #[test]
+    fn test_regenerate_world_keeps_players() {
+        let mut game = RustonatorGame::new(15, 15);
+        let mut clients = Vec::new();
+        for i in 1..=3 {
+            let id = PlayerId::from(i);
+            let (mut player, client) = PlayerBuilder::new(id).build_local();
+            // The last player hasn't joined yet.
+            if i < 3 {
+                player.reset_for_round(&game.world, game.config.effects());
+                player.increase_score(100);
+            }
+            game.players.insert(id, player);
+            clients.push(client);
+        }
+        add_mob_at(&mut game, MapPosition::new(5, 5));
+
+        futures::executor::block_on(game.regenerate_world(25, 21));
+        assert_eq!(*game.world.sizes().map_size(), SizeInTiles::new(25, 21));
+        assert!(game.mobs.is_empty());
+        let ids: HashSet<PlayerId> = game.players.keys().copied().collect();
+        assert_eq!(ids, (1..=3).map(PlayerId::from).collect());
+
+        for (i, client) in clients.iter_mut().enumerate() {
+            let messages = received_codes(client);
+            if i < 2 {
+                let p = &game.players[&PlayerId::from(i as u64 + 1)];
+                assert!(p.is_active());
+                assert_eq!(p.score(), 100);
+                let pos = p.map_position(&game.world);
+                assert_eq!(game.world.get_cell(pos), Some(CellType::Empty));
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0]["code"], "SPAWNPLAYER");
+            } else {
+                assert!(messages.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut game = RustonatorGame::new(15, 15);
+        for i in 1..=3 {
+            let id = PlayerId::from(i);
+            game.players.insert(id, PlayerBuilder::new(id).build());
+        }
+        add_mob_at(&mut game, MapPosition::new(1, 1));
+
+        let snapshot = game.snapshot();
+        assert_eq!(snapshot.players.len(), game.players.len());
+        assert_eq!(snapshot.mobs.len(), 1);
+        assert!(snapshot.bombs.is_empty());
+        assert!(snapshot.mobs[0].debug.is_none());
+        assert!(serde_json::to_string(&snapshot).is_ok());
+
+        let snapshot = game.debug_snapshot();
+        assert!(snapshot.mobs[0].debug.is_some());
+        let value = serde_json::to_value(&snapshot).unwrap();
+        assert!(value["mobs"][0]["debug"]["target_mode"].is_string());
+    }
+
+    #[test]
+    fn test_kick_player() {
+        let mut game = RustonatorGame::new(15, 15);
+        for i in 1..=2 {
+            let id = PlayerId::from(i);
+            game.players.insert(id, PlayerBuilder::new(id).build());
+        }
+
+        let kicked = PlayerId::from(1);
+        futures::executor::block_on(game.handle_admin_command(AdminCommand::Kick(kicked)));
+        assert!(!game.players.contains_key(&kicked));
+        assert_eq!(game.players.len(), 1);
+    }
+
+    /// Send a frame to a new player with the specified view size, returning
+    /// the size of the world chunk it was sent.
+    fn sent_chunk_size(
+        game: &mut RustonatorGame,
+        view_size: Option<SizeInTiles>,
+    ) -> (Option<i64>, Option<i64>)
+    {
+        let id = PlayerId::from(1);
+        let (mut player, mut client) = PlayerBuilder::new(id).build_local();
+        player.set_position(PixelPositionF64::from_map_position(
+            MapPosition::new(50, 51),
+            &game.world,
+        ));
+        if let Some(size) = view_size {
+            player.set_view_size(size);
+        }
+        futures::executor::block_on(game.send_data_to_player(&mut player)).unwrap();
+        let message = serde_json::to_value(client.try_recv().unwrap()).unwrap();
+        let world = &message["data"]["world"];
+        (world["width"].as_i64(), world["height"].as_i64())
+    }
+
+    /// Compare building each frame as a serde_json::Value, with its own copy
+    /// of the chunk, against send_data_to_player(), which shares the chunk
+    /// JSON between the players and copies it into each frame as raw text.
+    /// Run with:
+    /// cargo test --release bench_send_frames -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_send_frames() {
+        let mut game = RustonatorGame::new(101, 101);
+        let mut clients = Vec::new();
+        for i in 0..10 {
+            let id = PlayerId::from(i + 1);
+            let client = add_player_at(&mut game, id, MapPosition::new(45 + i as i32, 51));
+            clients.push((id, client));
+        }
+        game.world
+            .index_entities(&game.players, &game.mobs, &game.bombs, &game.explosions);
+        let size = *game.world.sizes().chunk_size();
+        let frames = 1000;
+
+        let start = Instant::now();
+        let mut bytes = 0;
+        for _ in 0..frames {
+            for player in game.players.values() {
+                let pos = player.map_position(&game.world);
+                let chunk = serde_json::to_value(game.world.get_chunk_data(pos, size)).unwrap();
+                let frame = json!({
+                    "code": "FRAMEDATA",
+                    "data": {
+                        "tick": game.tick,
+                        "player": player,
+                        "world": chunk,
+                        "players": game.players.values().collect::<Vec<&Player>>(),
+                        "mobs": [],
+                        "bombs": [],
+                        "explosions": [],
+                    }
+                });
+                bytes += frame.to_string().len();
+            }
+        }
+        let values = start.elapsed();
+
+        let start = Instant::now();
+        let mut raw_bytes = 0;
+        for _ in 0..frames {
+            game.chunk_cache.clear();
+            for (id, client) in clients.iter_mut() {
+                let mut player = game.players.remove(id).unwrap();
+                futures::executor::block_on(game.send_data_to_player(&mut player)).unwrap();
+                game.players.insert(*id, player);
+                let message = client.try_recv().unwrap();
+                raw_bytes += serde_json::to_string(&message).unwrap().len();
+            }
+        }
+        let raw = start.elapsed();
+
+        println!(
+            "10 players, {} frames: {:?} ({} bytes) as Values, {:?} ({} bytes) shared",
+            frames, values, bytes, raw, raw_bytes
+        );
+    }
+
+    #[test]
+    fn test_larger_view_gets_larger_chunk() {
+        let mut game = RustonatorGame::new(101, 101);
+        let default_size = *game.world.sizes().chunk_size();
+        let max_size = game.config.max_view_size();
+        let as_tuple = |size: SizeInTiles| (Some(size.width as i64), Some(size.height as i64));
+
+        assert_eq!(sent_chunk_size(&mut game, None), as_tuple(default_size));
+        let larger = SizeInTiles::new(default_size.width + 10, default_size.height + 6);
+        assert_eq!(sent_chunk_size(&mut game, Some(larger)), as_tuple(larger));
+
+        // Requests are limited to the configured maximum.
+        let huge = SizeInTiles::new(500, 500);
+        assert_eq!(sent_chunk_size(&mut game, Some(huge)), as_tuple(max_size));
+    }
+
+    #[test]
+    fn test_world_deltas_only_for_partial_world_clients() {
+        let mut config = GameConfig::new();
+        config.set_world_delta_updates(true);
+        let mut game = RustonatorGame::with_config(101, 101, config);
+        let mut clients = Vec::new();
+        for (i, partial) in [false, true].iter().enumerate() {
+            let id = PlayerId::from(i as u64 + 1);
+            let (mut player, client) = PlayerBuilder::new(id).build_local();
+            player.set_partial_world(*partial);
+            player.set_position(PixelPositionF64::from_map_position(
+                MapPosition::new(50, 51),
+                &game.world,
+            ));
+            game.players.insert(id, player);
+            clients.push((id, client));
+        }
+
+        for frame in 0..3 {
+            if frame == 2 {
+                game.world
+                    .set_cell(MapPosition::new(51, 51), CellType::Bomb);
+            }
+            for (id, client) in clients.iter_mut() {
+                let mut player = game.players.remove(id).unwrap();
+                futures::executor::block_on(game.send_data_to_player(&mut player)).unwrap();
+                let partial = player.partial_world();
+                game.players.insert(*id, player);
+
+                let messages = received_codes(client);
+                let codes: Vec<&serde_json::Value> = messages.iter().map(|m| &m["code"]).collect();
+                let sent_world = !messages.last().unwrap()["data"]["world"].is_null();
+                if !partial || frame == 0 {
+                    // The whole chunk, every frame.
+                    assert_eq!(codes, vec!["FRAMEDATA"]);
+                    assert!(sent_world);
+                } else if frame == 1 {
+                    // Nothing has changed.
+                    assert_eq!(codes, vec!["FRAMEDATA"]);
+                    assert!(!sent_world);
+                } else {
+                    assert_eq!(codes, vec!["WORLDDELTA", "FRAMEDATA"]);
+                    assert!(!sent_world);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sudden_death_crushes_mobs() {
+        let mut config = GameConfig::new();
+        config.set_sudden_death_after(10.0);
+        let mut game = RustonatorGame::with_config(15, 15, config);
+        let pos = MapPosition::new(1, 3);
+        game.world.set_cell(pos, CellType::Empty);
+        add_mob_at(&mut game, pos);
+
+        game.clock.advance(9.0);
+        game.game_process_sudden_death();
+        assert_eq!(game.world.get_cell(pos), Some(CellType::Empty));
+
+        game.clock.advance(1.0);
+        game.game_process_sudden_death();
+        assert_eq!(game.world.get_cell(pos), Some(CellType::Wall));
+        assert!(game.mobs.iter().all(|m| !m.is_active()));
+        assert!(!game.explosions.is_empty());
+    }
+
+    fn advance_match(game: &mut RustonatorGame, seconds: f64) -> Option<MatchState> {
+        game.clock.advance(seconds);
+        futures::executor::block_on(game.game_process_match());
+        game.match_state()
+    }
+
+    fn received_codes(client: &mut LocalClient) -> Vec<serde_json::Value> {
+        let mut messages = Vec::new();
+        while let Some(message) = client.try_recv() {
+            messages.push(serde_json::to_value(&message).unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn test_timed_round_returns_to_warmup() {
+        let mut config = GameConfig::new();
+        config.set_round_time(60.0);
+        config.set_warmup_time(5.0);
+        config.set_round_end_time(3.0);
+        let mut game = RustonatorGame::with_config(15, 15, config);
+        let mut clients = Vec::new();
+        for i in 1..=2 {
+            let id = PlayerId::from(i);
+            let (mut player, client) = PlayerBuilder::new(id).build_local();
+            player.reset_for_round(&game.world, game.config.effects());
+            game.players.insert(id, player);
+            clients.push(client);
+        }
+        assert_eq!(game.match_state(), Some(MatchState::Warmup));
+
+        // Warmup points don't count.
+        for p in game.players.values_mut() {
+            p.increase_score(50);
+        }
+        assert_eq!(advance_match(&mut game, 5.0), Some(MatchState::Active));
+        assert!(game.players.values().all(|p| p.score() == 0));
+
+        let winner = PlayerId::from(2);
+        if let Some(p) = game.players.get_mut(&winner) {
+            p.increase_score(100);
+        }
+        assert_eq!(advance_match(&mut game, 59.0), Some(MatchState::Active));
+        assert_eq!(advance_match(&mut game, 1.0), Some(MatchState::Ended));
+        for client in clients.iter_mut() {
+            let messages = received_codes(client);
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0]["code"], "MATCHENDED");
+            assert_eq!(messages[0]["data"]["winner"], 2);
+            assert_eq!(messages[0]["data"]["scores"][0]["score"], 100);
+        }
+
+        assert_eq!(advance_match(&mut game, 3.0), Some(MatchState::Warmup));
+        assert_eq!(game.players.len(), 2);
+        assert!(game
+            .players
+            .values()
+            .all(|p| p.is_active() && p.score() == 0));
+        for client in clients.iter_mut() {
+            let messages = received_codes(client);
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0]["code"], "SPAWNPLAYER");
+        }
+    }
+}
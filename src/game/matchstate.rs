@@ -0,0 +1,103 @@
+use crate::engine::config::GameConfig;
+use serde::Serialize;
+
+/// The phases of a round-based match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MatchState {
+    Warmup,
+    Active,
+    Ended,
+}
+
+/// Moves a match through warmup, the round itself and the results, then
+/// back to warmup for the next round.
+#[derive(Debug, Clone)]
+pub struct MatchRound {
+    state: MatchState,
+    // The elapsed game time when the current state began.
+    state_started: f64,
+    round_time: Option<f64>,
+    last_player_standing: bool,
+    warmup_time: f64,
+    round_end_time: f64,
+    // The number of players alive when the round started.
+    contenders: usize,
+}
+
+impl MatchRound {
+    pub fn new(config: &GameConfig) -> Self {
+        MatchRound {
+            state: MatchState::Warmup,
+            state_started: 0.0,
+            round_time: config.round_time(),
+            last_player_standing: config.last_player_standing(),
+            warmup_time: config.warmup_time(),
+            round_end_time: config.round_end_time(),
+            contenders: 0,
+        }
+    }
+
+    pub fn state(&self) -> MatchState {
+        self.state
+    }
+
+    /// True if the round was won by outlasting everyone else, rather than
+    /// running out of time.
+    pub fn is_last_player_standing(&self, alive: usize) -> bool {
+        self.last_player_standing && self.contenders > 1 && alive <= 1
+    }
+
+    fn set_state(&mut self, state: MatchState, elapsed: f64) -> Option<MatchState> {
+        self.state = state;
+        self.state_started = elapsed;
+        Some(state)
+    }
+
+    /// Get the new state if it has changed, given the number of players
+    /// still alive.
+    pub fn update(&mut self, elapsed: f64, alive: usize) -> Option<MatchState> {
+        let time_in_state = elapsed - self.state_started;
+        match self.state {
+            MatchState::Warmup if time_in_state >= self.warmup_time => {
+                self.contenders = alive;
+                self.set_state(MatchState::Active, elapsed)
+            }
+            MatchState::Active => {
+                let out_of_time = self.round_time.is_some_and(|t| time_in_state >= t);
+                if out_of_time || self.is_last_player_standing(alive) {
+                    self.set_state(MatchState::Ended, elapsed)
+                } else {
+                    None
+                }
+            }
+            MatchState::Ended if time_in_state >= self.round_end_time => {
+                self.contenders = 0;
+                self.set_state(MatchState::Warmup, elapsed)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_player_standing() {
+        let mut config = GameConfig::new();
+        config.set_last_player_standing(true);
+        config.set_warmup_time(5.0);
+        let mut round = MatchRound::new(&config);
+        assert_eq!(round.update(4.0, 3), None);
+        assert_eq!(round.update(5.0, 3), Some(MatchState::Active));
+        assert_eq!(round.update(100.0, 2), None);
+        assert_eq!(round.update(101.0, 1), Some(MatchState::Ended));
+        assert_eq!(round.update(105.0, 1), None);
+        assert_eq!(round.update(106.0, 1), Some(MatchState::Warmup));
+
+        // A round that starts with one player can't be won by outlasting anyone.
+        assert_eq!(round.update(111.0, 1), Some(MatchState::Active));
+        assert_eq!(round.update(200.0, 1), None);
+    }
+}
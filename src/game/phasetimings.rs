@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+/// Accumulates the time spent in each phase of the game loop, so that the
+/// cause of a slow frame can be found.
+#[derive(Debug, Default)]
+pub struct PhaseTimings {
+    // Phases are kept in the order they were first seen.
+    phases: Vec<(&'static str, Duration)>,
+    frames: u32,
+}
+
+impl PhaseTimings {
+    pub fn new() -> Self {
+        PhaseTimings::default()
+    }
+
+    pub fn add(&mut self, phase: &'static str, elapsed: Duration) {
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    /// The average time per frame spent in each phase,
+    /// e.g. "mobs: 4.20ms, net: 1.10ms".
+    pub fn summary(&self) -> String {
+        let frames = self.frames.max(1) as f64;
+        self.phases
+            .iter()
+            .map(|(name, total)| {
+                format!("{}: {:.2}ms", name, total.as_secs_f64() * 1000.0 / frames)
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    pub fn clear(&mut self) {
+        self.phases.clear();
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut timings = PhaseTimings::new();
+        for _ in 0..4 {
+            timings.add("mobs", Duration::from_millis(4));
+            timings.add("players", Duration::from_micros(500));
+            timings.add("mobs", Duration::from_millis(1));
+            timings.end_frame();
+        }
+        assert_eq!(timings.summary(), "mobs: 5.00ms, players: 0.50ms");
+
+        timings.clear();
+        assert_eq!(timings.summary(), "");
+    }
+}
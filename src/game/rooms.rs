@@ -0,0 +1,359 @@
+use crate::{
+    comms::{
+        admin::{AdminCommand, RoomCommand},
+        metrics::RoomMetrics,
+        playercomm::{PlayerComm, PlayerConnectEvent, PlayerMessage},
+    },
+    engine::{config::GameConfig, player::PlayerId},
+    game::maingame::RustonatorGame,
+};
+use log::{error, info};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// The room that players join if they don't ask for another one.
+pub const DEFAULT_ROOM: &str = "default";
+
+/// The longest room name allowed. Longer names are cut short.
+const MAX_ROOM_NAME_LENGTH: usize = 30;
+
+/// How often to check whether players who have connected have asked to join
+/// a room yet.
+const ROUTE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A game that hasn't been started yet, along with the other ends of its
+/// channels.
+struct RoomGame {
+    game: RustonatorGame,
+    join_rx: Receiver<PlayerConnectEvent>,
+    admin_rx: Receiver<AdminCommand>,
+}
+
+/// A game and the channels used to talk to it. Dropping the room closes the
+/// channels, which stops the game.
+struct Room {
+    join_tx: Sender<PlayerConnectEvent>,
+    admin_tx: Sender<AdminCommand>,
+    // The game, until the room is started.
+    game: Option<RoomGame>,
+}
+
+impl Room {
+    fn new(game: RustonatorGame) -> Self {
+        let (join_tx, join_rx) = channel(30);
+        let (admin_tx, admin_rx) = channel(10);
+        Room {
+            join_tx,
+            admin_tx,
+            game: Some(RoomGame {
+                game,
+                join_rx,
+                admin_rx,
+            }),
+        }
+    }
+
+    /// Run the game as a task of its own, if it isn't already running.
+    fn start(&mut self, name: &str) {
+        if let Some(mut room) = self.game.take() {
+            let name = name.to_string();
+            tokio::spawn(async move {
+                if let Err(e) = room.game.game_loop(room.join_rx, room.admin_rx).await {
+                    error!("Error in room '{}': {:?}", name, e);
+                }
+                info!("Room '{}' closed", name);
+            });
+        }
+    }
+}
+
+/// Runs any number of independent games, and sends each player who connects
+/// to the room they ask for when they join.
+pub struct RoomManager {
+    config: GameConfig,
+    rooms: HashMap<String, Room>,
+    metrics: Arc<RoomMetrics>,
+    admin_tx: Sender<RoomCommand>,
+    admin_rx: Receiver<RoomCommand>,
+    // Players who have connected but haven't said which room they want yet.
+    pending: Vec<PlayerComm>,
+    // The room each player was sent to.
+    player_rooms: HashMap<PlayerId, String>,
+}
+
+impl RoomManager {
+    /// Create the manager along with the default room. Rooms are started by
+    /// run().
+    pub fn new(config: GameConfig) -> Self {
+        let (admin_tx, admin_rx) = channel(10);
+        let mut manager = RoomManager {
+            config,
+            rooms: HashMap::new(),
+            metrics: Arc::new(RoomMetrics::new()),
+            admin_tx,
+            admin_rx,
+            pending: Vec::new(),
+            player_rooms: HashMap::new(),
+        };
+        let game = RustonatorGame::with_config(47, 47, manager.config.clone());
+        manager.add_room(DEFAULT_ROOM, game);
+        manager
+    }
+
+    fn add_room(&mut self, name: &str, game: RustonatorGame) {
+        self.metrics.insert(name, game.metrics());
+        self.rooms.insert(name.to_string(), Room::new(game));
+    }
+
+    pub fn room_count(&self) -> usize {
+        self.rooms.len()
+    }
+
+    /// The room the player was sent to, if they have joined one.
+    pub fn room_of(&self, pid: PlayerId) -> Option<&str> {
+        self.player_rooms.get(&pid).map(|room| room.as_str())
+    }
+
+    /// Admin commands, for any room.
+    pub fn admin_sender(&self) -> Sender<RoomCommand> {
+        self.admin_tx.clone()
+    }
+
+    /// Metrics for every room.
+    pub fn metrics(&self) -> Arc<RoomMetrics> {
+        self.metrics.clone()
+    }
+
+    pub async fn run(&mut self, mut players_rx: Receiver<PlayerConnectEvent>) {
+        loop {
+            self.start_rooms();
+            // Keep checking on anyone who hasn't picked a room yet, even if
+            // nobody new connects.
+            match tokio::time::timeout(ROUTE_INTERVAL, players_rx.recv()).await {
+                Ok(Some(event)) => self.handle_event(event).await,
+                Ok(None) => break,
+                Err(_) => {}
+            }
+            self.route_pending().await;
+            while let Ok(command) = self.admin_rx.try_recv() {
+                self.handle_admin_command(command).await;
+            }
+        }
+    }
+
+    /// Start any rooms that have been created since the last call.
+    pub fn start_rooms(&mut self) {
+        for (name, room) in self.rooms.iter_mut() {
+            room.start(name);
+        }
+    }
+
+    pub async fn handle_event(&mut self, event: PlayerConnectEvent) {
+        match event {
+            PlayerConnectEvent::Connected(comm) => self.pending.push(comm),
+            PlayerConnectEvent::Disconnected(pid) => match self.player_rooms.remove(&pid) {
+                Some(room_name) => {
+                    let event = PlayerConnectEvent::Disconnected(pid);
+                    if let Some(room) = self.rooms.get_mut(&room_name) {
+                        if room.join_tx.send(event).await.is_err() {
+                            error!("Room '{}' has stopped", room_name);
+                        }
+                    }
+                    self.remove_if_empty(&room_name);
+                }
+                None => self.pending.retain(|comm| comm.id() != pid),
+            },
+        }
+    }
+
+    /// Close a room once everyone has left. The default room is always kept.
+    fn remove_if_empty(&mut self, name: &str) {
+        if name == DEFAULT_ROOM || self.player_rooms.values().any(|room| room == name) {
+            return;
+        }
+
+        if self.rooms.remove(name).is_some() {
+            info!("Closing empty room '{}'", name);
+            self.metrics.remove(name);
+        }
+    }
+
+    pub async fn handle_admin_command(&mut self, command: RoomCommand) {
+        let name = command.room().unwrap_or(DEFAULT_ROOM).to_string();
+        match self.rooms.get_mut(&name) {
+            Some(room) => {
+                if room.admin_tx.send(command.into_command()).await.is_err() {
+                    error!("Room '{}' has stopped", name);
+                }
+            }
+            None => error!("No room '{}' for admin command {:?}", name, command),
+        }
+    }
+
+    /// Send each player who has asked to join a room to that room. The join
+    /// request is passed on for the room to deal with as normal.
+    pub async fn route_pending(&mut self) {
+        for mut comm in std::mem::take(&mut self.pending) {
+            match comm.recv_one().await {
+                Ok(None) => self.pending.push(comm),
+                Ok(Some(PlayerMessage::JoinGame(join))) => match self.choose_room(join.room()) {
+                    Some(room) => {
+                        comm.requeue(PlayerMessage::JoinGame(join));
+                        self.send_to_room(&room, comm).await;
+                    }
+                    None => {
+                        let message = PlayerMessage::JoinError(String::from(
+                            "There is no room for another game. Please join an existing one",
+                        ));
+                        if comm.send(message).await.is_ok() {
+                            self.pending.push(comm);
+                        }
+                    }
+                },
+                Ok(Some(message)) => {
+                    // Let the game deal with it, as it always has.
+                    comm.requeue(message);
+                    self.send_to_room(DEFAULT_ROOM, comm).await;
+                }
+                Err(e) => {
+                    info!("Player {:?} left before joining a room: {:?}", comm.id(), e);
+                }
+            }
+        }
+    }
+
+    /// Get the name of the room to join, creating it if necessary. Returns
+    /// None if it doesn't exist and no more rooms can be created.
+    fn choose_room(&mut self, requested: Option<&str>) -> Option<String> {
+        let name: String = match requested.map(str::trim) {
+            Some(name) if !name.is_empty() && self.config.max_rooms() > 1 => {
+                name.chars().take(MAX_ROOM_NAME_LENGTH).collect()
+            }
+            _ => return Some(DEFAULT_ROOM.to_string()),
+        };
+
+        if !self.rooms.contains_key(&name) {
+            if self.rooms.len() >= self.config.max_rooms() {
+                return None;
+            }
+
+            info!("Creating room '{}'", name);
+            let game = RustonatorGame::with_config(47, 47, self.config.for_extra_room());
+            self.add_room(&name, game);
+        }
+        Some(name)
+    }
+
+    async fn send_to_room(&mut self, name: &str, comm: PlayerComm) {
+        let pid = comm.id();
+        if let Some(room) = self.rooms.get_mut(name) {
+            match room.join_tx.send(PlayerConnectEvent::Connected(comm)).await {
+                Ok(()) => {
+                    self.player_rooms.insert(pid, name.to_string());
+                }
+                Err(_) => error!("Room '{}' has stopped, dropping {:?}", name, pid),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comms::playercomm::LocalClient;
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    fn join(client: &mut LocalClient, name: &str, room: &str) {
+        let request = serde_json::from_value(json!({ "name": name, "room": room })).unwrap();
+        block_on(client.send(PlayerMessage::JoinGame(request))).unwrap();
+    }
+
+    /// Take a room's game out of the manager so that the test can run it.
+    fn take_game(manager: &mut RoomManager, name: &str) -> RoomGame {
+        manager.rooms.get_mut(name).unwrap().game.take().unwrap()
+    }
+
+    /// Apply everything sent to the room so far, without running a frame.
+    fn update_room(room: &mut RoomGame) {
+        // Each call applies at most one event.
+        for _ in 0..10 {
+            block_on(room.game.player_connect_events(&mut room.join_rx));
+        }
+        while let Ok(command) = room.admin_rx.try_recv() {
+            block_on(room.game.handle_admin_command(command));
+        }
+        block_on(room.game.process_player_inputs(1.0 / 30.0));
+    }
+
+    /// Apply the next event, if any. Returns false once the room has closed.
+    fn still_open(room: &mut RoomGame) -> bool {
+        block_on(room.game.player_connect_events(&mut room.join_rx))
+    }
+
+    fn player_names(room: &RoomGame) -> Vec<String> {
+        let mut names: Vec<String> = room
+            .game
+            .snapshot()
+            .players
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_rooms_are_independent() {
+        let mut config = GameConfig::new();
+        config.set_max_rooms(3);
+        let mut manager = RoomManager::new(config);
+
+        let mut clients = Vec::new();
+        for (i, (name, room)) in [("alice", "a"), ("bob", "b"), ("carol", "a"), ("dave", "c")]
+            .iter()
+            .enumerate()
+        {
+            let (comm, mut client) = PlayerComm::local(PlayerId::from(i as u64 + 1));
+            join(&mut client, name, room);
+            block_on(manager.handle_event(PlayerConnectEvent::Connected(comm)));
+            clients.push(client);
+        }
+        block_on(manager.route_pending());
+
+        assert_eq!(manager.room_of(PlayerId::from(1)), Some("a"));
+        assert_eq!(manager.room_of(PlayerId::from(2)), Some("b"));
+        assert_eq!(manager.room_of(PlayerId::from(3)), Some("a"));
+        // There's no room for a fourth game.
+        assert_eq!(manager.room_of(PlayerId::from(4)), None);
+        assert_eq!(manager.room_count(), 3);
+        assert!(matches!(
+            clients[3].try_recv(),
+            Some(PlayerMessage::JoinError(_))
+        ));
+
+        // Admin commands only reach the room they are for.
+        let command = "room a spawn_mob".parse().unwrap();
+        block_on(manager.handle_admin_command(command));
+
+        let mut room_a = take_game(&mut manager, "a");
+        let mut room_b = take_game(&mut manager, "b");
+        update_room(&mut room_a);
+        update_room(&mut room_b);
+        assert_eq!(player_names(&room_a), vec!["alice", "carol"]);
+        assert_eq!(player_names(&room_b), vec!["bob"]);
+        assert_eq!(room_a.game.snapshot().mobs.len(), 1);
+        assert!(room_b.game.snapshot().mobs.is_empty());
+        assert!(manager.metrics().get("b").is_some());
+
+        // Once everyone has left a room, it is closed and its game stops.
+        block_on(manager.handle_event(PlayerConnectEvent::Disconnected(PlayerId::from(2))));
+        assert_eq!(manager.room_count(), 2);
+        assert!(manager.metrics().get("b").is_none());
+        assert!(manager.metrics().get(DEFAULT_ROOM).is_some());
+        // Room b gets the disconnect and then stops.
+        assert!(still_open(&mut room_b));
+        assert!(!still_open(&mut room_b));
+        assert!(still_open(&mut room_a));
+    }
+}
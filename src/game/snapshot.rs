@@ -0,0 +1,47 @@
+use crate::engine::{
+    bomb::BombId,
+    explosion::ExplosionId,
+    mob::{MobDebugState, MobId},
+    player::PlayerId,
+    position::{MapPosition, PixelPositionF64},
+};
+use serde::Serialize;
+
+/// A read-only copy of the state of a game, for use by spectators and other
+/// external tools.
+#[derive(Debug, Clone, Serialize)]
+pub struct GameSnapshot {
+    pub players: Vec<PlayerSnapshot>,
+    pub mobs: Vec<MobSnapshot>,
+    pub bombs: Vec<BombSnapshot>,
+    pub explosions: Vec<ExplosionSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerSnapshot {
+    pub id: PlayerId,
+    pub name: String,
+    pub score: u32,
+    pub position: PixelPositionF64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MobSnapshot {
+    pub id: MobId,
+    pub position: PixelPositionF64,
+    // Only included in debug snapshots.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug: Option<MobDebugState>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BombSnapshot {
+    pub id: BombId,
+    pub position: MapPosition,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExplosionSnapshot {
+    pub id: ExplosionId,
+    pub position: MapPosition,
+}
@@ -0,0 +1,290 @@
+use crate::error::ZResult;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "sqlite")]
+use std::collections::HashSet;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Cumulative stats for a single player across every game they have played.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStats {
+    kills: u32,
+    deaths: u32,
+    best_score: u32,
+    games_played: u32,
+}
+
+impl PlayerStats {
+    pub fn kills(&self) -> u32 {
+        self.kills
+    }
+
+    pub fn deaths(&self) -> u32 {
+        self.deaths
+    }
+
+    pub fn best_score(&self) -> u32 {
+        self.best_score
+    }
+
+    pub fn games_played(&self) -> u32 {
+        self.games_played
+    }
+}
+
+/// Persistent player stats, keyed by player name. Changes are kept in memory
+/// and only written out when flush() is called, so that the game loop never
+/// waits on storage for each kill.
+pub trait StatsStore: Send {
+    fn get(&self, name: &str) -> Option<&PlayerStats>;
+
+    /// Get the stats for the specified player, creating them if necessary.
+    fn get_mut(&mut self, name: &str) -> &mut PlayerStats;
+
+    /// Write any changes since the last flush.
+    fn flush(&mut self) -> ZResult<()>;
+
+    fn record_kill(&mut self, name: &str) {
+        self.get_mut(name).kills += 1;
+    }
+
+    fn record_death(&mut self, name: &str) {
+        self.get_mut(name).deaths += 1;
+    }
+
+    /// Record the end of a game with the player's final score.
+    fn record_game(&mut self, name: &str, score: u32) {
+        let stats = self.get_mut(name);
+        stats.games_played += 1;
+        stats.best_score = stats.best_score.max(score);
+    }
+}
+
+/// Stores stats for all players in a single JSON file.
+#[derive(Debug)]
+pub struct JsonStatsStore {
+    path: PathBuf,
+    stats: HashMap<String, PlayerStats>,
+    dirty: bool,
+}
+
+impl JsonStatsStore {
+    /// Load stats from the specified file. A missing file is treated as empty.
+    pub fn open<P: AsRef<Path>>(path: P) -> ZResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let stats = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(JsonStatsStore {
+            path,
+            stats,
+            dirty: false,
+        })
+    }
+}
+
+impl StatsStore for JsonStatsStore {
+    fn get(&self, name: &str) -> Option<&PlayerStats> {
+        self.stats.get(name)
+    }
+
+    fn get_mut(&mut self, name: &str) -> &mut PlayerStats {
+        self.dirty = true;
+        self.stats.entry(name.to_string()).or_default()
+    }
+
+    fn flush(&mut self) -> ZResult<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        // Write to a temporary file first so that a crash can't leave the
+        // stats half written.
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string(&self.stats)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Stores stats in an SQLite database, one row per player. Only the players
+/// that changed since the last flush are written.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStatsStore {
+    conn: rusqlite::Connection,
+    stats: HashMap<String, PlayerStats>,
+    dirty: HashSet<String>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStatsStore {
+    /// Open the specified database, creating it if necessary.
+    pub fn open<P: AsRef<Path>>(path: P) -> ZResult<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS player_stats (
+                name TEXT PRIMARY KEY,
+                kills INTEGER NOT NULL,
+                deaths INTEGER NOT NULL,
+                best_score INTEGER NOT NULL,
+                games_played INTEGER NOT NULL
+            )",
+        )?;
+
+        let mut stats = HashMap::new();
+        {
+            let mut statement = conn.prepare(
+                "SELECT name, kills, deaths, best_score, games_played FROM player_stats",
+            )?;
+            let mut rows = statement.query(rusqlite::NO_PARAMS)?;
+            while let Some(row) = rows.next()? {
+                let player_stats = PlayerStats {
+                    kills: row.get(1)?,
+                    deaths: row.get(2)?,
+                    best_score: row.get(3)?,
+                    games_played: row.get(4)?,
+                };
+                stats.insert(row.get(0)?, player_stats);
+            }
+        }
+
+        Ok(SqliteStatsStore {
+            conn,
+            stats,
+            dirty: HashSet::new(),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl StatsStore for SqliteStatsStore {
+    fn get(&self, name: &str) -> Option<&PlayerStats> {
+        self.stats.get(name)
+    }
+
+    fn get_mut(&mut self, name: &str) -> &mut PlayerStats {
+        if !self.dirty.contains(name) {
+            self.dirty.insert(name.to_string());
+        }
+        self.stats.entry(name.to_string()).or_default()
+    }
+
+    fn flush(&mut self) -> ZResult<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+
+        let tx = self.conn.transaction()?;
+        {
+            let mut statement = tx.prepare(
+                "INSERT OR REPLACE INTO player_stats
+                    (name, kills, deaths, best_score, games_played)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for name in self.dirty.iter() {
+                let stats = &self.stats[name];
+                statement.execute(rusqlite::params![
+                    name,
+                    stats.kills,
+                    stats.deaths,
+                    stats.best_score,
+                    stats.games_played
+                ])?;
+            }
+        }
+        tx.commit()?;
+        self.dirty.clear();
+        Ok(())
+    }
+}
+
+/// Open the stats store for the specified file. Files ending in .db or
+/// .sqlite are SQLite databases, which need the "sqlite" feature. Anything
+/// else is JSON.
+pub fn open_stats_store(path: &str) -> ZResult<Box<dyn StatsStore>> {
+    let extension = Path::new(path).extension().and_then(|x| x.to_str());
+    if let Some("db") | Some("sqlite") = extension {
+        #[cfg(feature = "sqlite")]
+        return Ok(Box::new(SqliteStatsStore::open(path)?));
+        #[cfg(not(feature = "sqlite"))]
+        return Err(crate::error::ZError::FatalError(String::from(
+            "SQLite stats need the \"sqlite\" feature",
+        )));
+    }
+
+    Ok(Box::new(JsonStatsStore::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_stats_survive_reload() {
+        let path = env::temp_dir().join(format!("rustonator_stats_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = JsonStatsStore::open(&path).unwrap();
+        assert!(store.get("steve").is_none());
+        store.record_kill("steve");
+        store.record_game("steve", 1500);
+        store.record_game("steve", 700);
+        store.flush().unwrap();
+
+        // Changes are only written when flushed.
+        store.record_death("steve");
+        let store = JsonStatsStore::open(&path).unwrap();
+        let stats = store.get("steve").unwrap();
+        assert_eq!(stats.kills(), 1);
+        assert_eq!(stats.deaths(), 0);
+        assert_eq!(stats.best_score(), 1500);
+        assert_eq!(stats.games_played(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_stats_survive_reload() {
+        let path = env::temp_dir().join(format!("rustonator_stats_{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = SqliteStatsStore::open(&path).unwrap();
+        assert!(store.get("steve").is_none());
+        store.record_kill("steve");
+        store.record_game("steve", 1500);
+        store.record_kill("bob");
+        store.flush().unwrap();
+
+        // Only changed players are written, without losing the others.
+        store.record_game("steve", 700);
+        store.flush().unwrap();
+        store.record_death("steve");
+        let store = SqliteStatsStore::open(&path).unwrap();
+        let stats = store.get("steve").unwrap();
+        assert_eq!(stats.kills(), 1);
+        assert_eq!(stats.deaths(), 0);
+        assert_eq!(stats.best_score(), 1500);
+        assert_eq!(stats.games_played(), 2);
+        assert_eq!(store.get("bob").unwrap().kills(), 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_stats_store_by_extension() {
+        let path = env::temp_dir().join(format!("rustonator_open_{}.db", std::process::id()));
+        let store = open_stats_store(path.to_str().unwrap());
+        assert_eq!(store.is_ok(), cfg!(feature = "sqlite"));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::engine::position::{MapPosition, SizeInTiles};
+use serde::Serialize;
+
+/// The area of the map that hasn't yet been walled in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SafeBounds {
+    top_left: MapPosition,
+    bottom_right: MapPosition,
+}
+
+impl SafeBounds {
+    pub fn top_left(&self) -> MapPosition {
+        self.top_left
+    }
+
+    pub fn bottom_right(&self) -> MapPosition {
+        self.bottom_right
+    }
+}
+
+/// Shrinks the arena once the game has been running for a while, by turning
+/// the outermost ring of open tiles into walls at a regular interval.
+/// Ring 0 is the border, which is always a wall.
+#[derive(Debug, Clone)]
+pub struct SuddenDeath {
+    start_after: f64,
+    interval: f64,
+    map_size: SizeInTiles,
+    rings: i32,
+}
+
+impl SuddenDeath {
+    /// Times are in seconds of simulation time.
+    pub fn new(start_after: f64, interval: f64, map_size: SizeInTiles) -> Self {
+        SuddenDeath {
+            start_after,
+            interval,
+            map_size,
+            rings: 0,
+        }
+    }
+
+    /// The number of rings walled in so far, including the border.
+    pub fn rings(&self) -> i32 {
+        self.rings
+    }
+
+    /// The innermost ring. This is never walled in.
+    fn last_ring(&self) -> i32 {
+        (self.map_size.width.min(self.map_size.height) - 1) / 2
+    }
+
+    /// Get the ring that should be walled in now, if any.
+    pub fn update(&mut self, elapsed: f64) -> Option<i32> {
+        if elapsed < self.start_after || self.rings + 1 >= self.last_ring() {
+            return None;
+        }
+
+        let due = ((elapsed - self.start_after) / self.interval) as i32 + 1;
+        if due > self.rings {
+            self.rings += 1;
+            Some(self.rings)
+        } else {
+            None
+        }
+    }
+
+    /// Get every position in the specified ring.
+    pub fn ring_positions(&self, ring: i32) -> Vec<MapPosition> {
+        let (left, top) = (ring, ring);
+        let right = self.map_size.width - 1 - ring;
+        let bottom = self.map_size.height - 1 - ring;
+        let mut positions = Vec::new();
+        for x in left..=right {
+            positions.push(MapPosition::new(x, top));
+            positions.push(MapPosition::new(x, bottom));
+        }
+        for y in (top + 1)..bottom {
+            positions.push(MapPosition::new(left, y));
+            positions.push(MapPosition::new(right, y));
+        }
+        positions
+    }
+
+    pub fn safe_bounds(&self) -> SafeBounds {
+        let ring = self.rings + 1;
+        SafeBounds {
+            top_left: MapPosition::new(ring, ring),
+            bottom_right: MapPosition::new(
+                self.map_size.width - 1 - ring,
+                self.map_size.height - 1 - ring,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_shrinks_on_schedule() {
+        let mut sudden_death = SuddenDeath::new(60.0, 10.0, SizeInTiles::new(15, 15));
+        assert_eq!(sudden_death.update(59.9), None);
+        assert_eq!(sudden_death.update(60.0), Some(1));
+        assert_eq!(sudden_death.update(65.0), None);
+        assert_eq!(sudden_death.update(70.0), Some(2));
+        assert_eq!(sudden_death.safe_bounds(), SafeBounds {
+            top_left: MapPosition::new(3, 3),
+            bottom_right: MapPosition::new(11, 11),
+        });
+
+        // A long frame only walls in one ring at a time.
+        assert_eq!(sudden_death.update(200.0), Some(3));
+        assert_eq!(sudden_death.update(200.0), Some(4));
+        assert_eq!(sudden_death.update(200.0), Some(5));
+        assert_eq!(sudden_death.update(200.0), Some(6));
+        // The centre tile is always left.
+        assert_eq!(sudden_death.update(1000.0), None);
+        assert_eq!(sudden_death.rings(), 6);
+        let bounds = sudden_death.safe_bounds();
+        assert_eq!(bounds.top_left(), MapPosition::new(7, 7));
+        assert_eq!(bounds.bottom_right(), MapPosition::new(7, 7));
+
+        assert_eq!(sudden_death.ring_positions(6).len(), 8);
+        assert_eq!(sudden_death.ring_positions(1).len(), 48);
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+/// Converts elapsed wall-clock time into a whole number of fixed size
+/// simulation steps, carrying any remainder over to the next frame.
+/// The simulation therefore advances identically regardless of how often
+/// the game loop happens to run.
+#[derive(Debug, Clone)]
+pub struct FixedTimestep {
+    step: Duration,
+    accumulator: Duration,
+}
+
+impl FixedTimestep {
+    pub fn new(step: Duration) -> Self {
+        FixedTimestep {
+            step,
+            accumulator: Duration::from_secs(0),
+        }
+    }
+
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+
+    /// Add the elapsed time and return the number of steps to run.
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        self.accumulator += elapsed;
+        let mut steps = 0;
+        while self.accumulator >= self.step {
+            self.accumulator -= self.step;
+            steps += 1;
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steps_independent_of_frame_rate() {
+        let step = Duration::from_secs_f64(1.0 / 30.0);
+        let frame_times = [
+            vec![Duration::from_secs(2)],
+            vec![Duration::from_millis(10); 200],
+            vec![Duration::from_millis(33); 60]
+                .into_iter()
+                .chain(vec![Duration::from_millis(20)])
+                .collect(),
+            (0..100)
+                .map(|i| Duration::from_millis(if i % 2 == 0 { 5 } else { 35 }))
+                .collect(),
+        ];
+
+        for frames in frame_times.iter() {
+            assert_eq!(frames.iter().sum::<Duration>(), Duration::from_secs(2));
+            let mut timestep = FixedTimestep::new(step);
+            let steps: u32 = frames.iter().map(|x| timestep.advance(*x)).sum();
+            assert_eq!(steps, 60);
+        }
+    }
+
+    #[test]
+    fn test_remainder_carried_over() {
+        let mut timestep = FixedTimestep::new(Duration::from_millis(10));
+        assert_eq!(timestep.advance(Duration::from_millis(7)), 0);
+        assert_eq!(timestep.advance(Duration::from_millis(7)), 1);
+        assert_eq!(timestep.advance(Duration::from_millis(25)), 2);
+        assert_eq!(timestep.advance(Duration::from_millis(1)), 1);
+    }
+}
@@ -1,12 +1,16 @@
 pub mod component {
     pub mod action;
     pub mod effect;
+    pub mod inputbuffer;
+    pub mod powerup;
 }
 pub mod engine {
     pub mod bomb;
     pub mod config;
+    pub mod entityindex;
     pub mod explosion;
     pub mod mob;
+    pub mod pathfinder;
     pub mod player;
     pub mod position;
     pub mod types;
@@ -15,7 +19,9 @@ pub mod engine {
     pub mod worldzone;
 }
 pub mod tools {
+    pub mod indexedset;
     pub mod itemstore;
+    pub mod spatialgrid;
 }
 pub mod traits {
     pub mod celltypes;
@@ -27,22 +33,84 @@ pub mod utils {
 }
 
 pub mod comms {
+    pub mod admin;
+    pub mod metrics;
     pub mod playercomm;
     pub mod websocket;
 }
 pub mod error;
 pub mod game {
+    pub mod events;
+    pub mod headless;
     pub mod maingame;
+    pub mod matchstate;
+    pub mod phasetimings;
+    pub mod rooms;
+    pub mod snapshot;
+    pub mod stats;
+    pub mod suddendeath;
+    pub mod timestep;
 }
 
-use crate::comms::websocket::spawn_websocket_server;
+use crate::comms::{
+    admin::spawn_admin_server,
+    metrics::spawn_metrics_server,
+    websocket::spawn_websocket_server,
+};
 use tokio::sync::mpsc::channel;
 
-use crate::game::maingame::RustonatorGame;
+use crate::{
+    engine::config::GameConfig,
+    error::{ZError, ZResult},
+    game::{maingame::RustonatorGame, rooms::RoomManager},
+};
+
+// Five minutes of game time.
+const BENCHMARK_TICKS: u64 = 30 * 60 * 5;
 
 #[tokio::main]
 async fn main() {
-    init_logging();
+    let mut config = GameConfig::new();
+    if let Ok(level) = std::env::var("RUST_LOG") {
+        match level.parse() {
+            Ok(level) => config.set_log_level(level),
+            Err(e) => eprintln!("Invalid log level '{}': {:?}", level, e),
+        }
+    }
+    if let Ok(path) = std::env::var("RUSTONATOR_LOG_FILE") {
+        config.set_log_file(&path);
+    }
+    if let Ok(port) = std::env::var("RUSTONATOR_METRICS_PORT") {
+        match port.parse() {
+            Ok(port) => config.set_metrics_port(port),
+            Err(e) => eprintln!("Invalid metrics port '{}': {:?}", port, e),
+        }
+    }
+    if let Ok(path) = std::env::var("RUSTONATOR_ADMIN_SOCKET") {
+        config.set_admin_socket(&path);
+    }
+    if let Ok(path) = std::env::var("RUSTONATOR_EVENT_LOG") {
+        config.set_event_log(&path);
+    }
+    if std::env::var("RUSTONATOR_PHASE_TIMINGS").is_ok() {
+        config.set_phase_timings(true);
+    }
+
+    if let Err(e) = init_logging(&config) {
+        eprintln!("Error setting up logging: {:?}", e);
+    }
+
+    if let Ok(bots) = std::env::var("RUSTONATOR_BENCHMARK_BOTS") {
+        match bots.parse() {
+            Ok(bots) => {
+                let mut game = RustonatorGame::with_config(47, 47, config);
+                let stats = game.run_headless(bots, BENCHMARK_TICKS).await;
+                println!("{} bots: {}", bots, stats.summary());
+            }
+            Err(e) => eprintln!("Invalid number of bots '{}': {:?}", bots, e),
+        }
+        return;
+    }
 
     let (player_join_tx, player_join_rx) = channel(30);
 
@@ -51,19 +119,41 @@ async fn main() {
             eprintln!("Websocket error: {:?}", e);
         }
     });
-    let mut game = RustonatorGame::new(47, 47);
-    if let Err(e) = game.game_loop(player_join_rx).await {
-        eprintln!("Error: {:?}", e);
+
+    if let Ok(max_rooms) = std::env::var("RUSTONATOR_MAX_ROOMS") {
+        match max_rooms.parse() {
+            Ok(max_rooms) => config.set_max_rooms(max_rooms),
+            Err(e) => eprintln!("Invalid max rooms '{}': {:?}", max_rooms, e),
+        }
+    }
+
+    let mut rooms = RoomManager::new(config.clone());
+    if let Some(port) = config.metrics_port() {
+        let metrics = rooms.metrics();
+        tokio::spawn(async move {
+            if let Err(e) = spawn_metrics_server(port, metrics).await {
+                eprintln!("Metrics server error: {:?}", e);
+            }
+        });
+    }
+
+    if let Some(path) = config.admin_socket() {
+        let path = path.to_string();
+        let admin_tx = rooms.admin_sender();
+        tokio::spawn(async move {
+            if let Err(e) = spawn_admin_server(&path, admin_tx).await {
+                eprintln!("Admin server error: {:?}", e);
+            }
+        });
     }
-}
 
-fn init_logging() {
-    // let mut log_file = path.clone();
-    // log_file.push("test.log");
+    rooms.run(player_join_rx).await;
+}
 
-    fern::Dispatch::new()
+fn init_logging(config: &GameConfig) -> ZResult<()> {
+    let mut dispatch = fern::Dispatch::new()
         .level(log::LevelFilter::Off)
-        .level_for("rustonator", log::LevelFilter::Debug)
+        .level_for("rustonator", config.log_level())
         .format(|out, message, record| {
             out.finish(format_args!(
                 "{}[{}][{}] {}",
@@ -73,7 +163,13 @@ fn init_logging() {
                 message
             ))
         })
-        .chain(std::io::stdout())
+        .chain(std::io::stdout());
+    if let Some(path) = config.log_file() {
+        dispatch = dispatch.chain(fern::log_file(path)?);
+    }
+
+    // This fails if a logger has already been set.
+    dispatch
         .apply()
-        .expect("Error setting up logging");
+        .map_err(|e| ZError::FatalError(e.to_string()))
 }
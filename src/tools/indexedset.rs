@@ -0,0 +1,105 @@
+use rand::Rng;
+use std::{collections::HashMap, hash::Hash};
+
+/// A set that also supports picking a random element in constant time.
+/// Elements are kept in a Vec, with a map from each element to its index.
+#[derive(Debug, Clone)]
+pub struct IndexedSet<T: Hash + Eq + Copy> {
+    items: Vec<T>,
+    index: HashMap<T, usize>,
+}
+
+impl<T: Hash + Eq + Copy> Default for IndexedSet<T> {
+    fn default() -> Self {
+        IndexedSet {
+            items: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Hash + Eq + Copy> IndexedSet<T> {
+    pub fn new() -> Self {
+        IndexedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.index.contains_key(item)
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.index.clear();
+    }
+
+    pub fn insert(&mut self, item: T) {
+        if !self.index.contains_key(&item) {
+            self.index.insert(item, self.items.len());
+            self.items.push(item);
+        }
+    }
+
+    pub fn remove(&mut self, item: &T) {
+        if let Some(i) = self.index.remove(item) {
+            self.items.swap_remove(i);
+            if let Some(moved) = self.items.get(i) {
+                self.index.insert(*moved, i);
+            }
+        }
+    }
+
+    pub fn choose<R: Rng>(&self, rng: &mut R) -> Option<T> {
+        if self.items.is_empty() {
+            None
+        } else {
+            Some(self.items[rng.gen_range(0, self.items.len())])
+        }
+    }
+
+    /// Iterate over all elements, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_insert_remove() {
+        let mut set = IndexedSet::new();
+        let mut expected = HashSet::new();
+        let mut rng = thread_rng();
+        for _ in 0..1000 {
+            let value: u32 = rng.gen_range(0, 50);
+            if rng.gen() {
+                set.insert(value);
+                expected.insert(value);
+            } else {
+                set.remove(&value);
+                expected.remove(&value);
+            }
+
+            assert_eq!(set.len(), expected.len());
+            assert_eq!(set.iter().copied().collect::<HashSet<u32>>(), expected);
+            if let Some(x) = set.choose(&mut rng) {
+                assert!(expected.contains(&x));
+            }
+        }
+
+        set.clear();
+        assert!(set.is_empty());
+        assert_eq!(set.choose(&mut rng), None);
+    }
+}
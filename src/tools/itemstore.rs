@@ -1,33 +1,61 @@
-use std::{collections::HashMap, fmt::Debug, hash::Hash};
+use std::{collections::BTreeMap, fmt::Debug};
 
 pub trait HasId<I: From<u64>> {
     fn set_id(&mut self, id: I);
 }
 
+/// Items are stored ordered by id. Since ids are allocated incrementally,
+/// iteration order is deterministic and matches insertion order.
 #[derive(Debug)]
-pub struct ItemStore<I: From<u64> + Debug + Hash + Eq, T: HasId<I>> {
-    items: HashMap<I, T>,
+pub struct ItemStore<I: From<u64> + Debug + Ord, T: HasId<I>> {
+    items: BTreeMap<I, T>,
+    // Scratch space for retain_and_collect(), kept to avoid allocating every
+    // frame.
+    removed_ids: Vec<I>,
     next_id: u64,
 }
 
-impl<I: From<u64> + Debug + Hash + Eq, T: HasId<I>> Default for ItemStore<I, T> {
+impl<I: From<u64> + Debug + Ord, T: HasId<I>> Default for ItemStore<I, T> {
     fn default() -> Self {
         ItemStore {
-            items: HashMap::new(),
+            items: BTreeMap::new(),
+            removed_ids: Vec::new(),
             next_id: 1, // NOTE: there is no id 0.
         }
     }
 }
 
-impl<I: Clone + From<u64> + Debug + Hash + Eq, T: HasId<I>> ItemStore<I, T> {
+impl<I: Clone + From<u64> + Debug + Ord, T: HasId<I>> ItemStore<I, T> {
     pub fn new() -> Self {
         ItemStore::default()
     }
 
+    /// Create a store that can remove up to `capacity` items at a time in
+    /// retain_and_collect() without allocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        ItemStore {
+            removed_ids: Vec::with_capacity(capacity),
+            ..ItemStore::default()
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.items.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn contains(&self, id: &I) -> bool {
+        self.items.contains_key(id)
+    }
+
+    /// Remove all items. Ids are not reused.
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
     fn get_next_id(&mut self) -> I {
         let next = self.next_id;
         self.next_id += 1;
@@ -74,4 +102,155 @@ impl<I: Clone + From<u64> + Debug + Hash + Eq, T: HasId<I>> ItemStore<I, T> {
     where F: FnMut(&I, &mut T) -> bool {
         self.items.retain(f);
     }
+
+    /// Same as retain() but the removed items are returned, in id order.
+    /// Each item still knows its own id.
+    pub fn retain_and_collect<F>(&mut self, mut f: F) -> Vec<T>
+    where F: FnMut(&I, &mut T) -> bool {
+        let mut removed_ids = std::mem::take(&mut self.removed_ids);
+        for (id, item) in self.items.iter_mut() {
+            if !f(id, item) {
+                removed_ids.push(id.clone());
+            }
+        }
+
+        let items = &mut self.items;
+        let removed = removed_ids
+            .drain(..)
+            .filter_map(|id| items.remove(&id))
+            .collect();
+        self.removed_ids = removed_ids;
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    struct TestId(u64);
+
+    impl From<u64> for TestId {
+        fn from(value: u64) -> Self {
+            TestId(value)
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct TestItem {
+        id: u64,
+    }
+
+    impl HasId<TestId> for TestItem {
+        fn set_id(&mut self, id: TestId) {
+            self.id = id.0;
+        }
+    }
+
+    #[test]
+    fn test_add_destroy_len() {
+        let mut store: ItemStore<TestId, TestItem> = ItemStore::new();
+        assert!(store.is_empty());
+        assert_eq!(store.len(), 0);
+
+        let id1 = store.add(TestItem::default());
+        let id2 = store.add(TestItem::default());
+        assert!(!store.is_empty());
+        assert_eq!(store.len(), 2);
+        assert!(store.contains(&id1));
+        assert!(store.contains(&id2));
+        assert_eq!(store.get(id2).map(|x| x.id), Some(2));
+
+        store.destroy(id1);
+        assert_eq!(store.len(), 1);
+        assert!(!store.contains(&id1));
+        assert!(store.contains(&id2));
+
+        // Destroying twice is harmless.
+        store.destroy(id1);
+        assert_eq!(store.len(), 1);
+
+        store.destroy(id2);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_iteration_order() {
+        let mut store: ItemStore<TestId, TestItem> = ItemStore::new();
+        let ids: Vec<TestId> = (0..50).map(|_| store.add(TestItem::default())).collect();
+        for id in ids.iter().step_by(3) {
+            store.destroy(*id);
+        }
+
+        let expected: Vec<u64> = ids
+            .iter()
+            .filter(|id| store.contains(id))
+            .map(|id| id.0)
+            .collect();
+        let actual: Vec<u64> = store.iter().map(|x| x.id).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_retain_and_collect() {
+        let mut store: ItemStore<TestId, TestItem> = ItemStore::with_capacity(10);
+        for _ in 0..10 {
+            store.add(TestItem::default());
+        }
+
+        let removed = store.retain_and_collect(|id, _| id.0 % 2 == 0);
+        let removed_ids: Vec<u64> = removed.iter().map(|x| x.id).collect();
+        assert_eq!(removed_ids, vec![1, 3, 5, 7, 9]);
+
+        let kept_ids: Vec<u64> = store.iter().map(|x| x.id).collect();
+        assert_eq!(kept_ids, vec![2, 4, 6, 8, 10]);
+
+        // Subsequent adds and lookups still work.
+        let id = store.add(TestItem::default());
+        assert_eq!(store.get(id).map(|x| x.id), Some(11));
+        assert!(store.retain_and_collect(|_, _| true).is_empty());
+        assert_eq!(store.len(), 6);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_retain_and_collect() {
+        // 1000 bombs, of which a tenth go off every frame and are replaced.
+        let frames = 10000;
+        let mut store: ItemStore<TestId, TestItem> = ItemStore::with_capacity(100);
+        for _ in 0..1000 {
+            store.add(TestItem::default());
+        }
+
+        // The separate scan for exploding bombs, followed by removing them.
+        let start = Instant::now();
+        for frame in 0..frames {
+            let exploded: Vec<TestId> = store
+                .iter()
+                .filter(|x| x.id % 10 == frame % 10)
+                .map(|x| TestId(x.id))
+                .collect();
+            for id in exploded {
+                store.destroy(id);
+                store.add(TestItem::default());
+            }
+        }
+        let scan = start.elapsed();
+
+        let start = Instant::now();
+        for frame in 0..frames {
+            let exploded = store.retain_and_collect(|id, _| id.0 % 10 != frame % 10);
+            for _ in exploded {
+                store.add(TestItem::default());
+            }
+        }
+        let collect = start.elapsed();
+
+        println!(
+            "1000 items, {} frames: {:?} scan then destroy, {:?} retain_and_collect",
+            frames, scan, collect
+        );
+    }
 }
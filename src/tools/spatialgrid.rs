@@ -0,0 +1,165 @@
+use crate::engine::position::{MapPosition, SizeInTiles};
+
+/// Buckets keys by map position so that everything within a region can be
+/// found by looking at a handful of buckets rather than every key.
+/// Positions outside the map are clamped into the edge buckets.
+#[derive(Debug, Clone)]
+pub struct SpatialGrid<K> {
+    bucket_size: SizeInTiles,
+    buckets_across: i32,
+    buckets_down: i32,
+    buckets: Vec<Vec<(MapPosition, K)>>,
+}
+
+impl<K: Copy> SpatialGrid<K> {
+    pub fn new(bucket_size: SizeInTiles, map_size: SizeInTiles) -> Self {
+        let buckets_across = ((map_size.width + bucket_size.width - 1) / bucket_size.width).max(1);
+        let buckets_down = ((map_size.height + bucket_size.height - 1) / bucket_size.height).max(1);
+        SpatialGrid {
+            bucket_size,
+            buckets_across,
+            buckets_down,
+            buckets: vec![Vec::new(); (buckets_across * buckets_down) as usize],
+        }
+    }
+
+    fn bucket_xy(&self, pos: MapPosition) -> (i32, i32) {
+        let x = pos.x.div_euclid(self.bucket_size.width);
+        let y = pos.y.div_euclid(self.bucket_size.height);
+        (
+            x.max(0).min(self.buckets_across - 1),
+            y.max(0).min(self.buckets_down - 1),
+        )
+    }
+
+    /// Remove all keys but keep the allocated buckets.
+    pub fn clear(&mut self) {
+        for bucket in &mut self.buckets {
+            bucket.clear();
+        }
+    }
+
+    pub fn insert(&mut self, pos: MapPosition, key: K) {
+        let (x, y) = self.bucket_xy(pos);
+        self.buckets[(y * self.buckets_across + x) as usize].push((pos, key));
+    }
+
+    /// Iterate over every key within the specified grid around centre, using
+    /// the same rules as MapPosition::is_within_grid().
+    pub fn query_region(
+        &self,
+        centre: MapPosition,
+        xrange: i32,
+        yrange: i32,
+    ) -> impl Iterator<Item = K> + '_
+    {
+        let (x1, y1) = self.bucket_xy(MapPosition::new(
+            centre.x - xrange + 1,
+            centre.y - yrange + 1,
+        ));
+        let (x2, y2) = self.bucket_xy(MapPosition::new(
+            centre.x + xrange - 1,
+            centre.y + yrange - 1,
+        ));
+        let across = self.buckets_across;
+        (y1..=y2)
+            .flat_map(move |y| (x1..=x2).map(move |x| (y * across + x) as usize))
+            .flat_map(move |index| self.buckets[index].iter())
+            .filter(move |(pos, _)| pos.is_within_grid(centre, xrange, yrange))
+            .map(|(_, key)| *key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+    use std::time::Instant;
+
+    fn random_positions(count: usize, width: i32, height: i32) -> Vec<MapPosition> {
+        let mut rng = thread_rng();
+        (0..count)
+            .map(|_| MapPosition::new(rng.gen_range(0, width), rng.gen_range(0, height)))
+            .collect()
+    }
+
+    fn brute_force(
+        positions: &[MapPosition],
+        centre: MapPosition,
+        xrange: i32,
+        yrange: i32,
+    ) -> Vec<usize>
+    {
+        positions
+            .iter()
+            .enumerate()
+            .filter(|(_, pos)| pos.is_within_grid(centre, xrange, yrange))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    #[test]
+    fn test_query_region_matches_brute_force() {
+        let map_size = SizeInTiles::new(101, 77);
+        let mut grid = SpatialGrid::new(SizeInTiles::new(16, 16), map_size);
+        // Include a few positions off the edge of the map.
+        let mut positions = random_positions(500, map_size.width, map_size.height);
+        positions.push(MapPosition::new(-3, 10));
+        positions.push(MapPosition::new(104, 80));
+        for (index, pos) in positions.iter().enumerate() {
+            grid.insert(*pos, index);
+        }
+
+        for centre in random_positions(200, map_size.width + 10, map_size.height + 10) {
+            let centre = MapPosition::new(centre.x - 5, centre.y - 5);
+            let mut found: Vec<usize> = grid.query_region(centre, 35, 28).collect();
+            found.sort();
+            assert_eq!(found, brute_force(&positions, centre, 35, 28));
+        }
+
+        grid.clear();
+        let centre = MapPosition::new(50, 38);
+        assert_eq!(grid.query_region(centre, 100, 100).count(), 0);
+    }
+
+    /// Compare the grid against a linear scan with 50 players and 500 mobs on
+    /// a full size map. Run with:
+    /// cargo test --release bench_query_region -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_query_region() {
+        let map_size = SizeInTiles::new(201, 201);
+        let players = random_positions(50, map_size.width, map_size.height);
+        let entities = random_positions(550, map_size.width, map_size.height);
+        let mut grid = SpatialGrid::new(SizeInTiles::new(16, 16), map_size);
+        let frames = 1000;
+
+        let start = Instant::now();
+        let mut linear_total = 0;
+        for _ in 0..frames {
+            for player in &players {
+                linear_total += brute_force(&entities, *player, 35, 28).len();
+            }
+        }
+        let linear = start.elapsed();
+
+        let start = Instant::now();
+        let mut grid_total = 0;
+        for _ in 0..frames {
+            grid.clear();
+            for (index, pos) in entities.iter().enumerate() {
+                grid.insert(*pos, index);
+            }
+            for player in &players {
+                grid_total += grid.query_region(*player, 35, 28).count();
+            }
+        }
+        let indexed = start.elapsed();
+
+        assert_eq!(linear_total, grid_total);
+        println!(
+            "{} frames: linear scan {:?}, spatial grid {:?}",
+            frames, linear, indexed
+        );
+    }
+}
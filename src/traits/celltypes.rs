@@ -1,9 +1,13 @@
 use crate::{
     engine::{position::MapPosition, world::World},
-    traits::randenum::RandEnumFrom,
+    error::{ZError, ZResult},
+    traits::randenum::RandEnum,
 };
+use serde_repr::Serialize_repr;
+use std::convert::TryFrom;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize_repr)]
+#[repr(u8)]
 pub enum CellType {
     Empty = 0,
     Wall = 1,
@@ -15,29 +19,57 @@ pub enum CellType {
     Bomb = 100,
 }
 
-impl From<u8> for CellType {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => CellType::Empty,
-            1 => CellType::Wall,
-            2 => CellType::Mystery,
-            3 => CellType::ItemBomb,
-            4 => CellType::ItemRange,
-            5 => CellType::ItemRandom,
-            6 => CellType::MobSpawner,
-            7 => CellType::Bomb, // To allow random to work.
-            100 => CellType::Bomb,
-            _ => panic!("Invalid cell type: {}", value),
+impl CellType {
+    /// Every cell type, in code order.
+    pub const ALL: [CellType; 8] = [
+        CellType::Empty,
+        CellType::Wall,
+        CellType::Mystery,
+        CellType::ItemBomb,
+        CellType::ItemRange,
+        CellType::ItemRandom,
+        CellType::MobSpawner,
+        CellType::Bomb,
+    ];
+
+    /// The stable name for this cell type used by the protocol.
+    pub fn name(self) -> &'static str {
+        match self {
+            CellType::Empty => "empty",
+            CellType::Wall => "wall",
+            CellType::Mystery => "mystery",
+            CellType::ItemBomb => "itemBomb",
+            CellType::ItemRange => "itemRange",
+            CellType::ItemRandom => "itemRandom",
+            CellType::MobSpawner => "mobSpawner",
+            CellType::Bomb => "bomb",
         }
     }
+
+    /// The code used for each cell type in world data, with its name.
+    pub fn legend() -> Vec<(u8, &'static str)> {
+        CellType::ALL
+            .iter()
+            .map(|cell| (*cell as u8, cell.name()))
+            .collect()
+    }
 }
 
-// TODO: do I need this?
-impl RandEnumFrom<u8> for CellType {
-    fn get_enum_values() -> Vec<u8> {
-        let mut v: Vec<u8> = (0..8).collect();
-        v.push(100);
-        v
+impl TryFrom<u8> for CellType {
+    type Error = ZError;
+
+    fn try_from(value: u8) -> ZResult<Self> {
+        CellType::ALL
+            .iter()
+            .find(|cell| **cell as u8 == value)
+            .copied()
+            .ok_or(ZError::InvalidCell(value))
+    }
+}
+
+impl RandEnum for CellType {
+    fn get_enum_values() -> Vec<Self> {
+        CellType::ALL.to_vec()
     }
 }
 
@@ -49,6 +81,35 @@ pub trait CanPass {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_legend_covers_every_cell_type() {
+        let legend = CellType::legend();
+        for cell in CellType::get_enum_values() {
+            assert!(
+                legend.contains(&(cell as u8, cell.name())),
+                "{:?} missing from legend",
+                cell
+            );
+        }
+
+        for (code, name) in legend.iter() {
+            assert_eq!(CellType::try_from(*code).unwrap().name(), *name);
+        }
+        let mut names: Vec<&str> = legend.iter().map(|(_, name)| *name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), legend.len());
+    }
+
+    #[test]
+    fn test_try_from() {
+        for cell in CellType::ALL.iter() {
+            assert_eq!(CellType::try_from(*cell as u8).unwrap(), *cell);
+        }
+        assert!(CellType::try_from(7).is_err());
+        assert!(CellType::try_from(255).is_err());
+    }
+
     #[test]
     fn test_random() {
         let r = CellType::random();
@@ -1,7 +1,67 @@
 use crate::engine::bomb::BombTime;
 use chrono::Utc;
+use lazy_static::lazy_static;
 use serde::Serialize;
-use std::{ops::Add, time::Duration};
+use std::{
+    convert::TryFrom,
+    ops::Add,
+    time::{Duration, Instant},
+};
+
+/// How far in the past a timestamp must be before is_past() returns true.
+const PAST_BUFFER_MILLIS: i64 = 1000;
+
+lazy_static! {
+    static ref CLOCK: MonotonicClock<SystemClock> = MonotonicClock::new(SystemClock);
+}
+
+/// Where the current time comes from, so that tests can control it.
+trait TimeSource {
+    /// Milliseconds since the Unix epoch. This can jump in either direction
+    /// if the system clock is changed.
+    fn wall_millis(&self) -> i64;
+
+    /// A clock that never goes backwards.
+    fn monotonic(&self) -> Instant;
+}
+
+struct SystemClock;
+
+impl TimeSource for SystemClock {
+    fn wall_millis(&self) -> i64 {
+        Utc::now().timestamp_millis()
+    }
+
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Timestamps start from the wall-clock time at startup but advance using a
+/// monotonic clock, so they never jump backwards if the system clock changes.
+struct MonotonicClock<S: TimeSource> {
+    source: S,
+    epoch: Instant,
+    epoch_millis: i64,
+}
+
+impl<S: TimeSource> MonotonicClock<S> {
+    fn new(source: S) -> Self {
+        MonotonicClock {
+            epoch: source.monotonic(),
+            epoch_millis: source.wall_millis(),
+            source,
+        }
+    }
+
+    fn now_millis(&self) -> i64 {
+        let elapsed = self
+            .source
+            .monotonic()
+            .saturating_duration_since(self.epoch);
+        self.epoch_millis + elapsed.as_millis() as i64
+    }
+}
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize)]
 #[serde(transparent)]
@@ -9,7 +69,7 @@ pub struct Timestamp(i64);
 
 impl Default for Timestamp {
     fn default() -> Self {
-        Timestamp(Utc::now().timestamp_millis())
+        Timestamp(CLOCK.now_millis())
     }
 }
 
@@ -26,9 +86,56 @@ impl Timestamp {
         self.0 == 0
     }
 
-    pub fn is_past(self) -> bool {
-        // Allow a 1 second buffer.
-        self.0 == 0 || self.0 < Utc::now().timestamp_millis() - 1000
+    /// Returns true if this timestamp is unset or is well before now.
+    pub fn is_past(self, now: Timestamp) -> bool {
+        self.0 == 0 || self.0 < now.0.saturating_sub(PAST_BUFFER_MILLIS)
+    }
+
+    /// The number of milliseconds from other until this timestamp.
+    pub fn millis_since(self, other: Timestamp) -> i64 {
+        self.0.saturating_sub(other.0)
+    }
+
+    /// Add the specified number of milliseconds, saturating at the numeric
+    /// bounds rather than overflowing.
+    pub fn saturating_add(self, millis: i64) -> Self {
+        Timestamp(self.0.saturating_add(millis))
+    }
+}
+
+/// The simulation time. This only advances when the game is updated, so that
+/// bomb fuses, explosions and effects all agree on how much time has passed.
+#[derive(Debug, Clone, Copy)]
+pub struct GameClock {
+    start: Timestamp,
+    elapsed: f64,
+}
+
+impl Default for GameClock {
+    fn default() -> Self {
+        GameClock {
+            start: Timestamp::new(),
+            elapsed: 0.0,
+        }
+    }
+}
+
+impl GameClock {
+    pub fn new() -> Self {
+        GameClock::default()
+    }
+
+    pub fn now(&self) -> Timestamp {
+        self.start.saturating_add((self.elapsed * 1000.0) as i64)
+    }
+
+    /// Seconds of simulation time since the clock started.
+    pub fn elapsed(&self) -> f64 {
+        self.elapsed
+    }
+
+    pub fn advance(&mut self, delta_time: f64) {
+        self.elapsed += delta_time;
     }
 }
 
@@ -36,7 +143,7 @@ impl Add<Duration> for Timestamp {
     type Output = Timestamp;
 
     fn add(self, rhs: Duration) -> Self::Output {
-        Timestamp(self.0 + rhs.as_millis() as i64)
+        self.saturating_add(i64::try_from(rhs.as_millis()).unwrap_or(i64::MAX))
     }
 }
 
@@ -44,6 +151,102 @@ impl Add<BombTime> for Timestamp {
     type Output = Timestamp;
 
     fn add(self, rhs: BombTime) -> Self::Output {
-        Timestamp(self.0 + rhs.millis() as i64)
+        // Float to int casts saturate too.
+        self.saturating_add(rhs.millis() as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// A time source that only changes when told to.
+    struct FakeClock {
+        wall_millis: Cell<i64>,
+        monotonic: Cell<Instant>,
+    }
+
+    impl TimeSource for &FakeClock {
+        fn wall_millis(&self) -> i64 {
+            self.wall_millis.get()
+        }
+
+        fn monotonic(&self) -> Instant {
+            self.monotonic.get()
+        }
+    }
+
+    impl FakeClock {
+        /// Let some time pass, while the system clock is moved by `step`.
+        fn advance(&self, millis: u64, step: i64) {
+            self.monotonic
+                .set(self.monotonic.get() + Duration::from_millis(millis));
+            self.wall_millis
+                .set(self.wall_millis.get() + millis as i64 + step);
+        }
+    }
+
+    #[test]
+    fn test_order_across_clock_step() {
+        let source = FakeClock {
+            wall_millis: Cell::new(1_600_000_000_000),
+            monotonic: Cell::new(Instant::now()),
+        };
+        let clock = MonotonicClock::new(&source);
+        source.advance(500, 0);
+        let before = Timestamp(clock.now_millis());
+
+        // The system clock is stepped back an hour (e.g. by NTP).
+        let hour = 60 * 60 * 1000;
+        source.advance(100, -hour);
+        assert!(source.wall_millis.get() < before.0);
+        let after = Timestamp(clock.now_millis());
+        assert!(after > before);
+        assert_eq!(after, before + Duration::from_millis(100));
+
+        // And forward again.
+        source.advance(100, hour);
+        let later = Timestamp(clock.now_millis());
+        assert_eq!(later, after + Duration::from_millis(100));
+
+        // Real timestamps never go backwards.
+        let first = Timestamp::new();
+        let second = Timestamp::new();
+        assert!(second >= first);
+        assert!(!first.is_past(Timestamp::new()));
+        assert!((first + Duration::from_millis(1)) > first);
+    }
+
+    #[test]
+    fn test_saturating_add() {
+        let max = Timestamp(i64::MAX);
+        assert_eq!(Timestamp(i64::MAX - 5) + Duration::from_millis(10), max);
+        assert_eq!(max + Duration::from_millis(1), max);
+        assert_eq!(Timestamp(1) + Duration::from_secs(u64::MAX), max);
+        assert_eq!(Timestamp(1) + BombTime::from(f64::INFINITY), max);
+        assert_eq!(Timestamp(10) + BombTime::from(1.5), Timestamp(1510));
+
+        let min = Timestamp(i64::MIN);
+        assert_eq!(Timestamp(i64::MIN + 5).saturating_add(-10), min);
+        assert_eq!(Timestamp(-5).saturating_add(i64::MIN), min);
+        assert!(min.is_past(Timestamp::new()));
+        assert!(!max.is_past(Timestamp::new()));
+    }
+
+    #[test]
+    fn test_game_clock() {
+        let mut clock = GameClock::new();
+        let start = clock.now();
+        for _ in 0..30 {
+            clock.advance(1.0 / 30.0);
+        }
+        assert!((clock.elapsed() - 1.0).abs() < 1e-9);
+        let now = clock.now();
+        assert!(now >= start + Duration::from_millis(999));
+        assert!(now <= start + Duration::from_millis(1000));
+        assert!(!start.is_past(now));
+        clock.advance(0.5);
+        assert!(start.is_past(clock.now()));
     }
 }